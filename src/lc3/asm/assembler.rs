@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+
+use super::parser::{parse, Op, Operand, Statement};
+use super::{AsmError, ErrorKind};
+use crate::lc3::cpu::decode::{self, encode, Instruction};
+use crate::lc3::cpu::instruction::extract_register;
+use crate::lc3::cpu::trap::TrapCode;
+use crate::lc3::hardware::Reg::RegisterEnum;
+
+/// The output of `assemble`: an image and the labels that went into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    /// Address of the first word, from `.ORIG`.
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Every label with its address, in the order they were defined.
+    pub symbols: Vec<(String, u16)>,
+    /// Line table: the 1-based source line of every instruction and its address, in
+    /// address order. Pseudo-ops are left out.
+    pub lines: Vec<(usize, u16)>,
+    /// The source line of every pseudo-op that reserves words, with its first address
+    /// and the number of words, in address order.
+    pub data: Vec<(usize, u16, u16)>,
+}
+
+impl Assembly {
+    /// Address of `label`, if it was defined.
+    pub fn symbol(&self, label: &str) -> Option<u16> {
+        self.symbols.iter().find(|(name, _)| name == label).map(|&(_, address)| address)
+    }
+
+    /// Source line of the instruction at `address`.
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.lines.iter().find(|&&(_, a)| a == address).map(|&(line, _)| line)
+    }
+
+    /// The first instruction on `line`, or on the closest line after it if `line` has
+    /// none (a comment, a label on its own, a pseudo-op). Returns its line and address.
+    pub fn instruction_at_line(&self, line: usize) -> Option<(usize, u16)> {
+        self.lines.iter().filter(|&&(l, _)| l >= line).min_by_key(|&&(l, a)| (l, a)).copied()
+    }
+}
+
+/// Assemble LC-3 source text.
+///
+/// The program is a single `.ORIG` block; anything after `.END` is ignored. The first
+/// pass lays out addresses and collects labels, the second encodes every statement.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let statements = parse(source)?;
+    let layout = layout(&statements)?;
+
+    let encoder = Encoder { symbols: &layout.index };
+    let mut words = Vec::new();
+    let mut lines = Vec::new();
+    let mut data = Vec::new();
+    for &(statement, address) in &layout.placed {
+        let Some(op) = &statement.op else { continue };
+        let start = words.len();
+        encoder
+            .emit(op, address, &mut words)
+            .map_err(|kind| AsmError { line: statement.line, kind })?;
+        match op {
+            Op::Instruction { .. } => lines.push((statement.line, address)),
+            _ if words.len() > start => data.push((statement.line, address, (words.len() - start) as u16)),
+            _ => {}
+        }
+    }
+
+    Ok(Assembly { origin: layout.origin, words, symbols: layout.symbols, lines, data })
+}
+
+/// The result of the first pass.
+struct Layout<'a> {
+    origin: u16,
+    /// Statements between `.ORIG` and `.END`, with the address each one starts at.
+    placed: Vec<(&'a Statement, u16)>,
+    symbols: Vec<(String, u16)>,
+    index: HashMap<String, u16>,
+}
+
+fn layout(statements: &[Statement]) -> Result<Layout<'_>, AsmError> {
+    let mut origin = None;
+    let mut address: u32 = 0;
+    let mut placed = Vec::new();
+    let mut symbols = Vec::new();
+    let mut index = HashMap::new();
+
+    for statement in statements {
+        let error = |kind| AsmError { line: statement.line, kind };
+
+        if let Some(Op::Directive { name, operands }) = &statement.op {
+            if name == "ORIG" {
+                if origin.is_some() {
+                    return Err(error(ErrorKind::MultipleOrig));
+                }
+                if statement.label.is_some() {
+                    return Err(error(ErrorKind::LabelOnOrig));
+                }
+                let start = match operands.as_slice() {
+                    [Operand::Number(n)] => unsigned(*n, 16).map_err(error)?,
+                    _ => return Err(error(ErrorKind::BadOperands("an address"))),
+                };
+                origin = Some(start);
+                address = start as u32;
+                continue;
+            }
+        }
+        if origin.is_none() {
+            return Err(error(ErrorKind::MissingOrig));
+        }
+
+        if let Some(label) = &statement.label {
+            if index.insert(label.clone(), address as u16).is_some() {
+                return Err(error(ErrorKind::DuplicateLabel(label.clone())));
+            }
+            symbols.push((label.clone(), address as u16));
+        }
+        if let Some(Op::Directive { name, .. }) = &statement.op {
+            if name == "END" {
+                break;
+            }
+        }
+
+        placed.push((statement, address as u16));
+        address += statement.op.as_ref().map_or(Ok(0), size).map_err(error)?;
+        if address > 0x1_0000 {
+            return Err(error(ErrorKind::TooLarge));
+        }
+    }
+
+    match origin {
+        Some(origin) => Ok(Layout { origin, placed, symbols, index }),
+        None => Err(AsmError { line: 1, kind: ErrorKind::MissingOrig }),
+    }
+}
+
+/// Number of words `op` occupies.
+fn size(op: &Op) -> Result<u32, ErrorKind> {
+    match op {
+        Op::Instruction { .. } => Ok(1),
+        Op::Directive { name, operands } => match (name.as_str(), operands.as_slice()) {
+            ("FILL", _) => Ok(1),
+            ("BLKW", [Operand::Number(n)]) => Ok(unsigned(*n, 16)? as u32),
+            ("BLKW", _) => Err(ErrorKind::BadOperands("a word count")),
+            ("STRINGZ", [Operand::Str(s)]) => match s.chars().find(|c| !c.is_ascii()) {
+                Some(c) => Err(ErrorKind::NonAscii(c)),
+                None => Ok(s.len() as u32 + 1),
+            },
+            ("STRINGZ", _) => Err(ErrorKind::BadOperands("a string")),
+            _ => Err(ErrorKind::UnknownDirective(name.clone())),
+        },
+    }
+}
+
+/// The second pass: turns statements into words once every label has an address.
+struct Encoder<'a> {
+    symbols: &'a HashMap<String, u16>,
+}
+
+impl Encoder<'_> {
+    /// Append the words for `op`, placed at `address`.
+    fn emit(&self, op: &Op, address: u16, words: &mut Vec<u16>) -> Result<(), ErrorKind> {
+        match op {
+            Op::Instruction { mnemonic, operands } => {
+                words.push(self.instruction(mnemonic, operands, address)?);
+            }
+            Op::Directive { name, operands } => match (name.as_str(), operands.as_slice()) {
+                ("FILL", [Operand::Number(n)]) => {
+                    if !(-0x8000..=0xFFFF).contains(n) {
+                        return Err(ErrorKind::OutOfRange { value: *n, bits: 16 });
+                    }
+                    words.push(*n as u16);
+                }
+                ("FILL", [Operand::Label(label)]) => words.push(self.address_of(label)?),
+                ("FILL", _) => return Err(ErrorKind::BadOperands("a value or label")),
+                ("BLKW", [Operand::Number(n)]) => words.extend(std::iter::repeat_n(0, *n as usize)),
+                ("STRINGZ", [Operand::Str(s)]) => {
+                    words.extend(s.bytes().map(u16::from));
+                    words.push(0);
+                }
+                _ => unreachable!("checked by the first pass"),
+            },
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand], address: u16) -> Result<u16, ErrorKind> {
+        use Operand::{Number, Register};
+
+        let reg = |r: &u8| extract_register(*r as u16, 0);
+        let trap = |code: TrapCode| Instruction::Trap { vector: code as u8 };
+
+        let instruction = match (mnemonic, operands) {
+            ("ADD" | "AND", [Register(dr), Register(sr1), second]) => {
+                let src = match second {
+                    Register(sr2) => decode::Operand::Register(reg(sr2)),
+                    Number(n) => decode::Operand::Immediate(signed(*n, 5)?),
+                    _ => return Err(ErrorKind::BadOperands(form(mnemonic))),
+                };
+                let (dr, sr1) = (reg(dr), reg(sr1));
+                if mnemonic == "ADD" {
+                    Instruction::Add { dr, sr1, src }
+                } else {
+                    Instruction::And { dr, sr1, src }
+                }
+            }
+            ("NOT", [Register(dr), Register(sr)]) => Instruction::Not { dr: reg(dr), sr: reg(sr) },
+            (branch, [target]) if branch.starts_with("BR") => {
+                let flags = &branch[2..];
+                let nzp = if flags.is_empty() {
+                    0b111
+                } else {
+                    (flags.contains('N') as u8) << 2 | (flags.contains('Z') as u8) << 1 | flags.contains('P') as u8
+                };
+                Instruction::Br { nzp, offset: self.pc_offset(target, address, 9)? }
+            }
+            ("JMP", [Register(base)]) => Instruction::Jmp { base: reg(base) },
+            ("RET", []) => Instruction::Jmp { base: RegisterEnum::R7 },
+            ("JSR", [target]) => Instruction::Jsr { offset: self.pc_offset(target, address, 11)? },
+            ("JSRR", [Register(base)]) => Instruction::Jsrr { base: reg(base) },
+            ("LD" | "LDI" | "LEA" | "ST" | "STI", [Register(r), target]) => {
+                let (r, offset) = (reg(r), self.pc_offset(target, address, 9)?);
+                match mnemonic {
+                    "LD" => Instruction::Ld { dr: r, offset },
+                    "LDI" => Instruction::Ldi { dr: r, offset },
+                    "LEA" => Instruction::Lea { dr: r, offset },
+                    "ST" => Instruction::St { sr: r, offset },
+                    _ => Instruction::Sti { sr: r, offset },
+                }
+            }
+            ("LDR" | "STR", [Register(r), Register(base), Number(n)]) => {
+                let (r, base, offset) = (reg(r), reg(base), signed(*n, 6)?);
+                if mnemonic == "LDR" {
+                    Instruction::Ldr { dr: r, base, offset }
+                } else {
+                    Instruction::Str { sr: r, base, offset }
+                }
+            }
+            ("TRAP", [Number(n)]) => Instruction::Trap { vector: unsigned(*n, 8)? as u8 },
+            ("RTI", []) => Instruction::Rti,
+            ("GETC", []) => trap(TrapCode::Getc),
+            ("OUT", []) => trap(TrapCode::Out),
+            ("PUTS", []) => trap(TrapCode::Puts),
+            ("IN", []) => trap(TrapCode::In),
+            ("PUTSP", []) => trap(TrapCode::Putsp),
+            ("HALT", []) => trap(TrapCode::Halt),
+            _ => return Err(ErrorKind::BadOperands(form(mnemonic))),
+        };
+        Ok(encode(&instruction))
+    }
+
+    /// A PC-relative operand: a label, or a literal offset used as is.
+    fn pc_offset(&self, target: &Operand, address: u16, bits: u8) -> Result<i16, ErrorKind> {
+        match target {
+            Operand::Label(label) => {
+                let offset = self.address_of(label)? as i32 - (address as i32 + 1);
+                signed(offset, bits)
+            }
+            Operand::Number(n) => signed(*n, bits),
+            _ => Err(ErrorKind::BadOperands("a label or offset")),
+        }
+    }
+
+    fn address_of(&self, label: &str) -> Result<u16, ErrorKind> {
+        self.symbols
+            .get(label)
+            .copied()
+            .ok_or_else(|| ErrorKind::UndefinedLabel(label.to_string()))
+    }
+}
+
+/// The operands `mnemonic` takes, for error messages.
+fn form(mnemonic: &str) -> &'static str {
+    match mnemonic {
+        "ADD" | "AND" => "DR, SR1, SR2 or DR, SR1, imm5",
+        "NOT" => "DR, SR",
+        "JMP" | "JSRR" => "a base register",
+        "JSR" => "a label or offset",
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => "a register and a label or offset",
+        "LDR" | "STR" => "two registers and an offset",
+        "TRAP" => "a trap vector",
+        branch if branch.starts_with("BR") => "a label or offset",
+        _ => "no operands",
+    }
+}
+
+/// `value`, checked to fit a `bits`-wide two's complement field.
+fn signed(value: i32, bits: u8) -> Result<i16, ErrorKind> {
+    let limit = 1 << (bits - 1);
+    if !(-limit..limit).contains(&value) {
+        return Err(ErrorKind::OutOfRange { value, bits });
+    }
+    Ok(value as i16)
+}
+
+/// `value` as a `bits`-wide unsigned field.
+fn unsigned(value: i32, bits: u8) -> Result<u16, ErrorKind> {
+    if !(0..1 << bits).contains(&value) {
+        return Err(ErrorKind::OutOfRange { value, bits });
+    }
+    Ok(value as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u16> {
+        assemble(source).unwrap().words
+    }
+
+    #[test]
+    fn test_every_instruction() {
+        let source = "
+            .ORIG x3000
+    TOP     ADD R1, R2, R3
+            ADD R1, R2, #-3
+            AND R0, R0, #0
+            NOT R4, R5
+            BRnz TOP
+            BR TOP
+            JMP R3
+            RET
+            JSR TOP
+            JSRR R2
+            LD R0, DATA
+            LDI R1, DATA
+            LDR R2, R6, #-1
+            LEA R3, TOP
+            ST R0, DATA
+            STI R1, DATA
+            STR R2, R6, #31
+            TRAP x25
+            RTI
+    DATA    .FILL xBEEF
+            .END
+        ";
+        assert_eq!(
+            words(source),
+            vec![
+                0x1283, 0x12BD, 0x5020, 0x997F, 0x0DFB, 0x0FFA, 0xC0C0, 0xC1C0, 0x4FF7, 0x4080,
+                0x2008, 0xA207, 0x65BF, 0xE7F2, 0x3004, 0xB203, 0x759F, 0xF025, 0x8000, 0xBEEF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pseudo_ops_and_trap_aliases() {
+        let assembly = assemble(
+            ".ORIG x4000\nGETC\nOUT\nPUTS\nIN\nPUTSP\nHALT\nBUF .BLKW 2\nMSG .STRINGZ \"hi\"\nPTR .FILL MSG\n.END\nignored garbage",
+        )
+        .unwrap();
+        assert_eq!(assembly.origin, 0x4000);
+        assert_eq!(
+            assembly.words,
+            vec![0xF020, 0xF021, 0xF022, 0xF023, 0xF024, 0xF025, 0, 0, 'h' as u16, 'i' as u16, 0, 0x4008]
+        );
+        assert_eq!(
+            assembly.symbols,
+            vec![("BUF".to_string(), 0x4006), ("MSG".to_string(), 0x4008), ("PTR".to_string(), 0x400B)]
+        );
+        assert_eq!(assembly.symbol("PTR"), Some(0x400B));
+    }
+
+    #[test]
+    fn test_line_table() {
+        let assembly = assemble(
+            "; counts down\n.ORIG x3000\n  AND R0, R0, #0\nLOOP\n  ADD R0, R0, #-1\n  BRp LOOP\nDATA .FILL 5\n  HALT\n.END",
+        )
+        .unwrap();
+        assert_eq!(assembly.lines, vec![(3, 0x3000), (5, 0x3001), (6, 0x3002), (8, 0x3004)]);
+        assert_eq!(assembly.data, vec![(7, 0x3003, 1)]);
+        assert_eq!(assembly.line_at(0x3002), Some(6));
+        assert_eq!(assembly.line_at(0x3003), None);
+        assert_eq!(assembly.instruction_at_line(3), Some((3, 0x3000)));
+        // A label on its own line and a .FILL move to the next instruction
+        assert_eq!(assembly.instruction_at_line(4), Some((5, 0x3001)));
+        assert_eq!(assembly.instruction_at_line(7), Some((8, 0x3004)));
+        assert_eq!(assembly.instruction_at_line(9), None);
+    }
+
+    #[test]
+    fn test_errors_carry_line_numbers() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(error("ADD R0, R0, #1"), AsmError { line: 1, kind: ErrorKind::MissingOrig });
+        assert_eq!(
+            error(".ORIG x3000\nBR NOWHERE"),
+            AsmError { line: 2, kind: ErrorKind::UndefinedLabel("NOWHERE".into()) }
+        );
+        assert_eq!(
+            error(".ORIG x3000\nADD R0, R0, #16"),
+            AsmError { line: 2, kind: ErrorKind::OutOfRange { value: 16, bits: 5 } }
+        );
+        assert_eq!(
+            error(".ORIG x3000\nA ADD R0, R0, R0\nA HALT"),
+            AsmError { line: 3, kind: ErrorKind::DuplicateLabel("A".into()) }
+        );
+        assert_eq!(
+            error(".ORIG x3000\nLDR R0, #1"),
+            AsmError { line: 2, kind: ErrorKind::BadOperands("two registers and an offset") }
+        );
+        assert_eq!(
+            error(".ORIG x3000\n.WORD 1"),
+            AsmError { line: 2, kind: ErrorKind::UnknownDirective("WORD".into()) }
+        );
+        assert_eq!(error("START .ORIG x3000\nHALT"), AsmError { line: 1, kind: ErrorKind::LabelOnOrig });
+        assert_eq!(error("START .ORIG x3000\nHALT").to_string(), "line 1: a label cannot go on the .ORIG line");
+        assert_eq!(
+            error(".ORIG x3000\nHALT\nMSG .STRINGZ \"caf\u{e9}\""),
+            AsmError { line: 3, kind: ErrorKind::NonAscii('\u{e9}') }
+        );
+        assert_eq!(
+            error(".ORIG xFFFF\nHALT\nHALT"),
+            AsmError { line: 3, kind: ErrorKind::TooLarge }
+        );
+    }
+}
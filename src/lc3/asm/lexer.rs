@@ -0,0 +1,184 @@
+use super::ErrorKind;
+
+/// One token of an assembly source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// A label or mnemonic, as written.
+    Ident(String),
+    /// A pseudo-op such as `.ORIG`, upper-cased.
+    Directive(String),
+    /// `R0`-`R7`.
+    Register(u8),
+    /// A decimal (`#-3`, `12`), hex (`x3000`) or binary (`b1010`) literal.
+    Number(i32),
+    /// A `.STRINGZ` operand with its escapes resolved.
+    Str(String),
+}
+
+/// Split one line into tokens. Commas and whitespace separate tokens; `;` starts a comment.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, ErrorKind> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            tokens.push(Token::Str(string_literal(&mut chars)?));
+            continue;
+        }
+
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == ',' || c == ';' || c == '"' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        tokens.push(classify(&word)?);
+    }
+    Ok(tokens)
+}
+
+/// Read the rest of a string literal, the opening quote already consumed.
+fn string_literal(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, ErrorKind> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some('0') => value.push('\0'),
+                Some('e') => value.push('\x1b'),
+                Some(c @ ('"' | '\\')) => value.push(c),
+                Some(c) => return Err(ErrorKind::BadEscape(c)),
+                None => return Err(ErrorKind::UnterminatedString),
+            },
+            Some(c) => value.push(c),
+            None => return Err(ErrorKind::UnterminatedString),
+        }
+    }
+}
+
+fn classify(word: &str) -> Result<Token, ErrorKind> {
+    if let Some(directive) = word.strip_prefix('.') {
+        return Ok(Token::Directive(directive.to_ascii_uppercase()));
+    }
+    if let Some(number) = parse_number(word) {
+        return number.map(Token::Number);
+    }
+    if let Some(register) = parse_register(word) {
+        return Ok(Token::Register(register));
+    }
+    // A trailing colon after a label is accepted and dropped
+    let ident = word.strip_suffix(':').unwrap_or(word);
+    let valid = ident.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && ident.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(ErrorKind::BadToken(word.to_string()));
+    }
+    Ok(Token::Ident(ident.to_string()))
+}
+
+fn parse_register(word: &str) -> Option<u8> {
+    match word.as_bytes() {
+        [b'R' | b'r', n @ b'0'..=b'7'] => Some(n - b'0'),
+        _ => None,
+    }
+}
+
+/// `None` if `word` isn't shaped like a literal at all (so it may be a label),
+/// `Some(Err)` if it is but doesn't parse.
+pub fn parse_number(word: &str) -> Option<Result<i32, ErrorKind>> {
+    let bad = || ErrorKind::BadNumber(word.to_string());
+    let (negative, digits, radix) = if let Some(rest) = word.strip_prefix('#') {
+        let (negative, rest) = split_sign(rest);
+        (negative, rest, 10)
+    } else if let Some(rest) = word.strip_prefix(['x', 'X']).or_else(|| word.strip_prefix("0x")) {
+        let (negative, rest) = split_sign(rest);
+        // `xyz` is a label, not a malformed number
+        if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        (negative, rest, 16)
+    } else if let Some(rest) = word.strip_prefix(['b', 'B']) {
+        let (negative, rest) = split_sign(rest);
+        if rest.is_empty() || !rest.chars().all(|c| c == '0' || c == '1') {
+            return None;
+        }
+        (negative, rest, 2)
+    } else if word.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        let (negative, rest) = split_sign(word);
+        (negative, rest, 10)
+    } else {
+        return None;
+    };
+
+    let value = match i32::from_str_radix(digits, radix) {
+        Ok(value) if value <= 0xFFFF => value,
+        _ => return Some(Err(bad())),
+    };
+    Some(Ok(if negative { -value } else { value }))
+}
+
+fn split_sign(word: &str) -> (bool, &str) {
+    match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_instruction() {
+        assert_eq!(
+            tokenize("LOOP  ADD R1, r2, #-3 ; decrement").unwrap(),
+            vec![
+                Token::Ident("LOOP".into()),
+                Token::Ident("ADD".into()),
+                Token::Register(1),
+                Token::Register(2),
+                Token::Number(-3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(
+            tokenize(".FILL x3000 0x10 b101 12 xBEEF").unwrap(),
+            vec![
+                Token::Directive("FILL".into()),
+                Token::Number(0x3000),
+                Token::Number(0x10),
+                Token::Number(5),
+                Token::Number(12),
+                Token::Number(0xBEEF),
+            ]
+        );
+        // Shaped like hex but not hex: a label
+        assert_eq!(tokenize("xyz").unwrap(), vec![Token::Ident("xyz".into())]);
+        assert_eq!(tokenize("#12a"), Err(ErrorKind::BadNumber("#12a".into())));
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        assert_eq!(
+            tokenize(r#".STRINGZ "a;b\n\"c\"" ; comment"#).unwrap(),
+            vec![Token::Directive("STRINGZ".into()), Token::Str("a;b\n\"c\"".into())]
+        );
+        assert_eq!(tokenize(r#".STRINGZ "open"#), Err(ErrorKind::UnterminatedString));
+    }
+}
@@ -0,0 +1,80 @@
+//! A two-pass LC-3 assembler.
+//!
+//! `assemble` turns source text into an `Assembly`: the origin and words that
+//! `sys::file::write_image` stores as an `.obj` image, plus the labels it defined.
+
+pub mod assembler;
+pub mod lexer;
+pub mod parser;
+
+pub use assembler::{assemble, Assembly};
+
+use std::error::Error;
+use std::fmt;
+
+/// What is wrong with a line of source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Something that is neither a label, mnemonic, register, literal nor string.
+    BadToken(String),
+    BadNumber(String),
+    BadEscape(char),
+    UnterminatedString,
+    /// A `.STRINGZ` character that doesn't fit a byte of ASCII.
+    NonAscii(char),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    /// The operands don't fit the instruction; holds the expected form.
+    BadOperands(&'static str),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    /// `value` doesn't fit the `bits`-wide field it is encoded in.
+    OutOfRange { value: i32, bits: u8 },
+    /// Code before the `.ORIG` that says where it goes.
+    MissingOrig,
+    /// A label on the `.ORIG` line, which has no address of its own.
+    LabelOnOrig,
+    /// A second `.ORIG`; an image has a single origin.
+    MultipleOrig,
+    /// The program runs past the end of memory.
+    TooLarge,
+}
+
+/// An error and the 1-based source line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::BadToken(token) => write!(f, "unexpected '{}'", token),
+            ErrorKind::BadNumber(token) => write!(f, "invalid number '{}'", token),
+            ErrorKind::BadEscape(c) => write!(f, "unknown escape '\\{}'", c),
+            ErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ErrorKind::NonAscii(c) => write!(f, "'{}' is not an ASCII character", c),
+            ErrorKind::UnknownMnemonic(word) => write!(f, "unknown instruction '{}'", word),
+            ErrorKind::UnknownDirective(name) => write!(f, "unknown pseudo-op '.{}'", name),
+            ErrorKind::BadOperands(form) => write!(f, "expected {}", form),
+            ErrorKind::UndefinedLabel(label) => write!(f, "undefined label '{}'", label),
+            ErrorKind::DuplicateLabel(label) => write!(f, "label '{}' defined twice", label),
+            ErrorKind::OutOfRange { value, bits } => {
+                write!(f, "{} does not fit in {} bits", value, bits)
+            }
+            ErrorKind::MissingOrig => write!(f, "code before .ORIG"),
+            ErrorKind::LabelOnOrig => write!(f, "a label cannot go on the .ORIG line"),
+            ErrorKind::MultipleOrig => write!(f, "only one .ORIG per file is supported"),
+            ErrorKind::TooLarge => write!(f, "program runs past the end of memory"),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl Error for AsmError {}
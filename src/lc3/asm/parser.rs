@@ -0,0 +1,162 @@
+use super::lexer::{tokenize, Token};
+use super::{AsmError, ErrorKind};
+
+/// An instruction or pseudo-op operand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Register(u8),
+    Number(i32),
+    Label(String),
+    Str(String),
+}
+
+/// What a line asks the assembler to emit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// An instruction or trap alias; `mnemonic` is upper-cased.
+    Instruction { mnemonic: String, operands: Vec<Operand> },
+    /// A pseudo-op without its dot; `name` is upper-cased.
+    Directive { name: String, operands: Vec<Operand> },
+}
+
+/// One non-empty source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    /// 1-based line number in the source.
+    pub line: usize,
+    pub label: Option<String>,
+    pub op: Option<Op>,
+}
+
+/// `true` for instruction names and trap aliases, in any case.
+pub fn is_mnemonic(word: &str) -> bool {
+    let upper = word.to_ascii_uppercase();
+    if let Some(flags) = upper.strip_prefix("BR") {
+        return is_condition(flags);
+    }
+    matches!(
+        upper.as_str(),
+        "ADD" | "AND" | "NOT" | "JMP" | "RET" | "JSR" | "JSRR" | "LD" | "LDI" | "LDR" | "LEA"
+            | "ST" | "STI" | "STR" | "TRAP" | "RTI" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP"
+            | "HALT"
+    )
+}
+
+/// The `n`, `z`, `p` suffix of a branch: each at most once, in that order.
+fn is_condition(flags: &str) -> bool {
+    let mut rest = flags;
+    for flag in ["N", "Z", "P"] {
+        rest = rest.strip_prefix(flag).unwrap_or(rest);
+    }
+    rest.is_empty()
+}
+
+/// Split `source` into statements, skipping blank and comment-only lines.
+/// Parsing stops after `.END`; whatever follows is not looked at.
+pub fn parse(source: &str) -> Result<Vec<Statement>, AsmError> {
+    let mut statements = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| AsmError { line, kind };
+        let tokens = tokenize(text).map_err(error)?;
+        if let Some(statement) = parse_line(line, tokens).map_err(error)? {
+            let end = matches!(&statement.op, Some(Op::Directive { name, .. }) if name == "END");
+            statements.push(statement);
+            if end {
+                break;
+            }
+        }
+    }
+    Ok(statements)
+}
+
+fn parse_line(line: usize, tokens: Vec<Token>) -> Result<Option<Statement>, ErrorKind> {
+    let mut tokens = tokens.into_iter().peekable();
+    let label = match tokens.peek() {
+        Some(Token::Ident(word)) if !is_mnemonic(word) => {
+            let label = word.clone();
+            tokens.next();
+            Some(label)
+        }
+        _ => None,
+    };
+
+    let op = match tokens.next() {
+        None => None,
+        Some(first) => {
+            let operands = tokens.map(operand).collect::<Result<Vec<_>, _>>()?;
+            Some(match first {
+                Token::Ident(word) if is_mnemonic(&word) => {
+                    Op::Instruction { mnemonic: word.to_ascii_uppercase(), operands }
+                }
+                Token::Ident(word) => return Err(ErrorKind::UnknownMnemonic(word)),
+                Token::Directive(name) => Op::Directive { name, operands },
+                other => return Err(ErrorKind::BadToken(describe(&other))),
+            })
+        }
+    };
+
+    if label.is_none() && op.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Statement { line, label, op }))
+}
+
+fn operand(token: Token) -> Result<Operand, ErrorKind> {
+    Ok(match token {
+        Token::Register(r) => Operand::Register(r),
+        Token::Number(n) => Operand::Number(n),
+        Token::Ident(label) => Operand::Label(label),
+        Token::Str(s) => Operand::Str(s),
+        Token::Directive(name) => return Err(ErrorKind::BadToken(format!(".{}", name))),
+    })
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(word) => word.clone(),
+        Token::Directive(name) => format!(".{}", name),
+        Token::Register(r) => format!("R{}", r),
+        Token::Number(n) => format!("#{}", n),
+        Token::Str(s) => format!("{:?}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branch_mnemonics() {
+        for word in ["BR", "brnzp", "BRn", "BRzp", "BRnp"] {
+            assert!(is_mnemonic(word), "{}", word);
+        }
+        for word in ["BRpn", "BRnn", "BRx"] {
+            assert!(!is_mnemonic(word), "{}", word);
+        }
+    }
+
+    #[test]
+    fn test_parse_labels_and_ops() {
+        let statements = parse("; header\nLOOP add R0, R0, #1\n  BRp LOOP\nEND\n").unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[0].line, 2);
+        assert_eq!(statements[0].label.as_deref(), Some("LOOP"));
+        assert_eq!(
+            statements[1].op,
+            Some(Op::Instruction {
+                mnemonic: "BRP".into(),
+                operands: vec![Operand::Label("LOOP".into())],
+            })
+        );
+        // A lone word that isn't a mnemonic is a label on its own line
+        assert_eq!(statements[2].label.as_deref(), Some("END"));
+        assert_eq!(statements[2].op, None);
+    }
+
+    #[test]
+    fn test_unknown_mnemonic() {
+        let error = parse("LABEL MOV R0, R1").unwrap_err();
+        assert_eq!(error, AsmError { line: 1, kind: ErrorKind::UnknownMnemonic("MOV".into()) });
+    }
+}
@@ -1,6 +1,12 @@
-use crate::lc3::cpu::instruction::Instructions;
+use crate::lc3::cpu::instruction::{extract_register, sign_extend, Instructions};
+use crate::lc3::cpu::interrupt::{self, Exception};
 use crate::lc3::cpu::opcode::{OpCode, OpCodeError};
-use crate::lc3::hardware::{Memory::Memory, Reg::Registers};
+use crate::lc3::cpu::trap::{self, TrapMode};
+use crate::lc3::hardware::{Memory::Memory, Reg::{RegisterEnum, Registers}};
+use crate::lc3::sys::console::Console;
+use crate::lc3::vm::ExitReason;
+use std::cell::RefCell;
+use std::fmt;
 
 /// Extracts the opcode (top 4 bits) from a 16-bit instruction.
 #[inline]
@@ -8,36 +14,236 @@ pub fn extract_op_code(instruction: u16) -> Result<OpCode, OpCodeError> {
     OpCode::get(instruction >> 12)
 }
 
-pub fn execute_instruction(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-    match extract_op_code(instr) {
-        Ok(OpCode::Add) => Instructions::add(instr, registers),
-        Ok(OpCode::And) => Instructions::bitwise_and(instr, registers),
-        Ok(OpCode::Not) => Instructions::bitwise_not(instr, registers),
-        Ok(OpCode::Br) => Instructions::br(instr, registers),
-        Ok(OpCode::Jmp) => Instructions::jmp(instr, registers),
-        Ok(OpCode::Jsr) => Instructions::jsr(instr, registers),
-        Ok(OpCode::Ld) => Instructions::ld(instr, registers, memory),
-        Ok(OpCode::Ldi) => Instructions::ldi(instr, registers, memory),
-        Ok(OpCode::Ldr) => Instructions::ldr(instr, registers, memory),
-        Ok(OpCode::Lea) => Instructions::lea(instr, registers),
-        Ok(OpCode::St) => Instructions::st(instr, registers, memory),
-        Ok(OpCode::Sti) => Instructions::sti(instr, registers, memory),
-        Ok(OpCode::Str) => Instructions::str(instr, registers, memory),
-        Ok(OpCode::Trap) => Instructions::trap(instr, registers, memory),
-        Ok(OpCode::Rti) => {
-            eprintln!("RTI instruction encountered: {:#06X}", instr);
+/// The second source operand of ADD and AND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// `SR2`
+    Register(RegisterEnum),
+    /// `imm5`, sign-extended.
+    Immediate(i16),
+}
+
+/// A fully decoded instruction word. Offsets are sign-extended but still relative:
+/// PC-relative ones to the incremented PC, `Ldr`/`Str` ones to the base register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Add { dr: RegisterEnum, sr1: RegisterEnum, src: Operand },
+    And { dr: RegisterEnum, sr1: RegisterEnum, src: Operand },
+    Not { dr: RegisterEnum, sr: RegisterEnum },
+    /// `nzp` holds the condition bits, N in bit 2.
+    Br { nzp: u8, offset: i16 },
+    /// Also RET, as `JMP R7`.
+    Jmp { base: RegisterEnum },
+    Jsr { offset: i16 },
+    Jsrr { base: RegisterEnum },
+    Ld { dr: RegisterEnum, offset: i16 },
+    Ldi { dr: RegisterEnum, offset: i16 },
+    Ldr { dr: RegisterEnum, base: RegisterEnum, offset: i16 },
+    Lea { dr: RegisterEnum, offset: i16 },
+    St { sr: RegisterEnum, offset: i16 },
+    Sti { sr: RegisterEnum, offset: i16 },
+    Str { sr: RegisterEnum, base: RegisterEnum, offset: i16 },
+    Trap { vector: u8 },
+    Rti,
+}
+
+/// Why a word does not decode to an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The reserved opcode `1101`; holds the whole word.
+    ReservedOpcode(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::ReservedOpcode(word) => write!(f, "reserved opcode in {:#06X}", word),
+        }
+    }
+}
+
+/// Decode an instruction word. Bits the ISA leaves unused are ignored, so
+/// `encode(&decode(word)?)` gives the canonical form of `word`.
+pub fn decode(word: u16) -> Result<Instruction, DecodeError> {
+    let dr = extract_register(word, 9);
+    let sr1 = extract_register(word, 6);
+    let offset = |bits: usize| sign_extend(word & ((1 << bits) - 1), bits) as i16;
+    let src = || {
+        if word & 0x20 != 0 {
+            Operand::Immediate(offset(5))
+        } else {
+            Operand::Register(extract_register(word, 0))
+        }
+    };
+
+    Ok(match extract_op_code(word) {
+        Ok(OpCode::Add) => Instruction::Add { dr, sr1, src: src() },
+        Ok(OpCode::And) => Instruction::And { dr, sr1, src: src() },
+        Ok(OpCode::Not) => Instruction::Not { dr, sr: sr1 },
+        Ok(OpCode::Br) => Instruction::Br { nzp: ((word >> 9) & 0x7) as u8, offset: offset(9) },
+        Ok(OpCode::Jmp) => Instruction::Jmp { base: sr1 },
+        Ok(OpCode::Jsr) if word & 0x0800 != 0 => Instruction::Jsr { offset: offset(11) },
+        Ok(OpCode::Jsr) => Instruction::Jsrr { base: sr1 },
+        Ok(OpCode::Ld) => Instruction::Ld { dr, offset: offset(9) },
+        Ok(OpCode::Ldi) => Instruction::Ldi { dr, offset: offset(9) },
+        Ok(OpCode::Ldr) => Instruction::Ldr { dr, base: sr1, offset: offset(6) },
+        Ok(OpCode::Lea) => Instruction::Lea { dr, offset: offset(9) },
+        Ok(OpCode::St) => Instruction::St { sr: dr, offset: offset(9) },
+        Ok(OpCode::Sti) => Instruction::Sti { sr: dr, offset: offset(9) },
+        Ok(OpCode::Str) => Instruction::Str { sr: dr, base: sr1, offset: offset(6) },
+        Ok(OpCode::Trap) => Instruction::Trap { vector: (word & 0xFF) as u8 },
+        Ok(OpCode::Rti) => Instruction::Rti,
+        Ok(OpCode::Res) | Err(_) => return Err(DecodeError::ReservedOpcode(word)),
+    })
+}
+
+/// Encode `instruction` as a word; the inverse of `decode`. Offsets and immediates are
+/// truncated to their field width.
+pub fn encode(instruction: &Instruction) -> u16 {
+    let op = |opcode: OpCode| (opcode as u16) << 12;
+    let reg = |r: RegisterEnum, shift: u16| (r as u16) << shift;
+    let field = |value: i16, bits: u16| value as u16 & ((1 << bits) - 1);
+    let src = |src: Operand| match src {
+        Operand::Register(sr2) => reg(sr2, 0),
+        Operand::Immediate(imm5) => 0x20 | field(imm5, 5),
+    };
+
+    match *instruction {
+        Instruction::Add { dr, sr1, src: s } => op(OpCode::Add) | reg(dr, 9) | reg(sr1, 6) | src(s),
+        Instruction::And { dr, sr1, src: s } => op(OpCode::And) | reg(dr, 9) | reg(sr1, 6) | src(s),
+        Instruction::Not { dr, sr } => op(OpCode::Not) | reg(dr, 9) | reg(sr, 6) | 0x3F,
+        Instruction::Br { nzp, offset } => op(OpCode::Br) | ((nzp as u16 & 0x7) << 9) | field(offset, 9),
+        Instruction::Jmp { base } => op(OpCode::Jmp) | reg(base, 6),
+        Instruction::Jsr { offset } => op(OpCode::Jsr) | 0x0800 | field(offset, 11),
+        Instruction::Jsrr { base } => op(OpCode::Jsr) | reg(base, 6),
+        Instruction::Ld { dr, offset } => op(OpCode::Ld) | reg(dr, 9) | field(offset, 9),
+        Instruction::Ldi { dr, offset } => op(OpCode::Ldi) | reg(dr, 9) | field(offset, 9),
+        Instruction::Ldr { dr, base, offset } => op(OpCode::Ldr) | reg(dr, 9) | reg(base, 6) | field(offset, 6),
+        Instruction::Lea { dr, offset } => op(OpCode::Lea) | reg(dr, 9) | field(offset, 9),
+        Instruction::St { sr, offset } => op(OpCode::St) | reg(sr, 9) | field(offset, 9),
+        Instruction::Sti { sr, offset } => op(OpCode::Sti) | reg(sr, 9) | field(offset, 9),
+        Instruction::Str { sr, base, offset } => op(OpCode::Str) | reg(sr, 9) | reg(base, 6) | field(offset, 6),
+        Instruction::Trap { vector } => op(OpCode::Trap) | vector as u16,
+        Instruction::Rti => op(OpCode::Rti),
+    }
+}
+
+/// Decodes and executes a single instruction word.
+///
+/// Returns `Err` when the instruction stops the machine (HALT, an unknown TRAP
+/// vector, a console failure, or an exception with no handler installed). TRAPs are
+/// serviced according to `trap_mode`; the built-in routines do their character I/O
+/// through `console`.
+pub fn execute_instruction(
+    instr: u16,
+    registers: &mut Registers,
+    memory: &mut Memory,
+    console: &RefCell<dyn Console>,
+    trap_mode: TrapMode,
+) -> Result<(), ExitReason> {
+    execute_decoded(decode(instr), registers, memory, console, trap_mode)
+}
+
+/// `execute_instruction` for a word that has already been through `decode`; the
+/// reserved opcode raises the illegal opcode exception.
+pub fn execute_decoded(
+    decoded: Result<Instruction, DecodeError>,
+    registers: &mut Registers,
+    memory: &mut Memory,
+    console: &RefCell<dyn Console>,
+    trap_mode: TrapMode,
+) -> Result<(), ExitReason> {
+    match decoded {
+        Ok(instruction) => execute(instruction, registers, memory, console, trap_mode),
+        Err(DecodeError::ReservedOpcode(word)) => {
+            // The PC has already been incremented past the offending word.
+            let pc = registers.read(RegisterEnum::PC).wrapping_sub(1);
+            interrupt::raise(
+                registers,
+                memory,
+                Exception::IllegalOpcode,
+                ExitReason::IllegalOpcode(pc, word),
+            )
+        }
+    }
+}
+
+/// Executes an already decoded instruction, see `execute_instruction`.
+///
+/// In user mode, an instruction that touches system space or the device registers
+/// is undone and an access control violation is raised in its place.
+pub fn execute(
+    instruction: Instruction,
+    registers: &mut Registers,
+    memory: &mut Memory,
+    console: &RefCell<dyn Console>,
+    trap_mode: TrapMode,
+) -> Result<(), ExitReason> {
+    if !registers.is_user_mode() {
+        return dispatch(instruction, registers, memory, console, trap_mode);
+    }
+
+    let saved = registers.clone();
+    memory.set_user_mode(true);
+    let result = dispatch(instruction, registers, memory, console, trap_mode);
+    memory.set_user_mode(false);
+
+    match memory.take_violation() {
+        Some(address) => {
+            *registers = saved;
+            let pc = registers.read(RegisterEnum::PC).wrapping_sub(1);
+            interrupt::raise(
+                registers,
+                memory,
+                Exception::AccessControlViolation,
+                ExitReason::AccessViolation(pc, address),
+            )
         }
-        Ok(OpCode::Res) => {
-            eprintln!("RES instruction encountered: {:#06X}", instr);
+        None => result,
+    }
+}
+
+fn dispatch(
+    instruction: Instruction,
+    registers: &mut Registers,
+    memory: &mut Memory,
+    console: &RefCell<dyn Console>,
+    trap_mode: TrapMode,
+) -> Result<(), ExitReason> {
+    match instruction {
+        Instruction::Add { dr, sr1, src } => Instructions::add(dr, sr1, src, registers),
+        Instruction::And { dr, sr1, src } => Instructions::bitwise_and(dr, sr1, src, registers),
+        Instruction::Not { dr, sr } => Instructions::bitwise_not(dr, sr, registers),
+        Instruction::Br { nzp, offset } => Instructions::br(nzp, offset, registers),
+        Instruction::Jmp { base } => Instructions::jmp(base, registers),
+        Instruction::Jsr { offset } => Instructions::jsr(offset, registers),
+        Instruction::Jsrr { base } => Instructions::jsrr(base, registers),
+        Instruction::Ld { dr, offset } => Instructions::ld(dr, offset, registers, memory),
+        Instruction::Ldi { dr, offset } => Instructions::ldi(dr, offset, registers, memory),
+        Instruction::Ldr { dr, base, offset } => Instructions::ldr(dr, base, offset, registers, memory),
+        Instruction::Lea { dr, offset } => Instructions::lea(dr, offset, registers),
+        Instruction::St { sr, offset } => Instructions::st(sr, offset, registers, memory),
+        Instruction::Sti { sr, offset } => Instructions::sti(sr, offset, registers, memory),
+        Instruction::Str { sr, base, offset } => Instructions::str(sr, base, offset, registers, memory),
+        Instruction::Trap { vector } => {
+            // The trap vector table is system space, and the built-in service routines
+            // stand in for the OS; either way the rest of the TRAP runs privileged
+            memory.set_user_mode(false);
+            return match trap_mode {
+                TrapMode::Fast => Instructions::trap(vector, registers, memory, console),
+                mode => trap::vectored(vector, registers, memory, mode),
+            };
         }
-        Err(_) => eprintln!("Invalid opcode in instruction: {:#06X}", instr),
+        Instruction::Rti => return Instructions::rti(registers, memory),
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::lc3::cpu::opcode::OpCode;
+    use crate::lc3::sys::console::BufferConsole;
 
     #[test]
     fn test_extract_all_opcodes() {
@@ -52,4 +258,93 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_encode_inverts_decode() {
+        use RegisterEnum::*;
+        let instructions = [
+            Instruction::Add { dr: R1, sr1: R2, src: Operand::Immediate(-3) },
+            Instruction::And { dr: R0, sr1: R7, src: Operand::Register(R5) },
+            Instruction::Not { dr: R4, sr: R5 },
+            Instruction::Br { nzp: 0b101, offset: -256 },
+            Instruction::Jmp { base: R7 },
+            Instruction::Jsr { offset: 1023 },
+            Instruction::Jsrr { base: R3 },
+            Instruction::Ld { dr: R0, offset: 255 },
+            Instruction::Ldi { dr: R1, offset: -1 },
+            Instruction::Ldr { dr: R2, base: R6, offset: -32 },
+            Instruction::Lea { dr: R3, offset: 0 },
+            Instruction::St { sr: R4, offset: 7 },
+            Instruction::Sti { sr: R5, offset: -7 },
+            Instruction::Str { sr: R6, base: R5, offset: 31 },
+            Instruction::Trap { vector: 0x25 },
+            Instruction::Rti,
+        ];
+        for instruction in instructions {
+            assert_eq!(decode(encode(&instruction)), Ok(instruction), "{:?}", instruction);
+        }
+        assert_eq!(encode(&Instruction::Add { dr: R1, sr1: R2, src: Operand::Immediate(-3) }), 0x12BD);
+        // Every word that isn't the reserved opcode survives a round trip up to unused bits
+        for word in 0..=u16::MAX {
+            match decode(word) {
+                Ok(instruction) => assert_eq!(decode(encode(&instruction)), Ok(instruction)),
+                Err(error) => assert_eq!(error, DecodeError::ReservedOpcode(word)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reserved_opcode_is_illegal() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        let console = RefCell::new(BufferConsole::default());
+        registers.write(RegisterEnum::PC, 0x3001);
+
+        let result = execute_instruction(0xD000, &mut registers, &mut memory, &console, TrapMode::Fast);
+        assert_eq!(result, Err(ExitReason::IllegalOpcode(0x3000, 0xD000)));
+    }
+
+    #[test]
+    fn test_reserved_opcode_dispatches_to_handler() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        let console = RefCell::new(BufferConsole::default());
+        registers.write(RegisterEnum::R6, 0x3000);
+        registers.write(RegisterEnum::PC, 0x3001);
+        memory.write(0x0101, 0x0600);
+
+        let result = execute_instruction(0xD000, &mut registers, &mut memory, &console, TrapMode::Fast);
+        assert_eq!(result, Ok(()));
+        assert_eq!(registers.read(RegisterEnum::PC), 0x0600);
+        assert_eq!(memory.read(0x2FFF), 0x0000);
+        assert_eq!(memory.read(0x2FFE), 0x3001);
+    }
+
+    #[test]
+    fn test_user_mode_access_violation_is_undone() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        let console = RefCell::new(BufferConsole::default());
+        registers.saved_ssp = 0x3000;
+        registers.set_psr(0x8002);
+        registers.write(RegisterEnum::R6, 0xFE00);
+        registers.write(RegisterEnum::R2, 0x0200);
+        registers.write(RegisterEnum::PC, 0x3001);
+        memory.write(0x0200, 0x1234);
+
+        // LDR R1, R2, #0 reads system space
+        let result = execute_instruction(0x6280, &mut registers, &mut memory, &console, TrapMode::Fast);
+        assert_eq!(result, Err(ExitReason::AccessViolation(0x3000, 0x0200)));
+        assert_eq!(registers.read(RegisterEnum::R1), 0);
+        assert!(registers.is_user_mode());
+
+        // With a handler installed, it runs in supervisor mode
+        memory.write(0x0102, 0x0700);
+        let result = execute_instruction(0x6280, &mut registers, &mut memory, &console, TrapMode::Fast);
+        assert_eq!(result, Ok(()));
+        assert!(!registers.is_user_mode());
+        assert_eq!(registers.read(RegisterEnum::PC), 0x0700);
+        assert_eq!(memory.read(0x2FFF), 0x8002);
+        assert_eq!(memory.read(0x2FFE), 0x3001);
+    }
 }
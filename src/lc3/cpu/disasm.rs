@@ -0,0 +1,166 @@
+use std::fmt;
+
+use crate::lc3::cpu::decode::{decode, DecodeError, Instruction, Operand};
+use crate::lc3::cpu::trap::TrapCode;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::sys::symbols::SymbolTable;
+
+/// An instruction word as fetched from `pc`, ready to be shown in assembler syntax.
+/// PC-relative offsets are shown as the addresses they refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disassembly {
+    pub pc: u16,
+    pub word: u16,
+    /// `Err` for the reserved opcode, which is shown as data.
+    pub instruction: Result<Instruction, DecodeError>,
+}
+
+/// Decode `word` as if it were fetched from `pc`.
+pub fn disassemble(word: u16, pc: u16) -> Disassembly {
+    Disassembly { pc, word, instruction: decode(word) }
+}
+
+impl Disassembly {
+    /// Render with addresses replaced by labels from `symbols` where one is close enough.
+    pub fn with_symbols<'a>(&'a self, symbols: &'a SymbolTable) -> WithSymbols<'a> {
+        WithSymbols { disassembly: self, symbols }
+    }
+
+    fn render(&self, f: &mut fmt::Formatter<'_>, symbols: Option<&SymbolTable>) -> fmt::Result {
+        let reg = |r: &RegisterEnum| format!("R{}", *r as u8);
+        // PC-relative operands count from the incremented PC
+        let addr = |offset: &i16| {
+            let address = self.pc.wrapping_add(1).wrapping_add(*offset as u16);
+            match symbols {
+                Some(symbols) => symbols.describe(address),
+                None => format!("x{:04X}", address),
+            }
+        };
+        let operand = |operand: &Operand| match operand {
+            Operand::Register(r) => reg(r),
+            Operand::Immediate(n) => format!("#{}", n),
+        };
+
+        let instruction = match &self.instruction {
+            Ok(instruction) => instruction,
+            Err(_) => return write!(f, ".FILL x{:04X}", self.word),
+        };
+        match instruction {
+            Instruction::Add { dr, sr1, src } => write!(f, "ADD {}, {}, {}", reg(dr), reg(sr1), operand(src)),
+            Instruction::And { dr, sr1, src } => write!(f, "AND {}, {}, {}", reg(dr), reg(sr1), operand(src)),
+            Instruction::Not { dr, sr } => write!(f, "NOT {}, {}", reg(dr), reg(sr)),
+            // Never branches
+            Instruction::Br { nzp: 0, .. } => write!(f, "NOP"),
+            Instruction::Br { nzp, offset } => {
+                let flags: String = [(4, 'n'), (2, 'z'), (1, 'p')]
+                    .iter()
+                    .filter(|(bit, _)| nzp & bit != 0)
+                    .map(|&(_, c)| c)
+                    .collect();
+                write!(f, "BR{} {}", flags, addr(offset))
+            }
+            Instruction::Jmp { base: RegisterEnum::R7 } => write!(f, "RET"),
+            Instruction::Jmp { base } => write!(f, "JMP {}", reg(base)),
+            Instruction::Jsr { offset } => write!(f, "JSR {}", addr(offset)),
+            Instruction::Jsrr { base } => write!(f, "JSRR {}", reg(base)),
+            Instruction::Ld { dr, offset } => write!(f, "LD {}, {}", reg(dr), addr(offset)),
+            Instruction::Ldi { dr, offset } => write!(f, "LDI {}, {}", reg(dr), addr(offset)),
+            Instruction::Ldr { dr, base, offset } => write!(f, "LDR {}, {}, #{}", reg(dr), reg(base), offset),
+            Instruction::Lea { dr, offset } => write!(f, "LEA {}, {}", reg(dr), addr(offset)),
+            Instruction::St { sr, offset } => write!(f, "ST {}, {}", reg(sr), addr(offset)),
+            Instruction::Sti { sr, offset } => write!(f, "STI {}, {}", reg(sr), addr(offset)),
+            Instruction::Str { sr, base, offset } => write!(f, "STR {}, {}, #{}", reg(sr), reg(base), offset),
+            Instruction::Trap { vector } => match trap_alias(*vector) {
+                Some(alias) => write!(f, "{}", alias),
+                None => write!(f, "TRAP x{:02X}", vector),
+            },
+            Instruction::Rti => write!(f, "RTI"),
+        }
+    }
+}
+
+/// The assembler alias of a built-in trap vector.
+fn trap_alias(vector: u8) -> Option<&'static str> {
+    const ALIASES: [(u8, &str); 6] = [
+        (TrapCode::Getc as u8, "GETC"),
+        (TrapCode::Out as u8, "OUT"),
+        (TrapCode::Puts as u8, "PUTS"),
+        (TrapCode::In as u8, "IN"),
+        (TrapCode::Putsp as u8, "PUTSP"),
+        (TrapCode::Halt as u8, "HALT"),
+    ];
+    ALIASES.into_iter().find(|&(code, _)| code == vector).map(|(_, alias)| alias)
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(f, None)
+    }
+}
+
+/// A `Disassembly` rendered with labels, see `Disassembly::with_symbols`.
+pub struct WithSymbols<'a> {
+    disassembly: &'a Disassembly,
+    symbols: &'a SymbolTable,
+}
+
+impl fmt::Display for WithSymbols<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.disassembly.render(f, Some(self.symbols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(word: u16, pc: u16) -> String {
+        disassemble(word, pc).to_string()
+    }
+
+    #[test]
+    fn test_operate_instructions() {
+        assert_eq!(text(0x12BD, 0x3000), "ADD R1, R2, #-3");
+        assert_eq!(text(0x1283, 0x3000), "ADD R1, R2, R3");
+        assert_eq!(text(0x5020, 0x3000), "AND R0, R0, #0");
+        assert_eq!(text(0x997F, 0x3000), "NOT R4, R5");
+    }
+
+    #[test]
+    fn test_control_flow_resolves_targets() {
+        assert_eq!(text(0x0DFB, 0x3004), "BRnz x3000");
+        assert_eq!(text(0x0FFF, 0x3000), "BRnzp x3000");
+        assert_eq!(text(0x0000, 0x3000), "NOP");
+        assert_eq!(text(0xC1C0, 0x3000), "RET");
+        assert_eq!(text(0xC0C0, 0x3000), "JMP R3");
+        assert_eq!(text(0x4FF7, 0x3008), "JSR x3000");
+        assert_eq!(text(0x4080, 0x3000), "JSRR R2");
+        assert_eq!(text(0x8000, 0x3000), "RTI");
+    }
+
+    #[test]
+    fn test_memory_instructions() {
+        assert_eq!(text(0x2008, 0x300A), "LD R0, x3013");
+        assert_eq!(text(0xA207, 0x300B), "LDI R1, x3013");
+        assert_eq!(text(0x65BF, 0x3000), "LDR R2, R6, #-1");
+        assert_eq!(text(0xE7F2, 0x300D), "LEA R3, x3000");
+        assert_eq!(text(0x759F, 0x3000), "STR R2, R6, #31");
+    }
+
+    #[test]
+    fn test_traps_and_reserved() {
+        assert_eq!(text(0xF025, 0x3000), "HALT");
+        assert_eq!(text(0xF022, 0x3000), "PUTS");
+        assert_eq!(text(0xF030, 0x3000), "TRAP x30");
+        assert_eq!(text(0xD123, 0x3000), ".FILL xD123");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols: SymbolTable = [("LOOP", 0x3000), ("DATA", 0x3010)].into_iter().collect();
+        let instruction = disassemble(0x0DFB, 0x3004);
+        assert_eq!(instruction.with_symbols(&symbols).to_string(), "BRnz LOOP");
+        let instruction = disassemble(0x2008, 0x300A);
+        assert_eq!(instruction.with_symbols(&symbols).to_string(), "LD R0, DATA+3");
+    }
+}
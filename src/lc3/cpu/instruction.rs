@@ -1,6 +1,11 @@
 use super::super::hardware::Memory::Memory;
-use super::super::hardware::Reg::{RegisterEnum, Registers};
+use super::super::hardware::Reg::{RegisterEnum, Registers, PSR_USER_MODE};
+use super::decode::Operand;
+use super::interrupt::{self, Exception};
 use super::trap;
+use crate::lc3::sys::console::Console;
+use crate::lc3::vm::ExitReason;
+use std::cell::RefCell;
 /// Represents LC-3 instructions and their implementations.
 pub struct Instructions;
 
@@ -13,25 +18,8 @@ impl Instructions {
     /// The ADD instruction can either:
     /// - Add two registers: `ADD DR, SR1, SR2`
     /// - Add a register and an immediate value: `ADD DR, SR1, imm5`
-    pub fn add(instr: u16, registers: &mut Registers) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-        // Extract first source register (SR1)
-        let sr1 = extract_register(instr, 6);
-        // Determine if immediate mode is used
-        let imm_flag = (instr >> 5) & 0x1;
-
-        let result = if imm_flag != 0 {
-            // Sign-extend the 5-bit immediate value
-            let imm5 = sign_extend(instr & 0x1F, 5);
-            // Perform addition with immediate value
-            registers.read(sr1).wrapping_add(imm5)
-        } else {
-            // Extract second source register (SR2)
-            let sr2 = extract_register(instr, 0);
-            // Perform addition with two registers
-            registers.read(sr1).wrapping_add(registers.read(sr2))
-        };
+    pub fn add(dr: RegisterEnum, sr1: RegisterEnum, src: Operand, registers: &mut Registers) {
+        let result = registers.read(sr1).wrapping_add(operand(src, registers));
 
         // Write the result to the destination register
         registers.write(dr, result);
@@ -45,18 +33,9 @@ impl Instructions {
     // |  Opcode    |   DR       |      PCoffset9           |
     // +------------+------------+---------------------------+
 
-    pub fn ldi(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-
-        // Extract PCoffset9 and sign-extend it
-        let pc_offset = sign_extend(instr & 0x1FF, 9) as i16;
-
-        // Read current PC
-        let pc = registers.read(RegisterEnum::PC) as i16;
-
+    pub fn ldi(dr: RegisterEnum, pc_offset: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate the first address: PC + PCoffset9
-        let addr1 = (pc.wrapping_add(pc_offset)) as usize;
+        let addr1 = pc_relative(pc_offset, registers) as usize;
 
         // Read the address stored at addr1
         let addr2 = memory.read(addr1) as usize;
@@ -79,35 +58,11 @@ impl Instructions {
     /// and the result stored in DR. The condition codes are set, based on whether the binary value produced,
     /// taken as a 2’s complement integer, is negative, zero, or positive.
     ///  BIT-Wise AND instruction.
-    pub fn bitwise_and(instr: u16, registers: &mut Registers) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-
-        // Extract source register (SR1)
-        let sr1 = extract_register(instr, 6);
-
-        // Check immediate flag
-        let imm_flag = (instr >> 5) & 0x1;
-
-        if imm_flag != 0 {
-            // Immediate mode: Extract and sign-extend imm5
-            let imm5 = sign_extend(instr & 0x1F, 5);
-
-            // Perform bitwise AND with immediate value
-            let result = registers.read(sr1) & imm5;
+    pub fn bitwise_and(dr: RegisterEnum, sr1: RegisterEnum, src: Operand, registers: &mut Registers) {
+        let result = registers.read(sr1) & operand(src, registers);
 
-            // Write the result to the destination register
-            registers.write(dr, result);
-        } else {
-            // Register mode: Extract source register 2 (SR2)
-            let sr2 = extract_register(instr, 0);
-
-            // Perform bitwise AND with second register
-            let result = registers.read(sr1) & registers.read(sr2);
-
-            // Write the result to the destination register
-            registers.write(dr, result);
-        }
+        // Write the result to the destination register
+        registers.write(dr, result);
 
         // Update condition flags based on the result
         registers.update_flags(dr);
@@ -119,12 +74,7 @@ impl Instructions {
     /// - Performs a bitwise NOT on the value in SR.
     /// - Stores the result in DR.
     /// - Updates condition flags based on the result.
-    pub fn bitwise_not(instr: u16, registers: &mut Registers) {
-        // Extract destination register (DR) from bits 11-9
-        let dr = extract_register(instr, 9);
-        // Extract source register (SR) from bits 8-6
-        let sr = extract_register(instr, 6);
-
+    pub fn bitwise_not(dr: RegisterEnum, sr: RegisterEnum, registers: &mut Registers) {
         // Perform bitwise NOT on the value in SR
         let value = !registers.read(sr);
 
@@ -139,23 +89,15 @@ impl Instructions {
     /// - Checks the condition flags (`n`, `z`, `p`).
     /// - If any of the specified flags match the current condition flags, branch is taken.
     /// - Branching is performed by adding the sign-extended `PCoffset9` to the current `PC`.
-    pub fn br(instr: u16, registers: &mut Registers) {
-        // Extract condition flags from bits 11-9
-        let cond_flag = (instr >> 9) & 0x7; // 3 bits: n, z, p
-
-        let pc_offset = sign_extend(instr & 0x1FF, 9) as i16;
-
-        // Current PC
-        let pc = registers.read(RegisterEnum::PC) as i16;
-
+    pub fn br(nzp: u8, pc_offset: i16, registers: &mut Registers) {
         // Current condition flags
-        let current_cond = registers.read(RegisterEnum::COND) as u16;
+        let current_cond = registers.read(RegisterEnum::COND);
 
         // Check if any of the specified condition flags are set
-        if cond_flag & current_cond != 0 {
+        if nzp as u16 & current_cond != 0 {
             // Branch is taken: update PC
-            let new_pc = pc.wrapping_add(pc_offset);
-            registers.write(RegisterEnum::PC, new_pc as u16);
+            let new_pc = pc_relative(pc_offset, registers);
+            registers.write(RegisterEnum::PC, new_pc);
         }
         // If branch not taken, PC remains unchanged (already incremented)
     }
@@ -167,11 +109,7 @@ impl Instructions {
     // +------------+-----+-----------+
     /// - Sets PC to the value contained in BaseR.
     /// - Also handles the RET instruction when BaseR is R7.
-    pub fn jmp(instr: u16, registers: &mut Registers) {
-        // Extract Base Register (BaseR) from bits 11-9
-        let base_r_index = (instr >> 6) & 0x7; // Bits 8-6 represent BaseR
-        let base_r = RegisterEnum::try_from(base_r_index as usize).expect("Invalid Base Register");
-
+    pub fn jmp(base_r: RegisterEnum, registers: &mut Registers) {
         // Retrieve the value from BaseR
         let target_address = registers.read(base_r);
 
@@ -189,24 +127,25 @@ impl Instructions {
     /// - Adds the sign-extended PCoffset11 to the current PC to get the target address.
     /// - Sets PC to the target address.
 
-    pub fn jsr(instr: u16, registers: &mut Registers) {
-        let long_flag = (instr >> 11) & 0x1;
+    pub fn jsr(pc_offset: i16, registers: &mut Registers) {
+        let new_pc = pc_relative(pc_offset, registers);
 
         // Save the current PC into R7
         let current_pc = registers.read(RegisterEnum::PC);
         registers.write(RegisterEnum::R7, current_pc);
+        registers.write(RegisterEnum::PC, new_pc);
+    }
 
-        if long_flag != 0 {
-            // JSR: Use PC-relative offset
-            let pc_offset = sign_extend(instr & 0x7FF, 11);
-            let new_pc = current_pc.wrapping_add(pc_offset as u16);
-            registers.write(RegisterEnum::PC, new_pc);
-        } else {
-            // JSRR: Use base register
-            let base_reg = extract_register(instr, 6);
-            let target_pc = registers.read(base_reg);
-            registers.write(RegisterEnum::PC, target_pc);
-        }
+    /// `JSRR BaseR`:
+    /// - Stores the current PC in R7 and jumps to the address in BaseR.
+    /// - BaseR is read before R7 is written, so `JSRR R7` works.
+    pub fn jsrr(base_reg: RegisterEnum, registers: &mut Registers) {
+        let target_pc = registers.read(base_reg);
+
+        // Save the current PC into R7
+        let current_pc = registers.read(RegisterEnum::PC);
+        registers.write(RegisterEnum::R7, current_pc);
+        registers.write(RegisterEnum::PC, target_pc);
     }
     /// LD
     /// 15        12 11        9 8                         0
@@ -214,14 +153,9 @@ impl Instructions {
     /// |   Opcode   | Destination |        PCoffset9         |
     /// +------------+------------+---------------------------+
 
-    pub fn ld(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-        let pc_offset = sign_extend(instr & 0x1FF, 9);
-
+    pub fn ld(dr: RegisterEnum, pc_offset: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate target address: PC + PCoffset9
-        let pc = registers.read(RegisterEnum::PC);
-        let target_address = pc as u32 + pc_offset as u32;
+        let target_address = pc_relative(pc_offset, registers);
 
         // Read value from memory at the target address
         let value = memory.read(target_address as usize);
@@ -242,18 +176,10 @@ impl Instructions {
     /// - Calculates the target memory address by adding the 6-bit signed `Offset6` to the value in the base register (`BaseR`).
     /// - Loads the value from the target memory address into the destination register (`DR`).
     /// - Updates the condition flags based on the loaded value.
-    pub fn ldr(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-
-        // Extract base register (BaseR)
-        let base_reg = extract_register(instr, 6);
-
-        let offset6 = sign_extend(instr & 0x3F, 6) as u32;
-
+    pub fn ldr(dr: RegisterEnum, base_reg: RegisterEnum, offset6: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate the target memory address
-        let base_address = registers.read(base_reg) as u32;
-        let target_address = (base_address + offset6) as usize;
+        let base_address = registers.read(base_reg);
+        let target_address = base_address.wrapping_add(offset6 as u16) as usize;
 
         // Read value from memory at the target address
         let value = memory.read(target_address);
@@ -276,18 +202,12 @@ impl Instructions {
     // |   Opcode   | Destination |        PCoffset9         |
     // +------------+------------+---------------------------+
 
-    pub fn lea(instr: u16, registers: &mut Registers) {
-        // Extract destination register (DR)
-        let dr = extract_register(instr, 9);
-
-        let pc_offset = sign_extend(instr & 0x1FF, 9);
-
+    pub fn lea(dr: RegisterEnum, pc_offset: i16, registers: &mut Registers) {
         // Calculate the effective address: PC + PCoffset9
-        let pc = registers.read(RegisterEnum::PC);
-        let effective_address = pc as u32 + pc_offset as u32;
+        let effective_address = pc_relative(pc_offset, registers);
 
         // Write the effective address to the destination register
-        registers.write(dr, effective_address as u16);
+        registers.write(dr, effective_address);
 
         // Update condition flags based on the effective address
         registers.update_flags(dr);
@@ -303,15 +223,9 @@ impl Instructions {
     // |   Opcode   | Source Reg  |        PCoffset9         |
     // +------------+------------+---------------------------+
 
-    pub fn st(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract source register (SR)
-        let sr = extract_register(instr, 9);
-
-        let pc_offset = sign_extend(instr & 0x1FF, 9);
-
+    pub fn st(sr: RegisterEnum, pc_offset: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate the target memory address: PC + PCoffset9
-        let pc = registers.read(RegisterEnum::PC);
-        let target_address = (pc as u32 + pc_offset as u32) as u16;
+        let target_address = pc_relative(pc_offset, registers);
 
         // Read value from the source register and write to the target memory address
         let value = registers.read(sr);
@@ -329,15 +243,9 @@ impl Instructions {
     // |   Opcode   | Source Reg  |        PCoffset9         |
     // +------------+------------+---------------------------+
 
-    pub fn sti(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract source register (SR)
-        let sr = extract_register(instr, 9);
-
-        let pc_offset = sign_extend(instr & 0x1FF, 9) as u32;
-
+    pub fn sti(sr: RegisterEnum, pc_offset: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate intermediate memory address: PC + PCoffset9
-        let pc = registers.read(RegisterEnum::PC) as u32;
-        let intermediate_address = pc + pc_offset;
+        let intermediate_address = pc_relative(pc_offset, registers);
 
         // Read final address from the intermediate memory location
         let final_address = memory.read(intermediate_address as usize);
@@ -356,35 +264,73 @@ impl Instructions {
     /// `STR SR, BaseR, Offset6`:
     /// - Computes the target memory address by adding the 6-bit signed `Offset6` to the value in the base register (`BaseR`).
     /// - Stores the value from the source register (`SR`) into the computed memory address.
-    pub fn str(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        // Extract source register (SR)
-        let sr = extract_register(instr, 9);
-
-        // Extract base register (BaseR)
-        let base_reg = extract_register(instr, 6);
-
-        // Extract Offset6 and sign-extend it
-        let offset6 = sign_extend(instr & 0x3F, 6);
-
+    pub fn str(sr: RegisterEnum, base_reg: RegisterEnum, offset6: i16, registers: &mut Registers, memory: &mut Memory) {
         // Calculate the target memory address: BaseR + Offset6
         let base_address = registers.read(base_reg);
-        let target_address = (base_address as u32 + offset6 as u32) as u16;
+        let target_address = base_address.wrapping_add(offset6 as u16);
 
         // Read value from the source register and write to the target memory address
         let value = registers.read(sr);
         memory.write(target_address as usize, value);
     }
-    pub fn trap(instr: u16, registers: &mut Registers, memory: &mut Memory) {
-        trap::trap(instr, registers, memory);
+    /// Executes the RTI (Return from Interrupt) instruction.
+    ///  15        12 11                               0
+    // +------------+----------------------------------+
+    // |    1000    |           000000000000           |
+    // +------------+----------------------------------+
+    /// - In supervisor mode: pops the PC, then the PSR, off the supervisor stack.
+    ///   If the restored PSR is in user mode, R6 switches back to the user stack.
+    /// - In user mode: raises a privilege mode violation exception instead.
+    pub fn rti(registers: &mut Registers, memory: &mut Memory) -> Result<(), ExitReason> {
+        if registers.is_user_mode() {
+            let pc = registers.read(RegisterEnum::PC).wrapping_sub(1);
+            return interrupt::raise(
+                registers,
+                memory,
+                Exception::PrivilegeViolation,
+                ExitReason::PrivilegeViolation(pc),
+            );
+        }
+
+        let pc = interrupt::pop(registers, memory);
+        let psr = interrupt::pop(registers, memory);
+        registers.write(RegisterEnum::PC, pc);
+        registers.set_psr(psr & !PSR_USER_MODE);
+        if psr & PSR_USER_MODE != 0 {
+            registers.enter_user_mode();
+        }
+        Ok(())
+    }
+
+    pub fn trap(
+        vector: u8,
+        registers: &mut Registers,
+        memory: &mut Memory,
+        console: &RefCell<dyn Console>,
+    ) -> Result<(), ExitReason> {
+        trap::trap(vector, registers, memory, console)
+    }
+}
+
+/// The value of an ADD/AND second operand: SR2, or the sign-extended imm5.
+fn operand(src: Operand, registers: &Registers) -> u16 {
+    match src {
+        Operand::Register(sr2) => registers.read(sr2),
+        Operand::Immediate(imm5) => imm5 as u16,
     }
 }
 
+/// The incremented PC plus a sign-extended PC offset.
+fn pc_relative(pc_offset: i16, registers: &Registers) -> u16 {
+    registers.read(RegisterEnum::PC).wrapping_add(pc_offset as u16)
+}
+
 /// Sign-extends a value to the given bit width.
 ///
 /// - `x`: The value to sign-extend.
 /// - `bit_count`: The original bit width of the value.
 
-fn sign_extend(x: u16, bit_count: usize) -> u16 {
+pub(crate) fn sign_extend(x: u16, bit_count: usize) -> u16 {
 
     // This if clause is testing the sign of the value.
     // We're moving `x` to the right up until
@@ -405,7 +351,7 @@ fn sign_extend(x: u16, bit_count: usize) -> u16 {
 /// - `instr`: The 16-bit LC-3 instruction word.
 /// - `shift`: The bit position of the register in the instruction.
 
-fn extract_register(instr: u16, shift: usize) -> RegisterEnum {
+pub(crate) fn extract_register(instr: u16, shift: usize) -> RegisterEnum {
     match (instr >> shift) & 0x7 {
         0 => RegisterEnum::R0,
         1 => RegisterEnum::R1,
@@ -1,16 +1,31 @@
 //Integration test
-use crate::lc3::cpu::instruction::Instructions;
+use crate::lc3::cpu::decode::{encode, execute_instruction, Instruction};
+use crate::lc3::cpu::instruction::extract_register;
+use crate::lc3::cpu::trap::TrapMode;
 use crate::lc3::hardware::Flag::ConditionFlags;
 use crate::lc3::hardware::Memory::Memory;
 use crate::lc3::hardware::Reg::RegisterEnum as Register;
 use crate::lc3::hardware::Reg::Registers;
+use crate::lc3::sys::console::BufferConsole;
+use crate::lc3::vm::ExitReason;
+use std::cell::RefCell;
 use std::io::{self, Read, Write};
 
+/// Decode and execute `instr` against `registers` and a fresh memory.
+fn execute(instr: u16, registers: &mut Registers) {
+    let mut memory = Memory::new();
+    execute_with_memory(instr, registers, &mut memory).unwrap();
+}
+
+/// Decode and execute `instr`, with the built-in trap routines.
+fn execute_with_memory(instr: u16, registers: &mut Registers, memory: &mut Memory) -> Result<(), ExitReason> {
+    let console = RefCell::new(BufferConsole::default());
+    execute_instruction(instr, registers, memory, &console, TrapMode::Fast)
+}
+
 fn encode_br(n: bool, z: bool, p: bool, pc_offset9: i16) -> u16 {
-    let opcode = 0b0000 << 12;
-    let flags = ((n as u16) << 11) | ((z as u16) << 10) | ((p as u16) << 9);
-    let offset = (pc_offset9 as u16) & 0x1FF; // Ensure 9 bits
-    opcode | flags | offset
+    let nzp = (n as u8) << 2 | (z as u8) << 1 | p as u8;
+    encode(&Instruction::Br { nzp, offset: pc_offset9 })
 }
 
 #[test]
@@ -23,7 +38,7 @@ fn integration_test_add_register_mode() {
 
     // Encode ADD R2, R0, R1
     let instr = 0b0001_010_000_000_001; // DR=R2, SR1=R0, SR2=R1
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = R0 + R1 = 42
     assert_eq!(registers.read(Register::R2), 42);
@@ -44,7 +59,7 @@ fn integration_test_add_immediate_mode() {
 
     // Encode ADD R2, R0, #10
     let instr = 0b0001_010_000_1_01010; // DR=R2, SR1=R0, imm5=10
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = R0 + 10 = 25
     assert_eq!(registers.read(Register::R2), 25);
@@ -65,7 +80,7 @@ fn integration_test_add_result_zero() {
 
     // Encode ADD R2, R0, R1
     let instr = 0b0001_010_000_000_001; // DR=R2, SR1=R0, SR2=R1
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = R0 + R1 = 0
     assert_eq!(registers.read(Register::R2), 0);
@@ -87,7 +102,7 @@ fn integration_test_add_overflow_negative() {
 
     // Encode ADD R2, R0, R1
     let instr = 0b0001_010_000_000_001; // DR=R2, SR1=R0, SR2=R1
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = R0 + R1 = -32768 + (-1) = 32767 (due to wrapping)
     assert_eq!(registers.read(Register::R2), 0x7FFF);
@@ -111,7 +126,7 @@ fn test_add_registers_negative() {
     let instr = 0b0001_011_001_000_010; // Opcode=0001 (ADD), DR=R3, SR1=R1, SR2=R2
 
     // Execute the ADD instruction
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R3 = R1 + R2 = -25 -> 0xFFE7
     assert_eq!(registers.read(Register::R3), 0xFFE7);
@@ -134,7 +149,7 @@ fn test_add_registers_zero() {
     let instr = 0b0001_011_001_000_010; // Opcode=0001 (ADD), DR=R3, SR1=R1, SR2=R2
 
     // Execute the ADD instruction
-    Instructions::add(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R3 = R1 + R2 = 0
     assert_eq!(registers.read(Register::R3), 0);
@@ -169,7 +184,7 @@ fn integration_test_ldi_positive_value() {
     memory.write(0x3000, 0x5555);
 
     // Execute the LDI instruction
-    Instructions::ldi(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify R1 = 0x5555
     assert_eq!(registers.read(dr), 0x5555);
@@ -206,7 +221,7 @@ fn integration_test_ldi_negative_value() {
     memory.write(0x3001, 0x8000);
 
     // Execute the LDI instruction
-    Instructions::ldi(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify R2 = 0x8000
     assert_eq!(registers.read(dr), 0x8000);
@@ -243,7 +258,7 @@ fn integration_test_ldi_zero_value() {
     memory.write(0x3002, 0x0000);
 
     // Execute the LDI instruction
-    Instructions::ldi(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify R3 = 0x0000
     assert_eq!(registers.read(dr), 0x0000);
@@ -280,7 +295,7 @@ fn integration_test_ldi_negative_pc_offset() {
     memory.write(0x3003, 0x5678);
 
     // Execute the LDI instruction
-    Instructions::ldi(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify R4 = 0x5678
     assert_eq!(registers.read(dr), 0x5678);
@@ -307,7 +322,7 @@ fn integration_test_br_take_branch_n_flag() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(1));
@@ -328,7 +343,7 @@ fn integration_test_br_take_branch_z_flag() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(2));
@@ -349,7 +364,7 @@ fn integration_test_br_take_branch_p_flag() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(3));
@@ -370,7 +385,7 @@ fn integration_test_br_take_branch_multiple_flags() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Since ZRO flag is set and BRnz checks for N or Z, branch is taken
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(4));
@@ -391,7 +406,7 @@ fn integration_test_br_not_take_branch_multiple_flags() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Since N and Z flags are not set, branch is not taken
     assert_eq!(registers.read(Register::PC), current_pc);
@@ -412,7 +427,7 @@ fn integration_test_br_no_flags() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Since no flags are specified, branch is never taken
     assert_eq!(registers.read(Register::PC), current_pc);
@@ -433,7 +448,7 @@ fn integration_test_br_backward_branch() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly (backwards by 1)
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_sub(1));
@@ -454,7 +469,7 @@ fn integration_test_br_PC_wrap_around() {
     let instr = encode_br(false, false, true, 3); // BRp with PCoffset9=3
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Expected PC: 0xFFFE + 3 = 0x0001 (wrap around)
     assert_eq!(registers.read(Register::PC), 0x0001);
@@ -475,7 +490,7 @@ fn integration_test_br_max_positive_offset() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(255));
@@ -496,7 +511,7 @@ fn integration_test_br_max_negative_offset() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify PC is updated correctly
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_sub(255));
@@ -522,7 +537,7 @@ fn integration_test_br_all_flags_set() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Since all flags are set, branch is taken
     assert_eq!(registers.read(Register::PC), current_pc.wrapping_add(1));
@@ -543,14 +558,14 @@ fn integration_test_br_no_matching_flags() {
     let current_pc = registers.read(Register::PC);
 
     // Execute the BR instruction
-    Instructions::br(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Since Negative flag is not set, branch is not taken
     assert_eq!(registers.read(Register::PC), current_pc);
 }
 
 fn encode_not(dr: u16, sr: u16) -> u16 {
-    (0b1001 << 12) | (dr << 9) | (sr << 6) | 0x3F
+    encode(&Instruction::Not { dr: extract_register(dr, 0), sr: extract_register(sr, 0) })
 }
 
 #[test]
@@ -565,7 +580,7 @@ fn integration_test_not_positive_value() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0xEDCB
     assert_eq!(registers.read(Register::R2), !0x1234);
@@ -589,7 +604,7 @@ fn integration_test_not_negative_value() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0x7FFF
     assert_eq!(registers.read(Register::R2), !0x8000);
@@ -613,7 +628,7 @@ fn integration_test_not_zero_value() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0xFFFF
     assert_eq!(registers.read(Register::R2), !0x0000);
@@ -637,7 +652,7 @@ fn integration_test_not_max_value() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0x0000
     assert_eq!(registers.read(Register::R2), !0xFFFF);
@@ -661,7 +676,7 @@ fn integration_test_not_min_value() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0x7FFF
     assert_eq!(registers.read(Register::R2), !0x8000);
@@ -685,7 +700,7 @@ fn integration_test_not_all_flags_set() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0xFFFE
     assert_eq!(registers.read(Register::R2), !0x0001);
@@ -711,7 +726,7 @@ fn integration_test_not_registers_unchanged() {
     let instr = encode_not(2, 1); // DR=R2, SR=R1
 
     // Execute the NOT instruction
-    Instructions::bitwise_not(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = ~R1 = 0xF0F0
     assert_eq!(registers.read(Register::R2), !0x0F0F);
@@ -739,7 +754,7 @@ fn integration_test_not_multiple_operations() {
     let instr1 = encode_not(3, 1); // DR=R3, SR=R1
 
     // Execute the first NOT instruction
-    Instructions::bitwise_not(instr1, &mut registers);
+    execute(instr1, &mut registers);
 
     // Verify R3 = ~R1 = 0xFF00
     assert_eq!(registers.read(Register::R3), !0x00FF);
@@ -754,7 +769,7 @@ fn integration_test_not_multiple_operations() {
     let instr2 = encode_not(4, 2); // DR=R4, SR=R2
 
     // Execute the second NOT instruction
-    Instructions::bitwise_not(instr2, &mut registers);
+    execute(instr2, &mut registers);
 
     // Verify R4 = ~R2 = 0x00FF
     assert_eq!(registers.read(Register::R4), !0xFF00);
@@ -1035,10 +1050,7 @@ fn integration_test_not_multiple_operations() {
 // }
 
 fn encode_jsr(pc_offset11: i16) -> u16 {
-    let opcode = 0b0100 << 12; // Opcode for JSR
-    let long_flag = 1 << 11; // long_flag set to 1 for JSR
-    let offset = (pc_offset11 as u16) & 0x07FF; // Ensure 11 bits
-    opcode | long_flag | offset
+    encode(&Instruction::Jsr { offset: pc_offset11 })
 }
 
 /// Helper function to encode the JSRR instruction.
@@ -1047,10 +1059,7 @@ fn encode_jsr(pc_offset11: i16) -> u16 {
 ///
 /// Returns the encoded 16-bit instruction.
 fn encode_jsrr(base_reg: usize) -> u16 {
-    let opcode = 0b0100 << 12; // Opcode for JSRR
-    let long_flag = 0 << 11; // long_flag set to 0 for JSRR
-    let base = (base_reg & 0x7) << 6; // Ensure base_reg is 3 bits
-    (opcode | long_flag | base) as u16
+    encode(&Instruction::Jsrr { base: extract_register(base_reg as u16, 0) })
 }
 
 // not in the mood maybe later
@@ -1065,7 +1074,7 @@ fn integration_test_lea_positive_offset() {
     let instr = (0b1110 << 12) | (1 << 9) | 0x002; // Opcode=1110 (LEA), DR=R1, PCoffset9=2
 
     // Execute the LEA instruction
-    Instructions::lea(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R1 = PC + 2 = 0x3002
     assert_eq!(registers.read(Register::R1), 0x3002);
@@ -1088,7 +1097,7 @@ fn integration_test_lea_negative_offset() {
     let instr = (0b1110 << 12) | (2 << 9) | 0x1FE; // PCoffset9=-2 (0x1FE is -2 in 9-bit two's complement)
 
     // Execute the LEA instruction
-    Instructions::lea(instr, &mut registers);
+    execute(instr, &mut registers);
 
     // Verify R2 = PC - 2 = 0x3000
     assert_eq!(registers.read(Register::R2), 0x3000);
@@ -1115,7 +1124,7 @@ fn integration_test_store_positive_offset() {
     let instr = (0b0011 << 12) | (1 << 9) | 0x002; // Opcode=0011 (ST), SR=R1, PCoffset9=2
 
     // Execute the ST instruction
-    Instructions::st(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at PC + 2 = 0x3002 contains 0x1234
     assert_eq!(memory.read(0x3002), 0x1234);
@@ -1136,7 +1145,7 @@ fn integration_test_store_negative_offset() {
     let instr = (0b0011 << 12) | (2 << 9) | 0x1FE; // PCoffset9=-2 (0x1FE is -2 in 9-bit two's complement)
 
     // Execute the ST instruction
-    Instructions::st(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at PC - 2 = 0x3000 contains 0xFFFF
     assert_eq!(memory.read(0x3000), 0xFFFF);
@@ -1160,7 +1169,7 @@ fn integration_test_store_indirect_positive_offset() {
     let instr = (0b1011 << 12) | (1 << 9) | 0x002; // Opcode=1011 (STI), SR=R1, PCoffset9=2
 
     // Execute the STI instruction
-    Instructions::sti(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at final address 0x4000 contains 0x1234
     assert_eq!(memory.read(0x4000), 0x1234);
@@ -1184,7 +1193,7 @@ fn integration_test_store_indirect_negative_offset() {
     let instr = (0b1011 << 12) | (2 << 9) | 0x1FE; // PCoffset9=-2 (0x1FE is -2 in 9-bit two's complement)
 
     // Execute the STI instruction
-    Instructions::sti(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at final address 0x2000 contains 0xFFFF
     assert_eq!(memory.read(0x2000), 0xFFFF);
@@ -1205,7 +1214,7 @@ fn integration_test_store_register_positive_offset() {
     let instr = (0b0111 << 12) | (2 << 9) | (1 << 6) | 0x02; // Opcode=0111 (STR), SR=R2, BaseR=R1, Offset6=2
 
     // Execute the STR instruction
-    Instructions::str(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at BaseR + Offset6 = 0x3002 contains 0x1234
     assert_eq!(memory.read(0x3002), 0x1234);
@@ -1226,8 +1235,82 @@ fn integration_test_store_register_negative_offset() {
     let instr = (0b0111 << 12) | (3 << 9) | (1 << 6) | 0x3E; // Offset6=-2 (0x3E is -2 in 6-bit two's complement)
 
     // Execute the STR instruction
-    Instructions::str(instr, &mut registers, &mut memory);
+    execute_with_memory(instr, &mut registers, &mut memory).unwrap();
 
     // Verify memory at BaseR + Offset6 = 0x3000 contains 0xFFFF
     assert_eq!(memory.read(0x3000), 0xFFFF);
 }
+
+#[test]
+fn integration_test_rti_returns_to_user_mode() {
+    let mut registers = Registers::new();
+    let mut memory = Memory::new();
+
+    // Supervisor stack holds the return PC and a user-mode PSR with Z set
+    registers.write(Register::R6, 0x2FFE);
+    memory.write(0x2FFE, 0x3010);
+    memory.write(0x2FFF, 0x8002);
+    registers.saved_usp = 0xFDF0;
+
+    assert_eq!(execute_with_memory(0x8000, &mut registers, &mut memory), Ok(()));
+
+    assert_eq!(registers.read(Register::PC), 0x3010);
+    assert_eq!(registers.psr(), 0x8002);
+    assert!(registers.is_user_mode());
+    // R6 is back on the user stack, and the popped SSP was saved
+    assert_eq!(registers.read(Register::R6), 0xFDF0);
+    assert_eq!(registers.saved_ssp, 0x3000);
+}
+
+#[test]
+fn integration_test_rti_stays_in_supervisor_mode() {
+    let mut registers = Registers::new();
+    let mut memory = Memory::new();
+
+    registers.write(Register::R6, 0x2FFE);
+    memory.write(0x2FFE, 0x0400);
+    memory.write(0x2FFF, 0x0301); // supervisor, priority 3, P
+
+    assert_eq!(execute_with_memory(0x8000, &mut registers, &mut memory), Ok(()));
+
+    assert_eq!(registers.read(Register::PC), 0x0400);
+    assert_eq!(registers.read(Register::R6), 0x3000);
+    assert_eq!(registers.priority(), 3);
+    assert!(!registers.is_user_mode());
+}
+
+#[test]
+fn integration_test_rti_in_user_mode_is_privilege_violation() {
+    let mut registers = Registers::new();
+    let mut memory = Memory::new();
+
+    registers.saved_ssp = 0x3000;
+    registers.set_psr(0x8004);
+    registers.write(Register::R6, 0xFE00);
+    registers.write(Register::PC, 0x3001);
+    memory.write(0x0100, 0x0500); // privilege mode violation handler
+
+    assert_eq!(execute_with_memory(0x8000, &mut registers, &mut memory), Ok(()));
+
+    assert!(!registers.is_user_mode());
+    assert_eq!(registers.read(Register::PC), 0x0500);
+    assert_eq!(memory.read(0x2FFE), 0x3001);
+    assert_eq!(memory.read(0x2FFF), 0x8004);
+}
+
+#[test]
+fn integration_test_rti_in_user_mode_without_handler_stops() {
+    let mut registers = Registers::new();
+    let mut memory = Memory::new();
+
+    registers.set_psr(0x8004);
+    registers.write(Register::R6, 0xFE00);
+    registers.write(Register::PC, 0x3001);
+
+    assert_eq!(
+        execute_with_memory(0x8000, &mut registers, &mut memory),
+        Err(ExitReason::PrivilegeViolation(0x3000))
+    );
+    assert!(registers.is_user_mode());
+    assert_eq!(registers.read(Register::R6), 0xFE00);
+}
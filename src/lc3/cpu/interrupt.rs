@@ -0,0 +1,254 @@
+use crate::lc3::hardware::{
+    Memory::Memory,
+    Reg::{RegisterEnum, Registers},
+};
+use crate::lc3::vm::ExitReason;
+
+/// Base of the interrupt vector table (0x0100-0x01FF). Exceptions use entries
+/// 0x00-0x7F, device interrupts 0x80-0xFF.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+/// Interrupt vector of the keyboard.
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+/// Priority level the keyboard interrupts at.
+pub const KEYBOARD_PRIORITY: u8 = 4;
+
+/// A request for the processor to run the service routine for `vector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptRequest {
+    /// Entry in the interrupt vector table (0x80-0xFF for devices).
+    pub vector: u8,
+    /// Priority level 0-7; the request is only taken above the running priority.
+    pub priority: u8,
+}
+
+/// Collects interrupt requests and decides which one, if any, the processor takes next.
+///
+/// Devices assert their requests through `Device::interrupt_request` for as long as the
+/// condition holds (level-triggered); the host can also raise one-shot requests.
+#[derive(Default)]
+pub struct InterruptController {
+    pending: Vec<InterruptRequest>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a one-shot request; it stays pending until the processor takes it.
+    pub fn raise(&mut self, request: InterruptRequest) {
+        self.pending.push(request);
+    }
+
+    /// One-shot requests that have not been taken yet.
+    pub fn pending(&self) -> &[InterruptRequest] {
+        &self.pending
+    }
+
+    /// Take the highest-priority request if it beats the running priority, and enter its
+    /// service routine. Returns the request taken.
+    pub fn service(&mut self, registers: &mut Registers, memory: &mut Memory) -> Option<InterruptRequest> {
+        let queued = self
+            .pending
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, request)| request.priority)
+            .map(|(i, request)| (i, *request));
+        let asserted = memory.interrupt_request();
+
+        // Ties go to the queued request, so a host-raised interrupt isn't starved
+        let (index, request) = match (queued, asserted) {
+            (Some((_, q)), Some(a)) if a.priority > q.priority => (None, a),
+            (Some((i, q)), _) => (Some(i), q),
+            (None, Some(a)) => (None, a),
+            (None, None) => return None,
+        };
+        if request.priority <= registers.priority() {
+            return None;
+        }
+        if let Some(index) = index {
+            self.pending.remove(index);
+        }
+        initiate(registers, memory, request.vector, Some(request.priority));
+        Some(request)
+    }
+}
+
+/// Exceptions raised by the processor itself, with their interrupt vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// RTI executed in user mode.
+    PrivilegeViolation = 0x00,
+    /// The reserved opcode was executed.
+    IllegalOpcode = 0x01,
+    /// User mode touched system space or the device registers.
+    AccessControlViolation = 0x02,
+}
+
+/// Push `value` onto the stack R6 points at.
+fn push(registers: &mut Registers, memory: &mut Memory, value: u16) {
+    let sp = registers.read(RegisterEnum::R6).wrapping_sub(1);
+    registers.write(RegisterEnum::R6, sp);
+    memory.write(sp as usize, value);
+}
+
+/// Pop a value off the stack R6 points at.
+pub fn pop(registers: &mut Registers, memory: &mut Memory) -> u16 {
+    let sp = registers.read(RegisterEnum::R6);
+    let value = memory.read(sp as usize);
+    registers.write(RegisterEnum::R6, sp.wrapping_add(1));
+    value
+}
+
+/// Start the service routine for interrupt vector `vector`.
+///
+/// - Switches to supervisor mode (and the supervisor stack, if coming from user mode).
+/// - Pushes the old PSR, then the PC, onto the supervisor stack.
+/// - Raises the running priority to `priority`, when given (interrupts do, exceptions don't).
+/// - Loads the PC from the interrupt vector table entry for `vector`.
+pub fn initiate(registers: &mut Registers, memory: &mut Memory, vector: u8, priority: Option<u8>) {
+    enter(registers, memory, INTERRUPT_VECTOR_TABLE + vector as u16, priority);
+}
+
+/// Like `initiate`, with the PC loaded from the vector table entry at `entry`. TRAPs use
+/// this with the trap vector table.
+pub fn enter(registers: &mut Registers, memory: &mut Memory, entry: u16, priority: Option<u8>) {
+    let psr = registers.psr();
+    let pc = registers.read(RegisterEnum::PC);
+
+    registers.enter_supervisor_mode();
+    // The pushes go to the supervisor stack, which user-mode access control would refuse
+    memory.set_user_mode(false);
+    push(registers, memory, psr);
+    push(registers, memory, pc);
+    if let Some(priority) = priority {
+        registers.set_priority(priority);
+    }
+
+    let handler = memory.read(entry as usize);
+    registers.write(RegisterEnum::PC, handler);
+}
+
+/// `true` if the operating system has filled in the vector table entry for `vector`.
+pub fn has_handler(memory: &Memory, vector: u8) -> bool {
+    memory.peek((INTERRUPT_VECTOR_TABLE + vector as u16) as usize) != 0
+}
+
+/// Start the service routine for `exception`. The running priority is unchanged.
+///
+/// Without an operating system there is nothing to dispatch to: when the vector table
+/// entry is empty the machine is left untouched and `unhandled` is returned instead,
+/// stopping the run.
+pub fn raise(
+    registers: &mut Registers,
+    memory: &mut Memory,
+    exception: Exception,
+    unhandled: ExitReason,
+) -> Result<(), ExitReason> {
+    if !has_handler(memory, exception as u8) {
+        return Err(unhandled);
+    }
+    initiate(registers, memory, exception as u8, None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exception_from_user_mode_switches_stacks() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.saved_ssp = 0x3000;
+        registers.set_psr(0x8001); // user mode, P
+        registers.write(RegisterEnum::R6, 0xFE00);
+        registers.write(RegisterEnum::PC, 0x3005);
+        memory.write(0x0100, 0x1000);
+
+        let result = raise(
+            &mut registers,
+            &mut memory,
+            Exception::PrivilegeViolation,
+            ExitReason::PrivilegeViolation(0x3004),
+        );
+
+        assert_eq!(result, Ok(()));
+        assert!(!registers.is_user_mode());
+        assert_eq!(registers.saved_usp, 0xFE00);
+        assert_eq!(registers.read(RegisterEnum::R6), 0x2FFE);
+        assert_eq!(memory.read(0x2FFF), 0x8001);
+        assert_eq!(memory.read(0x2FFE), 0x3005);
+        assert_eq!(registers.read(RegisterEnum::PC), 0x1000);
+    }
+
+    #[test]
+    fn test_exception_without_handler_stops() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::R6, 0x3000);
+        registers.write(RegisterEnum::PC, 0x3001);
+
+        let result = raise(
+            &mut registers,
+            &mut memory,
+            Exception::IllegalOpcode,
+            ExitReason::IllegalOpcode(0x3000, 0xD000),
+        );
+
+        assert_eq!(result, Err(ExitReason::IllegalOpcode(0x3000, 0xD000)));
+        assert_eq!(registers.read(RegisterEnum::PC), 0x3001);
+        assert_eq!(registers.read(RegisterEnum::R6), 0x3000);
+    }
+
+    #[test]
+    fn test_interrupt_raises_priority() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::R6, 0x3000);
+        memory.write(0x0180, 0x1200);
+
+        initiate(&mut registers, &mut memory, 0x80, Some(4));
+
+        assert_eq!(registers.priority(), 4);
+        assert_eq!(registers.read(RegisterEnum::PC), 0x1200);
+        assert_eq!(memory.read(0x2FFF), 0x0000);
+    }
+
+    #[test]
+    fn test_controller_respects_running_priority() {
+        let mut controller = InterruptController::new();
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::R6, 0x3000);
+        registers.set_priority(5);
+        memory.write(0x01A0, 0x1400);
+
+        controller.raise(InterruptRequest { vector: 0xA0, priority: 5 });
+        assert_eq!(controller.service(&mut registers, &mut memory), None);
+        assert_eq!(controller.pending().len(), 1);
+
+        registers.set_priority(2);
+        let taken = controller.service(&mut registers, &mut memory);
+        assert_eq!(taken, Some(InterruptRequest { vector: 0xA0, priority: 5 }));
+        assert!(controller.pending().is_empty());
+        assert_eq!(registers.read(RegisterEnum::PC), 0x1400);
+        assert_eq!(registers.priority(), 5);
+    }
+
+    #[test]
+    fn test_controller_takes_highest_priority_first() {
+        let mut controller = InterruptController::new();
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::R6, 0x3000);
+
+        controller.raise(InterruptRequest { vector: 0x81, priority: 1 });
+        controller.raise(InterruptRequest { vector: 0x86, priority: 6 });
+        let taken = controller.service(&mut registers, &mut memory).unwrap();
+        assert_eq!(taken.vector, 0x86);
+        // The priority-1 request can't preempt the priority-6 routine
+        assert_eq!(controller.service(&mut registers, &mut memory), None);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod decode;
+pub mod disasm;
 pub mod instruction;
+pub mod interrupt;
 pub mod opcode;
 pub mod trap;
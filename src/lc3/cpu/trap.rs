@@ -1,9 +1,12 @@
+use crate::lc3::cpu::interrupt;
 use crate::lc3::hardware::{
     Memory::Memory,
     Reg::{RegisterEnum, Registers},
 };
-use std::io::{self, Read, Write};
-use std::process;
+use crate::lc3::sys::console::Console;
+use crate::lc3::vm::ExitReason;
+use std::cell::RefCell;
+use std::io;
 
 /// Trap codes for the LC-3.
 pub enum TrapCode {
@@ -15,85 +18,252 @@ pub enum TrapCode {
     Halt = 0x25,  // Halt the program
 }
 
-/// Executes a TRAP instruction.
-/// - `instr`: The 16-bit LC-3 instruction word.
+/// Base of the trap vector table (0x0000-0x00FF).
+pub const TRAP_VECTOR_TABLE: u16 = 0x0000;
+
+/// How TRAP instructions are serviced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapMode {
+    /// GETC, OUT, PUTS, IN, PUTSP and HALT are implemented in Rust (`trap`); the trap
+    /// vector table is ignored.
+    #[default]
+    Fast,
+    /// Like the hardware (3rd edition): enter supervisor mode, push the PSR and PC, and
+    /// jump through the trap vector table. Service routines return with RTI.
+    Os,
+    /// Like the 2nd edition hardware: save the return address in R7 and jump through the
+    /// trap vector table, without changing privilege. Service routines return with RET.
+    LegacyOs,
+}
+
+/// Executes a TRAP instruction by jumping to the service routine the trap vector table
+/// points at, following `mode` (`Os` or `LegacyOs`).
+///
+/// Returns `Err(ExitReason::UnknownTrap(..))` if the table entry is empty.
+pub fn vectored(vector: u8, registers: &mut Registers, memory: &mut Memory, mode: TrapMode) -> Result<(), ExitReason> {
+    let entry = TRAP_VECTOR_TABLE + vector as u16;
+    if memory.peek(entry as usize) == 0 {
+        return Err(ExitReason::UnknownTrap(vector));
+    }
+
+    if mode == TrapMode::LegacyOs {
+        let pc = registers.read(RegisterEnum::PC);
+        registers.write(RegisterEnum::R7, pc);
+        let routine = memory.read(entry as usize);
+        registers.write(RegisterEnum::PC, routine);
+    } else {
+        interrupt::enter(registers, memory, entry, None);
+    }
+    Ok(())
+}
+
+/// Executes a TRAP instruction with the built-in service routines (`TrapMode::Fast`).
+/// - `vector`: The trap vector, the low byte of the TRAP instruction.
 /// - `registers`: The mutable reference to the `Registers` struct.
 /// - `memory`: The mutable reference to the `Memory` struct.
-pub fn trap(instr: u16, registers: &mut Registers, memory: &mut Memory) {
+/// - `console`: Where GETC/IN read from and OUT/PUTS/IN/PUTSP/HALT write to.
+///
+/// Returns `Err` with the reason execution has to stop (HALT, an unknown
+/// vector or a console failure); the caller decides what to do with it.
+pub fn trap(
+    vector: u8,
+    registers: &mut Registers,
+    memory: &mut Memory,
+    console: &RefCell<dyn Console>,
+) -> Result<(), ExitReason> {
     // Save PC to R7 for return address
     let pc = registers.read(RegisterEnum::PC);
     registers.write(RegisterEnum::R7, pc);
 
-    match instr & 0xFF {
+    match vector {
         0x20 => {
             // TRAP GETC: Get a single ASCII character
-            let input = io::stdin()
-                .lock()
-                .bytes()
-                .next()
-                .expect("No character input")
-                .unwrap();
+            let input = read_byte(console)?;
             registers.write(RegisterEnum::R0, input as u16);
         }
         0x21 => {
             // TRAP OUT: Output a single character
-            let char_output = (registers.read(RegisterEnum::R0) as u8) as char;
-            print!("{}", char_output);
-            io::stdout().flush().expect("Failed to flush stdout");
+            let char_output = registers.read(RegisterEnum::R0) as u8;
+            write_bytes(console, &[char_output])?;
         }
         0x22 => {
             // TRAP PUTS: Output a word string
             let mut address = registers.read(RegisterEnum::R0) as usize;
+            let mut output = Vec::new();
             loop {
                 let word = memory.read(address);
                 if word == 0 {
                     break;
                 }
                 // Only lower 8 bits are used for the character.
-                let c = (word & 0xFF) as u8 as char;
-                print!("{}", c);
+                output.push((word & 0xFF) as u8);
                 address += 1;
             }
-            io::stdout().flush().expect("Failed to flush stdout (PUTS).");
+            write_bytes(console, &output)?;
         }
         0x23 => {
             // TRAP IN: Get a single character with echo
-            print!("Enter a character: ");
-            io::stdout().flush().expect("Failed to flush stdout");
-            let input = io::stdin()
-                .lock()
-                .bytes()
-                .next()
-                .expect("No character input")
-                .unwrap();
-            let char_output = input as char;
-            print!("{}", char_output);
+            write_bytes(console, b"Enter a character: ")?;
+            let input = read_byte(console)?;
+            write_bytes(console, &[input])?;
             registers.write(RegisterEnum::R0, input as u16);
-            io::stdout().flush().expect("Failed to flush stdout after echo (IN).");
         }
         0x24 => {
             // TRAP PUTSP: Output a byte string
             let mut address = registers.read(RegisterEnum::R0) as usize;
+            let mut output = Vec::new();
             while memory.read(address) != 0 {
                 let word = memory.read(address);
-                let char1 = (word & 0xFF) as u8 as char;
-                print!("{}", char1);
-                let char2 = (word >> 8) as u8 as char;
-                if char2 != '\0' {
-                    print!("{}", char2);
+                output.push((word & 0xFF) as u8);
+                let char2 = (word >> 8) as u8;
+                if char2 != 0 {
+                    output.push(char2);
                 }
                 address += 1;
             }
-            io::stdout().flush().expect("Failed to flush stdout");
+            write_bytes(console, &output)?;
         }
         0x25 => {
             // TRAP HALT: Halt the program
-            println!("HALT");
-            process::exit(0);
+            write_bytes(console, b"HALT\n")?;
+            return Err(ExitReason::Halted);
         }
-        _ => {
+        vector => {
             // Unknown trap code
-            panic!("Unknown TRAP code: {:#X}", instr & 0xFF);
+            return Err(ExitReason::UnknownTrap(vector));
         }
     }
+    Ok(())
+}
+
+/// Blocks until a single byte is available on the console.
+fn read_byte(console: &RefCell<dyn Console>) -> Result<u8, ExitReason> {
+    match console.borrow_mut().read_byte()? {
+        Some(byte) => Ok(byte),
+        None => Err(ExitReason::IoError(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// Writes `bytes` and flushes, so output shows up before the program waits for input.
+/// The console is only borrowed here, after any memory reads, since devices share it.
+fn write_bytes(console: &RefCell<dyn Console>, bytes: &[u8]) -> Result<(), ExitReason> {
+    let mut console = console.borrow_mut();
+    console.write_bytes(bytes)?;
+    console.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::sys::console::BufferConsole;
+
+    fn run_trap(vector: u8, registers: &mut Registers, memory: &mut Memory, console: &RefCell<BufferConsole>) -> Result<(), ExitReason> {
+        trap(vector, registers, memory, console)
+    }
+
+    #[test]
+    fn test_getc_reads_console() {
+        let console = RefCell::new(BufferConsole::new(b"k"));
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::PC, 0x3001);
+
+        assert_eq!(run_trap(0x20, &mut registers, &mut memory, &console), Ok(()));
+        assert_eq!(registers.read(RegisterEnum::R0), 'k' as u16);
+        assert_eq!(registers.read(RegisterEnum::R7), 0x3001);
+    }
+
+    #[test]
+    fn test_getc_at_end_of_input() {
+        let console = RefCell::new(BufferConsole::default());
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+
+        assert_eq!(
+            run_trap(0x20, &mut registers, &mut memory, &console),
+            Err(ExitReason::IoError(io::ErrorKind::UnexpectedEof))
+        );
+    }
+
+    #[test]
+    fn test_out_puts_putsp() {
+        let console = RefCell::new(BufferConsole::default());
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+
+        registers.write(RegisterEnum::R0, '>' as u16);
+        run_trap(0x21, &mut registers, &mut memory, &console).unwrap();
+
+        for (i, c) in "hi".bytes().enumerate() {
+            memory.write(0x4000 + i, c as u16);
+        }
+        registers.write(RegisterEnum::R0, 0x4000);
+        run_trap(0x22, &mut registers, &mut memory, &console).unwrap();
+
+        // "abc" packed two characters per word, low byte first
+        memory.write(0x5000, ('b' as u16) << 8 | 'a' as u16);
+        memory.write(0x5001, 'c' as u16);
+        registers.write(RegisterEnum::R0, 0x5000);
+        run_trap(0x24, &mut registers, &mut memory, &console).unwrap();
+
+        assert_eq!(console.borrow().output_string(), ">hiabc");
+    }
+
+    #[test]
+    fn test_in_echoes() {
+        let console = RefCell::new(BufferConsole::new(b"z"));
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+
+        run_trap(0x23, &mut registers, &mut memory, &console).unwrap();
+        assert_eq!(registers.read(RegisterEnum::R0), 'z' as u16);
+        assert_eq!(console.borrow().output_string(), "Enter a character: z");
+    }
+
+    #[test]
+    fn test_halt_and_unknown_vector() {
+        let console = RefCell::new(BufferConsole::default());
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+
+        assert_eq!(run_trap(0x25, &mut registers, &mut memory, &console), Err(ExitReason::Halted));
+        assert_eq!(run_trap(0x30, &mut registers, &mut memory, &console), Err(ExitReason::UnknownTrap(0x30)));
+    }
+
+    #[test]
+    fn test_vectored_trap_enters_supervisor_mode() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.saved_ssp = 0x3000;
+        registers.set_psr(0x8001);
+        registers.write(RegisterEnum::R6, 0xFE00);
+        registers.write(RegisterEnum::PC, 0x3001);
+        memory.write(0x0021, 0x0420);
+
+        assert_eq!(vectored(0x21, &mut registers, &mut memory, TrapMode::Os), Ok(()));
+        assert!(!registers.is_user_mode());
+        assert_eq!(registers.read(RegisterEnum::PC), 0x0420);
+        assert_eq!(memory.read(0x2FFF), 0x8001);
+        assert_eq!(memory.read(0x2FFE), 0x3001);
+    }
+
+    #[test]
+    fn test_legacy_vectored_trap_links_r7() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new();
+        registers.write(RegisterEnum::R6, 0x2000);
+        registers.write(RegisterEnum::PC, 0x3001);
+        memory.write(0x0025, 0x0FD0);
+
+        assert_eq!(vectored(0x25, &mut registers, &mut memory, TrapMode::LegacyOs), Ok(()));
+        assert_eq!(registers.read(RegisterEnum::PC), 0x0FD0);
+        assert_eq!(registers.read(RegisterEnum::R7), 0x3001);
+        assert_eq!(registers.read(RegisterEnum::R6), 0x2000);
+
+        assert_eq!(
+            vectored(0x26, &mut registers, &mut memory, TrapMode::LegacyOs),
+            Err(ExitReason::UnknownTrap(0x26))
+        );
+    }
 }
@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::fmt;
+
+use super::debugger::WatchKind;
+use crate::lc3::asm::lexer::parse_number;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::sys::symbols::SymbolTable;
+use crate::lc3::vm::LC3;
+
+/// Something `print` and `set` can read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Place {
+    Register(RegisterEnum),
+    Psr,
+    Memory(u16),
+}
+
+impl Place {
+    pub fn read(&self, vm: &LC3) -> u16 {
+        match *self {
+            Place::Register(register) => vm.registers().read(register),
+            Place::Psr => vm.registers().psr(),
+            Place::Memory(address) => vm.memory().peek(address as usize),
+        }
+    }
+
+    pub fn write(&self, vm: &mut LC3, value: u16) {
+        match *self {
+            Place::Register(register) => vm.registers_mut().write(register, value),
+            Place::Psr => vm.registers_mut().set_psr(value),
+            Place::Memory(address) => vm.memory_mut().write(address as usize, value),
+        }
+    }
+}
+
+/// A debugger command, with its addresses resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Break(u16),
+    /// Remove the breakpoint and the watchpoint at an address.
+    Delete(u16),
+    Watch(u16, WatchKind),
+    Step(u64),
+    Next,
+    Finish,
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    /// Which instruction last stored to an address.
+    LastWrite(u16),
+    Record(bool),
+    /// A single place, or every register when `None`.
+    Print(Option<Place>),
+    Set(Place, u16),
+    /// `count` words from an address, disassembled.
+    Examine(u16, u16),
+    /// Write a snapshot of the machine to a file.
+    Save(String),
+    /// Restore the machine from a snapshot file.
+    Load(String),
+    Info,
+    Help,
+    Quit,
+}
+
+/// Why a command line was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    /// Wrong number or kind of arguments; holds the expected form.
+    Usage(&'static str),
+    /// Neither a number nor a known label.
+    BadAddress(String),
+    BadValue(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "unknown command '{}', try 'help'", name),
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::BadAddress(word) => write!(f, "no label or address '{}'", word),
+            CommandError::BadValue(word) => write!(f, "bad value '{}'", word),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Every command as listed by `help`: name, short name, form and what it does.
+pub const COMMANDS: &[(&str, &str, &str, &str)] = &[
+    ("break", "b", "break <address>", "stop in front of the instruction at an address"),
+    ("delete", "d", "delete <address>", "remove the breakpoint and watchpoint at an address"),
+    ("watch", "w", "watch <address> [read|write|access]", "stop after an instruction touches an address (default: write)"),
+    ("step", "s", "step [count]", "execute one instruction, or count of them"),
+    ("next", "n", "next", "step, running JSR, JSRR and TRAP through to their return"),
+    ("finish", "fin", "finish", "run until the current subroutine returns"),
+    ("continue", "c", "continue", "run until a breakpoint, a watchpoint or the end of the program"),
+    ("reverse-step", "rs", "reverse-step [count]", "undo one instruction, or count of them"),
+    ("reverse-continue", "rc", "reverse-continue", "run backward to a breakpoint, a watched store or the start of the history"),
+    ("last-write", "lw", "last-write <address>", "show which recorded instruction last stored to an address"),
+    ("record", "rec", "record on|off", "keep the history the reverse commands undo (on at start)"),
+    ("print", "p", "print [register|PSR|address]", "show every register, or one place"),
+    ("set", "set", "set <register|PSR|address> <value>", "change a register or a word of memory"),
+    ("examine", "x", "examine <address> [count]", "disassemble words of memory"),
+    ("save", "save", "save <file>", "write a snapshot of the machine to a file"),
+    ("load", "load", "load <file>", "restore the machine from a snapshot file"),
+    ("info", "i", "info", "list breakpoints, watchpoints and the size of the history"),
+    ("help", "h", "help", "show this list"),
+    ("quit", "q", "quit", "leave the debugger"),
+];
+
+/// Parse one command line; `Ok(None)` for a blank line.
+///
+/// Addresses and values are written like assembler literals (`x3000`, `#-1`, `b101`,
+/// `12`) or as a label from `symbols`, optionally with an offset (`LOOP+2`).
+pub fn parse(line: &str, symbols: &SymbolTable) -> Result<Option<Command>, CommandError> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return Ok(None);
+    };
+    let Some(&(name, _, usage, _)) = COMMANDS.iter().find(|(full, short, ..)| name == *full || name == *short) else {
+        return Err(CommandError::Unknown(name.to_string()));
+    };
+    let address = |word: &str| address(word, symbols);
+
+    let command = match (name, args) {
+        ("break", [location]) => Command::Break(address(location)?),
+        ("delete", [location]) => Command::Delete(address(location)?),
+        ("watch", [location]) => Command::Watch(address(location)?, WatchKind::Write),
+        ("watch", [location, kind]) => {
+            let kind = match *kind {
+                "write" | "w" => WatchKind::Write,
+                "read" | "r" => WatchKind::Read,
+                "access" | "rw" => WatchKind::Access,
+                _ => return Err(CommandError::Usage(usage)),
+            };
+            Command::Watch(address(location)?, kind)
+        }
+        ("step", []) => Command::Step(1),
+        ("step", [count]) => match count.parse() {
+            Ok(count) if count > 0 => Command::Step(count),
+            _ => return Err(CommandError::BadValue(count.to_string())),
+        },
+        ("next", []) => Command::Next,
+        ("finish", []) => Command::Finish,
+        ("continue", []) => Command::Continue,
+        ("reverse-step", []) => Command::ReverseStep(1),
+        ("reverse-step", [count]) => match count.parse() {
+            Ok(count) if count > 0 => Command::ReverseStep(count),
+            _ => return Err(CommandError::BadValue(count.to_string())),
+        },
+        ("reverse-continue", []) => Command::ReverseContinue,
+        ("last-write", [location]) => Command::LastWrite(address(location)?),
+        ("record", ["on"]) => Command::Record(true),
+        ("record", ["off"]) => Command::Record(false),
+        ("print", []) => Command::Print(None),
+        ("print", [place]) => Command::Print(Some(self::place(place, symbols)?)),
+        ("set", [place, val]) => Command::Set(self::place(place, symbols)?, value(val, symbols)?),
+        ("examine", [location]) => Command::Examine(address(location)?, 1),
+        ("examine", [location, count]) => match value(count, symbols)? {
+            0 => return Err(CommandError::BadValue(count.to_string())),
+            count => Command::Examine(address(location)?, count),
+        },
+        ("save", [path]) => Command::Save(path.to_string()),
+        ("load", [path]) => Command::Load(path.to_string()),
+        ("info", []) => Command::Info,
+        ("help", _) => Command::Help,
+        ("quit", []) => Command::Quit,
+        _ => return Err(CommandError::Usage(usage)),
+    };
+    Ok(Some(command))
+}
+
+/// A register name, `PSR`, or an address.
+pub fn place(word: &str, symbols: &SymbolTable) -> Result<Place, CommandError> {
+    let register = match word.to_ascii_uppercase().as_str() {
+        "PSR" => return Ok(Place::Psr),
+        "PC" => Some(RegisterEnum::PC),
+        "COND" => Some(RegisterEnum::COND),
+        upper => match upper.as_bytes() {
+            [b'R', n @ b'0'..=b'7'] => RegisterEnum::try_from((n - b'0') as usize).ok(),
+            _ => None,
+        },
+    };
+    match register {
+        Some(register) => Ok(Place::Register(register)),
+        None => address(word, symbols).map(Place::Memory),
+    }
+}
+
+/// An address: a literal in `0..=xFFFF` or a label with an optional offset.
+fn address(word: &str, symbols: &SymbolTable) -> Result<u16, CommandError> {
+    let bad = || CommandError::BadAddress(word.to_string());
+    if let Some(number) = parse_number(word) {
+        return match number {
+            Ok(n) if n >= 0 => Ok(n as u16),
+            _ => Err(bad()),
+        };
+    }
+    // `LOOP+2`, `DATA-1`: split at the last sign that isn't the first character
+    let sign = word.char_indices().skip(1).filter(|&(_, c)| c == '+' || c == '-').last();
+    let (label, offset) = match sign {
+        Some((at, _)) => {
+            let (label, offset) = word.split_at(at);
+            let negative = offset.starts_with('-');
+            let offset = match parse_number(&offset[1..]) {
+                Some(Ok(n)) => if negative { -n } else { n },
+                _ => return Err(bad()),
+            };
+            (label, offset)
+        }
+        None => (word, 0),
+    };
+    let base = symbols.address_of(label).ok_or_else(bad)?;
+    Ok(base.wrapping_add(offset as u16))
+}
+
+/// A 16-bit value: any literal, negative ones in two's complement, or an address.
+pub fn value(word: &str, symbols: &SymbolTable) -> Result<u16, CommandError> {
+    match parse_number(word) {
+        Some(Ok(n)) => Ok(n as u16),
+        Some(Err(_)) => Err(CommandError::BadValue(word.to_string())),
+        None => address(word, symbols).map_err(|_| CommandError::BadValue(word.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3004);
+        symbols.insert("DATA", 0x3100);
+        symbols
+    }
+
+    fn parse(line: &str) -> Result<Option<Command>, CommandError> {
+        super::parse(line, &symbols())
+    }
+
+    #[test]
+    fn test_addresses_and_labels() {
+        assert_eq!(parse("break x3000"), Ok(Some(Command::Break(0x3000))));
+        assert_eq!(parse("b LOOP"), Ok(Some(Command::Break(0x3004))));
+        assert_eq!(parse("b LOOP+2"), Ok(Some(Command::Break(0x3006))));
+        assert_eq!(parse("d DATA-x10"), Ok(Some(Command::Delete(0x30F0))));
+        assert_eq!(parse("b #12"), Ok(Some(Command::Break(12))));
+        assert_eq!(parse("b NOWHERE"), Err(CommandError::BadAddress("NOWHERE".into())));
+        assert_eq!(parse("b #-1"), Err(CommandError::BadAddress("#-1".into())));
+        assert_eq!(parse("  "), Ok(None));
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(parse("s"), Ok(Some(Command::Step(1))));
+        assert_eq!(parse("step 10"), Ok(Some(Command::Step(10))));
+        assert_eq!(parse("watch DATA"), Ok(Some(Command::Watch(0x3100, WatchKind::Write))));
+        assert_eq!(parse("w DATA+1 read"), Ok(Some(Command::Watch(0x3101, WatchKind::Read))));
+        assert_eq!(parse("p"), Ok(Some(Command::Print(None))));
+        assert_eq!(parse("p cond"), Ok(Some(Command::Print(Some(Place::Register(RegisterEnum::COND))))));
+        assert_eq!(parse("set R3 #-1"), Ok(Some(Command::Set(Place::Register(RegisterEnum::R3), 0xFFFF))));
+        assert_eq!(parse("set PC LOOP"), Ok(Some(Command::Set(Place::Register(RegisterEnum::PC), 0x3004))));
+        assert_eq!(parse("set DATA x41"), Ok(Some(Command::Set(Place::Memory(0x3100), 0x41))));
+        assert_eq!(parse("x LOOP 4"), Ok(Some(Command::Examine(0x3004, 4))));
+        assert_eq!(parse("rs 3"), Ok(Some(Command::ReverseStep(3))));
+        assert_eq!(parse("lw DATA"), Ok(Some(Command::LastWrite(0x3100))));
+        assert_eq!(parse("record maybe"), Err(CommandError::Usage("record on|off")));
+        assert_eq!(parse("next now"), Err(CommandError::Usage("next")));
+        assert_eq!(parse("w DATA often"), Err(CommandError::Usage("watch <address> [read|write|access]")));
+        assert_eq!(parse("jump"), Err(CommandError::Unknown("jump".into())));
+    }
+}
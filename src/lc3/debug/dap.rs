@@ -0,0 +1,784 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use super::command::{self, Place};
+use super::debugger::{Debugger, PauseCheck, Stop};
+use super::history::DEFAULT_HISTORY;
+use super::repl::{condition, describe};
+use crate::lc3::asm::{assemble, Assembly};
+use crate::lc3::cpu::decode::{decode, Instruction};
+use crate::lc3::cpu::trap::TrapMode;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::sys::console::BufferConsole;
+use crate::lc3::sys::json::Json;
+use crate::lc3::vm::{ExitReason, LC3};
+
+/// The only thread the adapter reports.
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const LABELS_REFERENCE: i64 = 2;
+const MEMORY_REFERENCE: i64 = 3;
+/// Instructions run between looks for a request that arrived while the program runs.
+const POLL_INTERVAL: u32 = 10_000;
+/// Words of memory listed when a client asks for the memory scope without a range.
+const MEMORY_PAGE: i64 = 256;
+const NOT_LAUNCHED: &str = "no program has been launched";
+
+/// How the program was last set going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Motion {
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+    StepBack,
+    ReverseContinue,
+}
+
+/// Requests from the client, shared with the pause check that looks for them while
+/// the program runs.
+struct Inbox {
+    receiver: Receiver<Json>,
+    /// Taken off the channel by the pause check, not handled yet.
+    queued: VecDeque<Json>,
+    /// Instructions left until the pause check next looks at the channel.
+    countdown: u32,
+    /// The client went away while the program ran.
+    closed: bool,
+    /// The pause check stopped in front of a GETC or IN with no input queued.
+    waiting_for_input: bool,
+}
+
+/// The program a `launch` loaded.
+struct Program {
+    path: PathBuf,
+    /// The assembled source, for `.asm` programs; images have no line table.
+    assembly: Option<Assembly>,
+}
+
+/// A Debug Adapter Protocol session over a `Debugger`, for editors such as VS Code.
+///
+/// `launch` takes a `program` (an `.asm` file, assembled on the spot so breakpoints can
+/// be set on source lines, or an `.obj` image), an optional `stopOnEntry` and an
+/// optional `input` queued for the program's GETC and IN. Registers, labels and memory
+/// are shown as variables; an `evaluate` starting with `>` queues the rest of the line
+/// as more input.
+pub struct Adapter {
+    inbox: Rc<RefCell<Inbox>>,
+    console: Rc<RefCell<BufferConsole>>,
+    debugger: Option<Debugger>,
+    program: Option<Program>,
+    /// Breakpoints the last `setBreakpoints` placed, which the next one replaces.
+    source_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+    /// Set while the program runs, until it stops.
+    running: Option<Motion>,
+    /// The motion a wait for input interrupted, picked up again once input arrives.
+    waiting: Option<Motion>,
+    /// Set once the client disconnects.
+    finished: bool,
+    lines_start_at_1: bool,
+    seq: i64,
+    /// Responses and events not sent yet.
+    outbox: Vec<Json>,
+}
+
+impl Adapter {
+    pub fn new(receiver: Receiver<Json>) -> Self {
+        let inbox = Inbox {
+            receiver,
+            queued: VecDeque::new(),
+            countdown: 0,
+            closed: false,
+            waiting_for_input: false,
+        };
+        Adapter {
+            inbox: Rc::new(RefCell::new(inbox)),
+            console: Rc::new(RefCell::new(BufferConsole::default())),
+            debugger: None,
+            program: None,
+            source_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            running: None,
+            waiting: None,
+            finished: false,
+            lines_start_at_1: true,
+            seq: 0,
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Handle requests from the channel, and run the program when asked to, until the
+    /// client disconnects. Every response and event goes to `send`.
+    pub fn serve(&mut self, mut send: impl FnMut(&Json) -> io::Result<()>) -> io::Result<()> {
+        while !self.finished {
+            if self.running.is_some() {
+                self.run();
+            } else {
+                let request = {
+                    let mut inbox = self.inbox.borrow_mut();
+                    inbox.queued.pop_front().or_else(|| inbox.receiver.recv().ok())
+                };
+                match request {
+                    Some(request) => self.handle(request),
+                    None => self.finished = true,
+                }
+            }
+            for message in self.outbox.drain(..) {
+                send(&message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer one request. Events it raises follow the response.
+    pub fn handle(&mut self, request: Json) {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default().to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Object(Vec::new()));
+        let events_at = self.outbox.len();
+        self.seq += 1;
+        let seq = self.seq;
+        let result = self.request(&command, &arguments);
+
+        let mut response = vec![
+            ("seq", Json::from(seq)),
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", result.is_ok().into()),
+            ("command", command.into()),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", message.into())),
+        }
+        self.outbox.insert(events_at, Json::object(response));
+    }
+
+    fn request(&mut self, command: &str, arguments: &Json) -> Result<Json, String> {
+        match command {
+            "initialize" => {
+                self.lines_start_at_1 = arguments.get("linesStartAt1").and_then(Json::as_bool).unwrap_or(true);
+                Ok(Json::object([
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsSetVariable", true.into()),
+                    ("supportsEvaluateForHovers", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                    ("supportsStepBack", true.into()),
+                ]))
+            }
+            "launch" => {
+                self.launch(arguments)?;
+                self.event("initialized", Json::Null);
+                Ok(Json::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" => {
+                self.debugger()?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.running = Some(Motion::Continue);
+                }
+                Ok(Json::Null)
+            }
+            "threads" => Ok(Json::object([(
+                "threads",
+                Json::from(vec![Json::object([("id", Json::from(THREAD_ID)), ("name", "LC-3".into())])]),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(),
+            "variables" => self.variables(arguments),
+            "setVariable" => self.set_variable(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                self.debugger()?;
+                self.waiting = None;
+                self.running = Some(match command {
+                    "continue" => Motion::Continue,
+                    "next" => Motion::Next,
+                    "stepIn" => Motion::StepIn,
+                    "stepOut" => Motion::StepOut,
+                    "stepBack" => Motion::StepBack,
+                    _ => Motion::ReverseContinue,
+                });
+                match command {
+                    "continue" => Ok(Json::object([("allThreadsContinued", Json::from(true))])),
+                    _ => Ok(Json::Null),
+                }
+            }
+            "pause" => {
+                if self.running.take().is_some() {
+                    self.stopped("pause", None);
+                }
+                Ok(Json::Null)
+            }
+            "terminate" => {
+                self.running = None;
+                self.event("terminated", Json::Null);
+                Ok(Json::Null)
+            }
+            "disconnect" => {
+                self.running = None;
+                self.finished = true;
+                Ok(Json::Null)
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger.as_mut().ok_or_else(|| NOT_LAUNCHED.to_string())
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<(), String> {
+        let path = arguments.get("program").and_then(Json::as_str).ok_or("launch needs a 'program'")?;
+        let mut vm = LC3::with_console(self.console.clone());
+        let is_source = Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("asm"));
+        let assembly = if is_source {
+            let source = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let assembly = assemble(&source).map_err(|e| format!("{}:{}", path, e))?;
+            vm.load_words(assembly.origin, &assembly.words);
+            for (name, address) in &assembly.symbols {
+                vm.symbols_mut().insert(name, *address);
+            }
+            Some(assembly)
+        } else {
+            vm.load_image(path).map_err(|e| format!("Failed to load {}: {}", path, e))?;
+            None
+        };
+        if let Some(input) = arguments.get("input").and_then(Json::as_str) {
+            self.console.borrow_mut().push_input(input.as_bytes());
+        }
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+
+        let mut debugger = Debugger::new(vm);
+        debugger.record(Some(DEFAULT_HISTORY));
+        debugger.set_pause_check(Some(pause_check(self.inbox.clone(), self.console.clone())));
+        self.debugger = Some(debugger);
+        self.program = Some(Program { path: canonical(path), assembly });
+        self.source_breakpoints.clear();
+        Ok(())
+    }
+
+    /// Replace the breakpoints in one source file. Each line moves to the first
+    /// instruction on or after it.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str);
+        let lines = arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default();
+        let debugger = self.debugger.as_mut().ok_or(NOT_LAUNCHED)?;
+        let assembly = self
+            .program
+            .as_ref()
+            .filter(|program| path.is_some_and(|path| canonical(path) == program.path))
+            .and_then(|program| program.assembly.as_ref());
+
+        for address in self.source_breakpoints.drain(..) {
+            debugger.vm_mut().remove_breakpoint(address);
+        }
+        let mut breakpoints = Vec::new();
+        for requested in lines.iter().filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_i64)) {
+            let line = from_client_line(requested, self.lines_start_at_1);
+            match assembly.zip(line).and_then(|(assembly, line)| assembly.instruction_at_line(line)) {
+                Some((line, address)) => {
+                    debugger.vm_mut().add_breakpoint(address);
+                    self.source_breakpoints.push(address);
+                    breakpoints.push(Json::object([
+                        ("verified", Json::from(true)),
+                        ("line", to_client_line(line, self.lines_start_at_1).into()),
+                    ]));
+                }
+                None => breakpoints.push(Json::object([
+                    ("verified", Json::from(false)),
+                    ("line", requested.into()),
+                    ("message", "no instruction on or after this line".into()),
+                ])),
+            }
+        }
+        Ok(Json::object([("breakpoints", Json::from(breakpoints))]))
+    }
+
+    /// A single frame: execution stands in front of the instruction at PC.
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let vm = self.debugger.as_ref().ok_or(NOT_LAUNCHED)?.vm();
+        let pc = vm.registers().read(RegisterEnum::PC);
+        let mut frame = vec![
+            ("id", Json::from(0)),
+            ("name", describe(vm.symbols(), pc).into()),
+            ("line", 0.into()),
+            ("column", 0.into()),
+            ("instructionPointerReference", format!("0x{:04X}", pc).into()),
+        ];
+        let location = self.program.as_ref().and_then(|program| {
+            let line = program.assembly.as_ref()?.line_at(pc)?;
+            Some((program, line))
+        });
+        if let Some((program, line)) = location {
+            let name = program.path.file_name().unwrap_or_default().to_string_lossy();
+            frame[2].1 = to_client_line(line, self.lines_start_at_1).into();
+            frame[3].1 = 1.into();
+            frame.push((
+                "source",
+                Json::object([("name", Json::from(name.as_ref())), ("path", program.path.to_string_lossy().as_ref().into())]),
+            ));
+        }
+        Ok(Json::object([("stackFrames", Json::from(vec![Json::object(frame)])), ("totalFrames", 1.into())]))
+    }
+
+    fn scopes(&mut self) -> Result<Json, String> {
+        let vm = self.debugger.as_ref().ok_or(NOT_LAUNCHED)?.vm();
+        let scope = |name: &str, reference: i64, expensive: bool| {
+            Json::object([
+                ("name", Json::from(name)),
+                ("variablesReference", reference.into()),
+                ("expensive", expensive.into()),
+            ])
+        };
+        let mut scopes = vec![scope("Registers", REGISTERS_REFERENCE, false)];
+        if !vm.symbols().is_empty() {
+            scopes.push(scope("Labels", LABELS_REFERENCE, false));
+        }
+        let mut memory = scope("Memory", MEMORY_REFERENCE, true);
+        if let Json::Object(members) = &mut memory {
+            members.push(("indexedVariables".to_string(), 0x10000.into()));
+        }
+        scopes.push(memory);
+        Ok(Json::object([("scopes", Json::from(scopes))]))
+    }
+
+    fn variables(&mut self, arguments: &Json) -> Result<Json, String> {
+        let vm = self.debugger.as_ref().ok_or(NOT_LAUNCHED)?.vm();
+        let variable = |name: String, place: Place| Json::object([
+            ("name", Json::from(name)),
+            ("value", show(vm, place).into()),
+            ("variablesReference", 0.into()),
+        ]);
+        let variables: Vec<Json> = match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS_REFERENCE) => (0..8)
+                .map(|i| Place::Register(RegisterEnum::try_from(i).expect("R0-R7")))
+                .chain([Place::Register(RegisterEnum::PC), Place::Register(RegisterEnum::COND), Place::Psr])
+                .map(|place| {
+                    let name = match place {
+                        Place::Psr => "PSR".to_string(),
+                        Place::Register(register) => format!("{:?}", register),
+                        Place::Memory(_) => unreachable!(),
+                    };
+                    variable(name, place)
+                })
+                .collect(),
+            Some(LABELS_REFERENCE) => {
+                let mut labels: Vec<(&str, u16)> = vm.symbols().iter().collect();
+                labels.sort_by_key(|&(_, address)| address);
+                labels.into_iter().map(|(name, address)| variable(name.to_string(), Place::Memory(address))).collect()
+            }
+            Some(MEMORY_REFERENCE) => {
+                let start = arguments.get("start").and_then(Json::as_i64).unwrap_or(0).clamp(0, 0xFFFF);
+                let count = arguments.get("count").and_then(Json::as_i64).unwrap_or(MEMORY_PAGE).clamp(0, 0x10000 - start);
+                (start..start + count)
+                    .map(|address| variable(format!("x{:04X}", address), Place::Memory(address as u16)))
+                    .collect()
+            }
+            _ => return Err("unknown variables reference".to_string()),
+        };
+        Ok(Json::object([("variables", Json::from(variables))]))
+    }
+
+    /// Registers go by name, labels and memory words by label or address; the value is
+    /// anything the debugger command line accepts.
+    fn set_variable(&mut self, arguments: &Json) -> Result<Json, String> {
+        let name = arguments.get("name").and_then(Json::as_str).unwrap_or_default();
+        let value = arguments.get("value").and_then(Json::as_str).unwrap_or_default().trim();
+        let vm = self.debugger()?.vm_mut();
+        let place = command::place(name, vm.symbols()).map_err(|e| e.to_string())?;
+        let value = command::value(value, vm.symbols()).map_err(|e| e.to_string())?;
+        place.write(vm, value);
+        Ok(Json::object([("value", Json::from(show(vm, place)))]))
+    }
+
+    fn evaluate(&mut self, arguments: &Json) -> Result<Json, String> {
+        let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or_default().trim();
+        if let Some(input) = expression.strip_prefix('>') {
+            self.debugger()?;
+            let input = input.strip_prefix(' ').unwrap_or(input);
+            self.console.borrow_mut().push_input(format!("{}\n", input).as_bytes());
+            // Input was what the program stopped for
+            if let Some(motion) = self.waiting.take() {
+                self.running = Some(motion);
+                self.event("continued", Json::object([("threadId", Json::from(THREAD_ID)), ("allThreadsContinued", true.into())]));
+            }
+            return Ok(Json::object([("result", Json::from("")), ("variablesReference", 0.into())]));
+        }
+        let vm = self.debugger()?.vm();
+        let place = command::place(expression, vm.symbols()).map_err(|e| e.to_string())?;
+        Ok(Json::object([("result", Json::from(show(vm, place))), ("variablesReference", 0.into())]))
+    }
+
+    /// Carry out the motion in `running` until something stops it: a breakpoint, the
+    /// end of the step, a request from the client or the program waiting for input.
+    fn run(&mut self) {
+        let Some(motion) = self.running else {
+            return;
+        };
+        let debugger = self.debugger.as_mut().expect("only a launched program runs");
+        let stop = match motion {
+            Motion::Continue => debugger.cont(),
+            Motion::Next => debugger.next(),
+            Motion::StepIn => debugger.step(1),
+            Motion::StepOut => debugger.finish(),
+            Motion::StepBack => debugger.step_back(1),
+            Motion::ReverseContinue => debugger.reverse_cont(),
+        };
+        self.flush_output();
+        match stop {
+            Stop::Paused => {
+                let (request, closed, waiting_for_input) = {
+                    let mut inbox = self.inbox.borrow_mut();
+                    let waiting_for_input = std::mem::take(&mut inbox.waiting_for_input);
+                    (inbox.queued.pop_front(), inbox.closed, waiting_for_input)
+                };
+                if waiting_for_input {
+                    self.running = None;
+                    // Only motions that start over where they stopped are picked up again
+                    self.waiting = matches!(motion, Motion::Continue | Motion::StepIn).then_some(motion);
+                    self.output("console", "The program is waiting for input: evaluate '> text' to type a line.\n");
+                    self.stopped("pause", Some("Waiting for input".to_string()));
+                } else if let Some(request) = request {
+                    // A continue carries on after the request; other motions end here
+                    self.handle(request);
+                    if self.running.is_some() && motion != Motion::Continue {
+                        self.running = None;
+                        self.stopped("pause", None);
+                    }
+                } else if closed {
+                    self.running = None;
+                    self.finished = true;
+                }
+            }
+            Stop::Done => {
+                self.running = None;
+                self.stopped("step", None);
+            }
+            Stop::HistoryStart => {
+                self.running = None;
+                self.stopped("step", Some("Start of the recorded history".to_string()));
+            }
+            Stop::Breakpoint(_) => {
+                self.running = None;
+                self.stopped("breakpoint", None);
+            }
+            Stop::Watchpoint { .. } => {
+                self.running = None;
+                self.stopped("data breakpoint", None);
+            }
+            Stop::Exited(ExitReason::Halted) => {
+                self.running = None;
+                self.event("exited", Json::object([("exitCode", Json::from(0))]));
+                self.event("terminated", Json::Null);
+            }
+            Stop::Exited(reason) => {
+                self.running = None;
+                self.stopped("exception", Some(reason.to_string()));
+            }
+        }
+    }
+
+    /// Pass on what the program printed.
+    fn flush_output(&mut self) {
+        let output = self.console.borrow_mut().take_output();
+        if !output.is_empty() {
+            self.output("stdout", &String::from_utf8_lossy(&output));
+        }
+    }
+
+    fn output(&mut self, category: &str, text: &str) {
+        self.event("output", Json::object([("category", Json::from(category)), ("output", text.into())]));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(description) = description {
+            body.push(("description", description.clone().into()));
+            body.push(("text", description.into()));
+        }
+        self.event("stopped", Json::object(body));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.seq += 1;
+        let mut message = vec![("seq", Json::from(self.seq)), ("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            message.push(("body", body));
+        }
+        self.outbox.push(Json::object(message));
+    }
+}
+
+/// Stop in front of a built-in GETC or IN that would find no input, and now and then
+/// look for a request from the client.
+fn pause_check(inbox: Rc<RefCell<Inbox>>, console: Rc<RefCell<BufferConsole>>) -> PauseCheck {
+    Box::new(move |vm| {
+        let mut inbox = inbox.borrow_mut();
+        if vm.trap_mode() == TrapMode::Fast && !console.borrow().has_input() {
+            let pc = vm.registers().read(RegisterEnum::PC);
+            if let Ok(Instruction::Trap { vector: 0x20 | 0x23 }) = decode(vm.memory().peek(pc as usize)) {
+                inbox.waiting_for_input = true;
+                return true;
+            }
+        }
+        if inbox.countdown > 0 {
+            inbox.countdown -= 1;
+            return false;
+        }
+        inbox.countdown = POLL_INTERVAL;
+        match inbox.receiver.try_recv() {
+            Ok(request) => {
+                inbox.queued.push_back(request);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => {
+                inbox.closed = true;
+                true
+            }
+        }
+    })
+}
+
+/// How a variable shows a place: the word in hex and decimal, or the flags for COND.
+fn show(vm: &LC3, place: Place) -> String {
+    let value = place.read(vm);
+    match place {
+        Place::Register(RegisterEnum::COND) => condition(value),
+        _ => format!("x{:04X} (#{})", value, value as i16),
+    }
+}
+
+/// Paths name the same file when they canonicalize to the same path.
+fn canonical(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+fn from_client_line(line: i64, lines_start_at_1: bool) -> Option<usize> {
+    usize::try_from(if lines_start_at_1 { line } else { line + 1 }).ok().filter(|&line| line > 0)
+}
+
+fn to_client_line(line: usize, lines_start_at_1: bool) -> usize {
+    if lines_start_at_1 { line } else { line - 1 }
+}
+
+/// Serve one client on `input` and `output`, usually stdin and stdout, until it
+/// disconnects.
+pub fn serve(input: impl Read + Send + 'static, mut output: impl Write) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    // Requests are read on their own thread, so a pause can arrive while the program runs.
+    // The thread is not joined: it may be blocked reading input nobody will send.
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Ok(Some(request)) = read_message(&mut input) {
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+    Adapter::new(receiver).serve(|message| write_message(&mut output, message))
+}
+
+/// Read one `Content-Length` framed message; `Ok(None)` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    let text = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Json::parse(&text).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    const PROGRAM: &str = "\
+        .ORIG x3000
+        GETC
+        ADD R1, R0, #0
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        LEA R0, TEXT
+        PUTS
+        HALT
+TEXT    .STRINGZ \"done\"
+        .END
+";
+
+    /// An adapter that has launched `PROGRAM` from a temporary file, with the path.
+    fn launched(name: &str, stop_on_entry: bool) -> (Adapter, Sender<Json>, String) {
+        let path = std::env::temp_dir().join(format!("demovm-dap-{}-{}.asm", name, std::process::id()));
+        std::fs::write(&path, PROGRAM).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let (sender, receiver) = mpsc::channel();
+        let mut adapter = Adapter::new(receiver);
+        exchange(&mut adapter, "initialize", Json::object([("adapterID", Json::from("lc3"))]));
+        let messages = exchange(
+            &mut adapter,
+            "launch",
+            Json::object([("program", Json::from(path.as_str())), ("stopOnEntry", stop_on_entry.into())]),
+        );
+        assert_eq!(kinds(&messages), ["response launch", "event initialized"]);
+        (adapter, sender, path)
+    }
+
+    /// Send one request, let the program run if it asks to, and collect what comes back.
+    fn exchange(adapter: &mut Adapter, command: &str, arguments: Json) -> Vec<Json> {
+        adapter.handle(Json::object([
+            ("seq", Json::from(1)),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ]));
+        while adapter.running.is_some() {
+            adapter.run();
+        }
+        adapter.outbox.drain(..).collect()
+    }
+
+    /// `response <command>` or `event <event>` for each message, `stopped` with its reason.
+    fn kinds(messages: &[Json]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| {
+                let field = |key| message.get(key).and_then(Json::as_str).unwrap_or_default();
+                match (field("type"), message.get("body").and_then(|body| body.get("reason"))) {
+                    ("response", _) => format!("response {}", field("command")),
+                    (_, Some(reason)) => format!("event {} {}", field("event"), reason.as_str().unwrap()),
+                    _ => format!("event {}", field("event")),
+                }
+            })
+            .collect()
+    }
+
+    fn body(messages: &[Json]) -> &Json {
+        messages[0].get("body").unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints_stepping_and_variables() {
+        let (mut adapter, _sender, path) = launched("session", true);
+        let messages = exchange(
+            &mut adapter,
+            "setBreakpoints",
+            Json::object([
+                ("source", Json::object([("path", Json::from(path.as_str()))])),
+                ("breakpoints", vec![Json::object([("line", Json::from(4))]), Json::object([("line", Json::from(9))])].into()),
+            ]),
+        );
+        // Line 4 is LOOP's ADD; the string on line 9 has no instruction after it
+        let breakpoints = body(&messages).get("breakpoints").and_then(Json::as_array).unwrap();
+        assert_eq!(breakpoints[0].to_string(), r#"{"verified":true,"line":4}"#);
+        assert_eq!(breakpoints[1].get("verified"), Some(&Json::Bool(false)));
+        let messages = exchange(&mut adapter, "configurationDone", Json::Null);
+        assert_eq!(kinds(&messages), ["response configurationDone", "event stopped entry"]);
+
+        // GETC with nothing typed stops, and typed input picks the continue up again
+        let messages = exchange(&mut adapter, "continue", Json::Null);
+        assert_eq!(kinds(&messages), ["response continue", "event output", "event stopped pause"]);
+        let messages = exchange(&mut adapter, "evaluate", Json::object([("expression", Json::from("> \u{2}"))]));
+        assert_eq!(kinds(&messages), ["response evaluate", "event continued", "event stopped breakpoint"]);
+        let trace = exchange(&mut adapter, "stackTrace", Json::Null);
+        let frame = &body(&trace).get("stackFrames").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(frame.get("name").and_then(Json::as_str), Some("x3002 (LOOP)"));
+        assert_eq!(frame.get("line").and_then(Json::as_i64), Some(4));
+
+        let registers = exchange(&mut adapter, "variables", Json::object([("variablesReference", Json::from(1))]));
+        let registers = body(&registers).get("variables").and_then(Json::as_array).unwrap();
+        assert_eq!(registers[1].to_string(), r#"{"name":"R1","value":"x0002 (#2)","variablesReference":0}"#);
+        assert_eq!(registers[9].get("value").and_then(Json::as_str), Some("POS"));
+        let memory = exchange(
+            &mut adapter,
+            "variables",
+            Json::object([("variablesReference", Json::from(3)), ("start", 0x3000.into()), ("count", 2.into())]),
+        );
+        let memory = body(&memory).get("variables").and_then(Json::as_array).unwrap();
+        assert_eq!(memory[1].get("name").and_then(Json::as_str), Some("x3001"));
+        assert_eq!(memory[1].get("value").and_then(Json::as_str), Some("x1220 (#4640)"));
+
+        let messages = exchange(&mut adapter, "setVariable", Json::object([("name", Json::from("R1")), ("value", "#1".into())]));
+        assert_eq!(body(&messages).get("value").and_then(Json::as_str), Some("x0001 (#1)"));
+        let messages = exchange(&mut adapter, "evaluate", Json::object([("expression", Json::from("TEXT"))]));
+        assert_eq!(body(&messages).get("result").and_then(Json::as_str), Some("x0064 (#100)"));
+        let messages = exchange(&mut adapter, "next", Json::Null);
+        assert_eq!(kinds(&messages), ["response next", "event stopped step"]);
+        // Stepping back lands on the breakpoint again, with R1 as it was set
+        let messages = exchange(&mut adapter, "stepBack", Json::Null);
+        assert_eq!(kinds(&messages), ["response stepBack", "event stopped breakpoint"]);
+        let messages = exchange(&mut adapter, "evaluate", Json::object([("expression", Json::from("R1"))]));
+        assert_eq!(body(&messages).get("result").and_then(Json::as_str), Some("x0001 (#1)"));
+        exchange(&mut adapter, "next", Json::Null);
+
+        // R1 reaches zero on this pass, so the program runs to the end
+        let messages = exchange(&mut adapter, "continue", Json::Null);
+        assert_eq!(kinds(&messages), ["response continue", "event output", "event exited", "event terminated"]);
+        assert_eq!(messages[1].get("body").and_then(|body| body.get("output")).and_then(Json::as_str), Some("doneHALT\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pause_and_errors() {
+        let (mut adapter, sender, path) = launched("pause", false);
+        // With input queued the first look for requests comes before GETC runs
+        adapter.console.borrow_mut().push_input(b"x");
+        sender.send(Json::object([("seq", Json::from(2)), ("type", "request".into()), ("command", "pause".into())])).unwrap();
+        let messages = exchange(&mut adapter, "configurationDone", Json::Null);
+        assert_eq!(kinds(&messages), ["response configurationDone", "response pause", "event stopped pause"]);
+
+        let messages = exchange(&mut adapter, "evaluate", Json::object([("expression", Json::from("NOWHERE"))]));
+        assert_eq!(messages[0].get("success"), Some(&Json::Bool(false)));
+        assert_eq!(messages[0].get("message").and_then(Json::as_str), Some("no label or address 'NOWHERE'"));
+        let messages = exchange(&mut adapter, "restartFrame", Json::Null);
+        assert_eq!(messages[0].get("message").and_then(Json::as_str), Some("unsupported request 'restartFrame'"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut output = Vec::new();
+        write_message(&mut output, &Json::object([("seq", Json::from(1))])).unwrap();
+        assert_eq!(output, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+        let mut input = io::Cursor::new([output.as_slice(), b"Content-Length: 2\r\n\r\n[]"].concat());
+        assert_eq!(read_message(&mut input).unwrap(), Some(Json::object([("seq", Json::from(1))])));
+        assert_eq!(read_message(&mut input).unwrap(), Some(Json::Array(Vec::new())));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+}
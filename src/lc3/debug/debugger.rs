@@ -0,0 +1,446 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::history::{History, LastWrite};
+use crate::lc3::cpu::decode::{decode, Instruction};
+use crate::lc3::hardware::Memory::MemoryAccess;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::vm::{ExitReason, Snapshot, StepInfo, LC3};
+
+/// Which accesses to a watched address stop execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes.
+    Access,
+}
+
+impl WatchKind {
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        match (self, access) {
+            (WatchKind::Access, _) => true,
+            (WatchKind::Read, MemoryAccess::Read { .. }) => true,
+            (WatchKind::Write, MemoryAccess::Write { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The step, next or finish that was asked for completed.
+    Done,
+    /// Stopped in front of the instruction at a breakpoint.
+    Breakpoint(u16),
+    /// The instruction at `pc` made `access` to a watched address; it has completed.
+    Watchpoint { pc: u16, access: MemoryAccess },
+    /// The machine stopped on its own.
+    Exited(ExitReason),
+    /// The pause check asked to stop in front of the instruction at PC.
+    Paused,
+    /// Going backward, every recorded instruction has been undone.
+    HistoryStart,
+}
+
+/// An `LC3` with watchpoints and the stepping commands of a source-level debugger.
+///
+/// Breakpoints are the machine's own (`LC3::add_breakpoint`). Everything except an
+/// unwatched, unrecorded `cont` goes through `LC3::step`, so watchpoints see each
+/// instruction's memory accesses and the history can record how to undo it.
+pub struct Debugger {
+    vm: LC3,
+    watchpoints: BTreeMap<u16, WatchKind>,
+    /// Set once the program halts; the built-in HALT leaves the MCR running.
+    halted: bool,
+    pause: Option<PauseCheck>,
+    /// Undo log for going backward, while recording.
+    history: Option<History>,
+}
+
+/// Asked in front of every instruction whether to stop there; see `Debugger::set_pause_check`.
+pub type PauseCheck = Box<dyn FnMut(&LC3) -> bool>;
+
+impl Debugger {
+    pub fn new(vm: LC3) -> Self {
+        Debugger { vm, watchpoints: BTreeMap::new(), halted: false, pause: None, history: None }
+    }
+
+    pub fn vm(&self) -> &LC3 {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut LC3 {
+        &mut self.vm
+    }
+
+    /// Watch `address`, replacing an earlier watchpoint there.
+    pub fn watch(&mut self, address: u16, kind: WatchKind) {
+        self.watchpoints.insert(address, kind);
+    }
+
+    /// Returns `true` if `address` was watched.
+    pub fn unwatch(&mut self, address: u16) -> bool {
+        self.watchpoints.remove(&address).is_some()
+    }
+
+    /// Watchpoints in address order.
+    pub fn watchpoints(&self) -> impl Iterator<Item = (u16, WatchKind)> + '_ {
+        self.watchpoints.iter().map(|(&address, &kind)| (address, kind))
+    }
+
+    /// Let `check` stop any step, next, finish or continue with `Stop::Paused`, in front
+    /// of an instruction it returns `true` for. Front-ends use it to notice a pause
+    /// request while the program runs.
+    pub fn set_pause_check(&mut self, check: Option<PauseCheck>) {
+        self.pause = check;
+    }
+
+    /// Keep an undo log of the last `limit` instructions, so execution can go backward;
+    /// `None` stops recording and forgets the log.
+    pub fn record(&mut self, limit: Option<usize>) {
+        self.history = limit.map(History::new);
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Put the machine back into a snapshot's state. The history starts over, since it
+    /// undoes toward a past the snapshot doesn't share.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.vm.restore(snapshot);
+        self.halted = false;
+        if let Some(history) = &mut self.history {
+            *history = History::new(history.limit());
+        }
+    }
+
+    /// The latest recorded store to `address`.
+    pub fn last_write(&self, address: u16) -> Option<LastWrite> {
+        self.history.as_ref()?.last_write(address)
+    }
+
+    /// Undo `count` instructions, stopping early in front of a breakpoint or after
+    /// undoing a watched store.
+    pub fn step_back(&mut self, count: u64) -> Stop {
+        let mut remaining = count;
+        self.reverse(|| {
+            remaining -= 1;
+            remaining == 0
+        })
+    }
+
+    /// Undo instructions until one at a breakpoint or one that stored to a watched
+    /// address, or until the history runs out.
+    pub fn reverse_cont(&mut self) -> Stop {
+        self.reverse(|| false)
+    }
+
+    /// Execute `count` instructions.
+    pub fn step(&mut self, count: u64) -> Stop {
+        let mut remaining = count;
+        self.resume(|_| {
+            remaining -= 1;
+            remaining == 0
+        })
+    }
+
+    /// Execute one instruction, running a JSR, JSRR or TRAP through to its return.
+    pub fn next(&mut self) -> Stop {
+        let pc = self.vm.registers().read(RegisterEnum::PC);
+        match decode(self.vm.memory().peek(pc as usize)) {
+            Ok(Instruction::Jsr { .. } | Instruction::Jsrr { .. } | Instruction::Trap { .. }) => {
+                let return_address = pc.wrapping_add(1);
+                let mut depth = 0;
+                self.resume(|info| {
+                    depth += call_depth_change(info);
+                    depth <= 0 && info.pc_after == return_address
+                })
+            }
+            _ => self.step(1),
+        }
+    }
+
+    /// Run until the current subroutine returns with RET (or an interrupt or trap
+    /// routine with RTI); stops just after the return.
+    pub fn finish(&mut self) -> Stop {
+        let mut depth = 0;
+        self.resume(|info| {
+            depth += call_depth_change(info);
+            depth < 0
+        })
+    }
+
+    /// Run until a breakpoint, a watchpoint or the end of the program. The instruction
+    /// at PC is executed even if it has a breakpoint.
+    pub fn cont(&mut self) -> Stop {
+        if !self.watchpoints.is_empty() || self.history.is_some() {
+            return self.resume(|_| false);
+        }
+        // Nothing to check per instruction: once past PC, let the machine run at full speed
+        match self.step(1) {
+            Stop::Done => {}
+            stop => return stop,
+        }
+        let pause = &mut self.pause;
+        match self.vm.run_until(|vm| pause.as_mut().is_some_and(|check| check(vm))) {
+            Ok(()) => Stop::Paused,
+            Err(ExitReason::Breakpoint(pc)) => Stop::Breakpoint(pc),
+            Err(reason) => self.exited(reason),
+        }
+    }
+
+    /// Step until `done` holds for an executed instruction, or something stops execution first.
+    fn resume(&mut self, mut done: impl FnMut(&StepInfo) -> bool) -> Stop {
+        if self.halted || !self.vm.memory().clock_enabled() {
+            return Stop::Exited(ExitReason::Halted);
+        }
+        loop {
+            if self.pause.as_mut().is_some_and(|check| check(&self.vm)) {
+                return Stop::Paused;
+            }
+            let before =
+                self.history.is_some().then(|| (self.vm.registers().clone(), self.vm.instructions_executed()));
+            let info = self.vm.step();
+            if let (Some(history), Some((registers, instructions))) = (&mut self.history, before) {
+                history.push(registers, instructions, &info);
+            }
+            if let Some(reason) = info.exit {
+                return self.exited(reason);
+            }
+            let hit = info.memory.iter().find(|access| {
+                let address = match access {
+                    MemoryAccess::Read { address, .. } | MemoryAccess::Write { address, .. } => address,
+                };
+                self.watchpoints.get(address).is_some_and(|kind| kind.matches(access))
+            });
+            if let Some(&access) = hit {
+                return Stop::Watchpoint { pc: info.pc_before, access };
+            }
+            if self.vm.has_breakpoint(info.pc_after) {
+                return Stop::Breakpoint(info.pc_after);
+            }
+            if done(&info) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// Undo recorded instructions until `done` holds after one, or something stops first.
+    fn reverse(&mut self, mut done: impl FnMut() -> bool) -> Stop {
+        loop {
+            let Some(entry) = self.history.as_mut().and_then(|history| history.undo(&mut self.vm)) else {
+                return Stop::HistoryStart;
+            };
+            self.halted = false;
+            let hit = entry.stores.iter().find(|store| {
+                self.watchpoints.get(&store.address).is_some_and(|kind| kind.matches(&store.access()))
+            });
+            if let Some(store) = hit {
+                return Stop::Watchpoint { pc: entry.pc, access: store.access() };
+            }
+            if self.vm.has_breakpoint(entry.pc) {
+                return Stop::Breakpoint(entry.pc);
+            }
+            if done() {
+                return Stop::Done;
+            }
+        }
+    }
+
+    fn exited(&mut self, reason: ExitReason) -> Stop {
+        self.halted |= reason == ExitReason::Halted;
+        Stop::Exited(reason)
+    }
+}
+
+/// +1 for an instruction that entered a subroutine, trap or interrupt routine, -1 for
+/// one that returned from it.
+fn call_depth_change(info: &StepInfo) -> i32 {
+    let mut change = 0;
+    if info.interrupt.is_some() {
+        change += 1;
+    }
+    match decode(info.instr) {
+        Ok(Instruction::Jsr { .. } | Instruction::Jsrr { .. }) => change += 1,
+        // Built-in trap routines complete within the TRAP itself
+        Ok(Instruction::Trap { .. })
+            if info.interrupt.is_none() && info.pc_after != info.pc_before.wrapping_add(1) =>
+        {
+            change += 1
+        }
+        Ok(Instruction::Jmp { base: RegisterEnum::R7 } | Instruction::Rti) => change -= 1,
+        _ => {}
+    }
+    change
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+
+    /// Counts down from 3 recursively, then stores the result and halts.
+    const PROGRAM: &str = "
+        .ORIG x3000
+        LD  R0, DEPTH
+        JSR COUNT
+        ST  R1, RESULT
+        HALT
+COUNT   ADD R1, R1, #1
+        ADD R0, R0, #-1
+        BRz BOTTOM
+        ADD R6, R6, #-1
+        STR R7, R6, #0
+        JSR COUNT
+        LDR R7, R6, #0
+        ADD R6, R6, #1
+BOTTOM  RET
+DEPTH   .FILL #3
+RESULT  .BLKW #1
+        .END
+    ";
+
+    fn debugger() -> (Debugger, impl Fn(&str) -> u16) {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+        (Debugger::new(vm), move |name: &str| assembly.symbol(name).unwrap())
+    }
+
+    fn pc(debugger: &Debugger) -> u16 {
+        debugger.vm().registers().read(RegisterEnum::PC)
+    }
+
+    #[test]
+    fn test_step_and_next_over_recursion() {
+        let (mut debugger, _) = debugger();
+        assert_eq!(debugger.step(1), Stop::Done);
+        assert_eq!(pc(&debugger), 0x3001);
+        // The whole recursive call runs, not just up to its innermost RET
+        assert_eq!(debugger.next(), Stop::Done);
+        assert_eq!(pc(&debugger), 0x3002);
+        assert_eq!(debugger.vm().registers().read(RegisterEnum::R1), 3);
+        assert_eq!(debugger.next(), Stop::Done);
+        assert_eq!(debugger.next(), Stop::Exited(ExitReason::Halted));
+        assert_eq!(debugger.step(1), Stop::Exited(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_finish_returns_from_current_frame() {
+        let (mut debugger, label) = debugger();
+        debugger.vm_mut().add_breakpoint(label("BOTTOM"));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(label("BOTTOM")));
+        // Innermost frame returns into the middle of its caller
+        assert_eq!(debugger.finish(), Stop::Done);
+        assert_eq!(pc(&debugger), label("COUNT") + 6);
+        debugger.vm_mut().remove_breakpoint(label("BOTTOM"));
+        assert_eq!(debugger.finish(), Stop::Done);
+        assert_eq!(debugger.finish(), Stop::Done);
+        assert_eq!(pc(&debugger), 0x3002);
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let (mut debugger, label) = debugger();
+        debugger.vm_mut().add_breakpoint(label("COUNT"));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(label("COUNT")));
+        // Continuing from a breakpoint executes it and stops at the next hit
+        assert_eq!(debugger.cont(), Stop::Breakpoint(label("COUNT")));
+        assert_eq!(debugger.vm().registers().read(RegisterEnum::R1), 1);
+        debugger.vm_mut().remove_breakpoint(label("COUNT"));
+        assert_eq!(debugger.cont(), Stop::Exited(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, label) = debugger();
+        let result = label("RESULT");
+        debugger.watch(result, WatchKind::Read);
+        debugger.watch(label("DEPTH"), WatchKind::Access);
+        assert_eq!(
+            debugger.cont(),
+            Stop::Watchpoint { pc: 0x3000, access: MemoryAccess::Read { address: label("DEPTH"), value: 3 } }
+        );
+        // A read watchpoint ignores the store
+        assert!(debugger.unwatch(label("DEPTH")));
+        assert!(!debugger.unwatch(label("DEPTH")));
+        assert_eq!(debugger.cont(), Stop::Exited(ExitReason::Halted));
+
+        let (mut debugger, _) = self::debugger();
+        debugger.watch(result, WatchKind::Write);
+        assert_eq!(
+            debugger.cont(),
+            Stop::Watchpoint { pc: 0x3002, access: MemoryAccess::Write { address: result, old: 0, value: 3 } }
+        );
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), [(result, WatchKind::Write)]);
+    }
+
+    #[test]
+    fn test_pause_check() {
+        let (mut debugger, label) = debugger();
+        let bottom = label("BOTTOM");
+        debugger.set_pause_check(Some(Box::new(move |vm| vm.registers().read(RegisterEnum::PC) == bottom)));
+        // Both the full-speed and the per-step continue consult it
+        assert_eq!(debugger.cont(), Stop::Paused);
+        assert_eq!(pc(&debugger), bottom);
+        debugger.watch(label("RESULT"), WatchKind::Write);
+        assert_eq!(debugger.cont(), Stop::Paused);
+        assert_eq!(pc(&debugger), bottom);
+        debugger.set_pause_check(None);
+        assert_eq!(debugger.step(1), Stop::Done);
+    }
+
+    #[test]
+    fn test_history() {
+        let (mut debugger, label) = debugger();
+        debugger.record(Some(100));
+        assert_eq!(debugger.step_back(1), Stop::HistoryStart);
+        debugger.vm_mut().add_breakpoint(label("COUNT"));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(label("COUNT")));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(label("COUNT")));
+        debugger.vm_mut().remove_breakpoint(label("COUNT"));
+        assert_eq!(debugger.cont(), Stop::Exited(ExitReason::Halted));
+        assert_eq!(debugger.vm().memory().peek(label("RESULT") as usize), 3);
+
+        // Who left the result there, and what was on the stack before
+        let write = debugger.last_write(label("RESULT")).unwrap();
+        assert_eq!((write.ago, write.pc, write.store.old, write.store.value), (1, 0x3002, 0, 3));
+        let executed = debugger.vm().instructions_executed();
+        assert_eq!(debugger.step_back(2), Stop::Done);
+        assert_eq!(pc(&debugger), 0x3002);
+        assert_eq!(debugger.vm().instructions_executed(), executed - 2);
+        assert_eq!(debugger.vm().memory().peek(label("RESULT") as usize), 0);
+
+        // Back into the innermost of the three calls, then the one around it
+        debugger.vm_mut().add_breakpoint(label("COUNT"));
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(label("COUNT")));
+        assert_eq!(debugger.vm().registers().read(RegisterEnum::R1), 2);
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(label("COUNT")));
+        assert_eq!(debugger.vm().registers().read(RegisterEnum::R1), 1);
+        debugger.vm_mut().remove_breakpoint(label("COUNT"));
+        debugger.watch(label("DEPTH"), WatchKind::Write);
+        assert_eq!(debugger.reverse_cont(), Stop::HistoryStart);
+        assert_eq!(pc(&debugger), 0x3000);
+        assert_eq!(debugger.vm().instructions_executed(), 0);
+
+        // Forward again over the undone instructions gives the same result
+        assert_eq!(debugger.cont(), Stop::Exited(ExitReason::Halted));
+        assert_eq!(debugger.vm().memory().peek(label("RESULT") as usize), 3);
+        assert_eq!(debugger.history().unwrap().len(), 26);
+        assert_eq!(debugger.vm().instructions_executed(), executed);
+    }
+}
@@ -0,0 +1,590 @@
+//! A GDB Remote Serial Protocol stub, so GDB and IDE front-ends can drive a `Debugger`.
+//!
+//! GDB addresses memory in bytes, so the stub presents the LC-3's 64K words as 128K
+//! bytes: LC-3 address `a` is byte address `2 * a`, each word stored little-endian.
+//! PC is reported the same way, as a 32-bit byte address; R0-R7 and the PSR are plain
+//! 16-bit values. `TARGET_XML` describes this layout.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use super::debugger::{Debugger, Stop, WatchKind};
+use crate::lc3::hardware::Memory::MemoryAccess;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::vm::ExitReason;
+
+/// Target description served through `qXfer:features:read:target.xml`.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <!-- LC-3: 16-bit words, one per address. GDB sees byte addresses, twice the
+       LC-3 address, with every word stored as two little-endian bytes. -->
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="int16"/>
+    <reg name="r7" bitsize="16" type="int16"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+/// Largest packet the stub accepts, advertised in `qSupported`.
+pub const PACKET_SIZE: usize = 0x4000;
+/// Instructions run between checks for a GDB interrupt (Ctrl+C) while continuing.
+const INTERRUPT_CHECK_INTERVAL: u64 = 10_000;
+
+/// Register numbers as GDB uses them, following `TARGET_XML`.
+const PC_REGNUM: usize = 8;
+const PSR_REGNUM: usize = 9;
+
+/// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// What to send back for a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Packet(String),
+    /// Nothing; the connection ends (`k`).
+    Close,
+    /// Send the packet, then end the connection (`D`).
+    PacketThenClose(String),
+}
+
+/// The protocol logic, independent of the connection it runs over.
+pub struct GdbStub {
+    debugger: Debugger,
+    /// Set by `QStartNoAckMode`; packets are then no longer acknowledged.
+    no_ack: bool,
+    /// Set once the program has stopped for good, so `?` reports it.
+    exited: Option<ExitReason>,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> Self {
+        GdbStub { debugger, no_ack: false, exited: None }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Answer one packet (without its `$` and checksum). While the program runs,
+    /// `interrupted` is polled now and then; returning `true` stops it with SIGINT.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.halt_reason(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b's') => self.resume(&packet[1..], false, interrupted),
+            Some(b'c') => self.resume(&packet[1..], true, interrupted),
+            Some(b'b') => self.reverse(&packet[1..]),
+            Some(b'H') | Some(b'T') => Some("OK".to_string()),
+            Some(b'D') => return Reply::PacketThenClose("OK".to_string()),
+            Some(b'k') => return Reply::Close,
+            Some(b'q') | Some(b'Q') | Some(b'v') => self.query(packet, interrupted),
+            _ => None,
+        };
+        // Unsupported packets, and ones too malformed to answer, get the empty reply
+        Reply::Packet(reply.unwrap_or_default())
+    }
+
+    fn query(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            let reverse = if self.debugger.history().is_some() { ";ReverseStep+;ReverseContinue+" } else { "" };
+            return Some(format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+{}", PACKET_SIZE, reverse));
+        }
+        if let Some(annex) = packet.strip_prefix("qXfer:features:read:") {
+            return Some(self.target_xml(annex));
+        }
+        if let Some(actions) = packet.strip_prefix("vCont") {
+            // Only one thread, so the first action is the one that applies
+            return match actions.strip_prefix(';').map(|actions| actions.as_bytes().first()) {
+                None => Some("vCont;c;C;s;S".to_string()),
+                Some(Some(b'c' | b'C')) => self.resume("", true, interrupted),
+                Some(Some(b's' | b'S')) => self.resume("", false, interrupted),
+                Some(_) => Some("E01".to_string()),
+            };
+        }
+        let reply = match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK"
+            }
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            "vMustReplyEmpty" => "",
+            _ => return None,
+        };
+        Some(reply.to_string())
+    }
+
+    /// `target.xml:offset,length`
+    fn target_xml(&self, annex: &str) -> String {
+        let Some((offset, length)) = annex.strip_prefix("target.xml:").and_then(|range| range.split_once(',')) else {
+            return "E00".to_string();
+        };
+        let (Some(offset), Some(length)) = (hex(offset), hex(length)) else {
+            return "E01".to_string();
+        };
+        let offset = (offset as usize).min(TARGET_XML.len());
+        let end = offset.saturating_add(length as usize).min(TARGET_XML.len());
+        let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+        format!("{}{}", marker, &TARGET_XML[offset..end])
+    }
+
+    fn halt_reason(&self) -> Option<String> {
+        Some(match self.exited {
+            Some(reason) => stop_reply(Stop::Exited(reason)),
+            None => format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    fn read_registers(&self) -> Option<String> {
+        Some((0..=PSR_REGNUM).map(|regnum| self.register(regnum)).collect())
+    }
+
+    fn write_registers(&mut self, data: &str) -> Option<String> {
+        let mut rest = data;
+        for regnum in 0..=PSR_REGNUM {
+            let width = register_width(regnum) * 2;
+            if rest.len() < width {
+                return Some("E01".to_string());
+            }
+            let (value, tail) = rest.split_at(width);
+            self.set_register(regnum, little_endian(value)?);
+            rest = tail;
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        match hex(args) {
+            Some(regnum) if regnum as usize <= PSR_REGNUM => Some(self.register(regnum as usize)),
+            _ => Some("E01".to_string()),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (regnum, value) = args.split_once('=')?;
+        match (hex(regnum), little_endian(value)) {
+            (Some(regnum), Some(value)) if regnum as usize <= PSR_REGNUM => {
+                self.set_register(regnum as usize, value);
+                Some("OK".to_string())
+            }
+            _ => Some("E01".to_string()),
+        }
+    }
+
+    /// Register `regnum` as little-endian hex.
+    fn register(&self, regnum: usize) -> String {
+        let registers = self.debugger.vm().registers();
+        match regnum {
+            PC_REGNUM => hex_bytes(&(registers.read(RegisterEnum::PC) as u32 * 2).to_le_bytes()),
+            PSR_REGNUM => hex_bytes(&registers.psr().to_le_bytes()),
+            r => hex_bytes(&registers.data[r].to_le_bytes()),
+        }
+    }
+
+    fn set_register(&mut self, regnum: usize, value: u32) {
+        let registers = self.debugger.vm_mut().registers_mut();
+        match regnum {
+            PC_REGNUM => registers.write(RegisterEnum::PC, (value / 2) as u16),
+            PSR_REGNUM => registers.set_psr(value as u16),
+            r => registers.data[r] = value as u16,
+        }
+    }
+
+    /// `addr,length`: bytes of the word-per-address memory.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let Some((address, length)) = address_and_length(args) else {
+            return Some("E01".to_string());
+        };
+        let memory = self.debugger.vm().memory();
+        let bytes: Vec<u8> = (address..address + length)
+            .map(|byte| {
+                let word = memory.peek((byte / 2) as usize & 0xFFFF);
+                word.to_le_bytes()[(byte % 2) as usize]
+            })
+            .collect();
+        Some(hex_bytes(&bytes))
+    }
+
+    /// `addr,length:XX...`
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let Some((address, length)) = address_and_length(range) else {
+            return Some("E01".to_string());
+        };
+        let Some(bytes) = parse_hex_bytes(data).filter(|bytes| bytes.len() == length as usize) else {
+            return Some("E01".to_string());
+        };
+        let memory = self.debugger.vm_mut().memory_mut();
+        for (byte, value) in (address..).zip(bytes) {
+            let word_address = (byte / 2) as usize & 0xFFFF;
+            let mut word = memory.peek(word_address).to_le_bytes();
+            word[(byte % 2) as usize] = value;
+            memory.write(word_address, u16::from_le_bytes(word));
+        }
+        Some("OK".to_string())
+    }
+
+    /// `type,addr,kind`: types 0 and 1 are breakpoints, 2-4 write, read and access watchpoints.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = (hex(fields.next()?)? / 2) as u16;
+        let watch = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return None,
+        };
+        match (watch, insert) {
+            (None, true) => self.debugger.vm_mut().add_breakpoint(address),
+            (None, false) => {
+                self.debugger.vm_mut().remove_breakpoint(address);
+            }
+            (Some(kind), true) => self.debugger.watch(address, kind),
+            (Some(_), false) => {
+                self.debugger.unwatch(address);
+            }
+        }
+        Some("OK".to_string())
+    }
+
+    /// `s [addr]` and `c [addr]`: optionally move PC, then step or continue.
+    fn resume(&mut self, args: &str, continuing: bool, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        if !args.is_empty() {
+            let address = hex(args)?;
+            self.set_register(PC_REGNUM, address);
+        }
+        let stop = if continuing {
+            loop {
+                match self.debugger.step(INTERRUPT_CHECK_INTERVAL) {
+                    Stop::Done if interrupted() => break None,
+                    Stop::Done => {}
+                    stop => break Some(stop),
+                }
+            }
+        } else {
+            Some(self.debugger.step(1))
+        };
+        let Some(stop) = stop else {
+            return Some(format!("S{:02x}", SIGINT));
+        };
+        if let Stop::Exited(reason) = stop {
+            self.exited = Some(reason);
+        }
+        Some(stop_reply(stop))
+    }
+
+    /// `bs` and `bc`: step or continue backward through the recorded history.
+    fn reverse(&mut self, args: &str) -> Option<String> {
+        if self.debugger.history().is_none() {
+            return Some("E01".to_string());
+        }
+        let stop = match args {
+            "s" => self.debugger.step_back(1),
+            "c" => self.debugger.reverse_cont(),
+            _ => return None,
+        };
+        self.exited = None;
+        Some(stop_reply(stop))
+    }
+}
+
+/// The stop reply packet for `stop`.
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Done | Stop::Breakpoint(_) => format!("S{:02x}", SIGTRAP),
+        Stop::Watchpoint { access, .. } => {
+            let (name, address) = match access {
+                MemoryAccess::Read { address, .. } => ("rwatch", address),
+                MemoryAccess::Write { address, .. } => ("watch", address),
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, address as u32 * 2)
+        }
+        Stop::Paused => format!("S{:02x}", SIGINT),
+        Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        Stop::Exited(ExitReason::Halted) => "W00".to_string(),
+        Stop::Exited(ExitReason::IllegalOpcode(..) | ExitReason::PrivilegeViolation(_)) => format!("S{:02x}", SIGILL),
+        Stop::Exited(ExitReason::AccessViolation(..)) => format!("S{:02x}", SIGSEGV),
+        Stop::Exited(_) => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn register_width(regnum: usize) -> usize {
+    if regnum == PC_REGNUM { 4 } else { 2 }
+}
+
+fn hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// `addr,length` in bytes, limited to the 128K bytes the stub presents.
+fn address_and_length(args: &str) -> Option<(u32, u32)> {
+    let (address, length) = args.split_once(',')?;
+    let (address, length) = (hex(address)?, hex(length)?);
+    (address.checked_add(length)? <= 0x20000).then_some((address, length))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A register value sent as little-endian hex bytes.
+fn little_endian(text: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(text)?;
+    (bytes.len() <= 4).then(|| bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+}
+
+/// Sum of the packet bytes modulo 256, sent after the `#`.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Serve GDB on `stream` until it detaches, kills the program or disconnects.
+pub fn serve(stream: TcpStream, debugger: Debugger) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut stub = GdbStub::new(debugger);
+
+    while let Some(packet) = read_packet(&mut reader, &mut writer, stub.no_ack)? {
+        let mut interrupted = || poll_interrupt(&mut reader);
+        let reply = stub.handle(&packet, &mut interrupted);
+        match reply {
+            Reply::Packet(data) => write_packet(&mut writer, &data)?,
+            Reply::PacketThenClose(data) => return write_packet(&mut writer, &data),
+            Reply::Close => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// Read the next `$data#cs` packet and acknowledge it; `None` once GDB disconnects.
+/// A stray interrupt byte between packets is dropped, there is nothing to stop.
+fn read_packet(reader: &mut impl BufRead, writer: &mut impl Write, no_ack: bool) -> io::Result<Option<String>> {
+    loop {
+        let mut byte = [0u8];
+        // Skip acks and anything else until the start of a packet
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        if reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+            return Ok(None);
+        }
+        let mut sum = [0u8; 2];
+        reader.read_exact(&mut sum)?;
+        let valid = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+            .is_some_and(|sum| sum == checksum(&data));
+        if !no_ack {
+            writer.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if valid || no_ack {
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+}
+
+fn write_packet(writer: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(writer, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+    writer.flush()
+}
+
+/// `true` if GDB sent its interrupt byte (0x03) while the program was running.
+fn poll_interrupt(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.buffer().is_empty() {
+        if reader.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+        let filled = reader.fill_buf().map(|buffer| !buffer.is_empty());
+        let _ = reader.get_ref().set_nonblocking(false);
+        // A closed connection stops the program too
+        match filled {
+            Ok(true) => {}
+            Ok(false) => return true,
+            Err(_) => return false,
+        }
+    }
+    if reader.buffer().first() == Some(&0x03) {
+        reader.consume(1);
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::LC3;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        LD  R1, VALUE
+LOOP    ADD R1, R1, #-1
+        ST  R1, COUNT
+        BRp LOOP
+        HALT
+VALUE   .FILL #3
+COUNT   .BLKW #1
+        .END
+    ";
+
+    fn stub() -> GdbStub {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+        GdbStub::new(Debugger::new(vm))
+    }
+
+    fn send(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet, &mut || false) {
+            Reply::Packet(data) | Reply::PacketThenClose(data) => data,
+            Reply::Close => panic!("unexpected close"),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = stub();
+        // R0-R7, then PC x3000 as byte address x6000, then the PSR with Z set
+        assert_eq!(send(&mut stub, "g"), format!("{}{}{}{}", "0000".repeat(6), "00300000", "00600000", "0200"));
+        assert_eq!(send(&mut stub, "p8"), "00600000");
+        assert_eq!(send(&mut stub, "P2=3412"), "OK");
+        assert_eq!(stub.debugger().vm().registers().read(RegisterEnum::R2), 0x1234);
+        assert_eq!(send(&mut stub, "P8=02600000"), "OK");
+        assert_eq!(stub.debugger().vm().registers().read(RegisterEnum::PC), 0x3001);
+
+        // LD R1, VALUE is x2204, stored little-endian at byte address x6000
+        assert_eq!(send(&mut stub, "m6000,4"), "04227f12");
+        assert_eq!(send(&mut stub, "m6001,1"), "22");
+        assert_eq!(send(&mut stub, "M600b,1:ab"), "OK");
+        assert_eq!(stub.debugger().vm().memory().peek(0x3005), 0xAB03);
+        assert_eq!(send(&mut stub, "m1fffe,4"), "E01");
+        assert_eq!(send(&mut stub, "p1f"), "E01");
+    }
+
+    #[test]
+    fn test_breakpoints_steps_and_exit() {
+        let mut stub = stub();
+        assert_eq!(send(&mut stub, "?"), "S05");
+        assert_eq!(send(&mut stub, "Z0,6002,2"), "OK");
+        assert_eq!(send(&mut stub, "c"), "S05");
+        assert_eq!(send(&mut stub, "p8"), "02600000");
+        assert_eq!(send(&mut stub, "s"), "S05");
+        assert_eq!(send(&mut stub, "p8"), "04600000");
+        assert_eq!(send(&mut stub, "vCont;c"), "S05");
+        assert_eq!(send(&mut stub, "p1"), "0200");
+        assert_eq!(send(&mut stub, "z0,6002,2"), "OK");
+        // Stop on the store of the count, reported with its byte address
+        assert_eq!(send(&mut stub, "Z2,600c,2"), "OK");
+        assert_eq!(send(&mut stub, "c"), "T05watch:600c;");
+        assert_eq!(send(&mut stub, "z2,600c,2"), "OK");
+        assert_eq!(send(&mut stub, "c"), "W00");
+        assert_eq!(send(&mut stub, "?"), "W00");
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut stub = stub();
+        assert_eq!(send(&mut stub, "bs"), "E01");
+        stub.debugger.record(Some(100));
+        assert!(send(&mut stub, "qSupported").contains("ReverseStep+;ReverseContinue+"));
+        assert_eq!(send(&mut stub, "bs"), "T05replaylog:begin;");
+        assert_eq!(send(&mut stub, "Z0,6002,2"), "OK");
+        for _ in 0..3 {
+            assert_eq!(send(&mut stub, "c"), "S05");
+        }
+        assert_eq!(send(&mut stub, "p1"), "0100");
+        // Back to the previous pass through LOOP, then one more instruction to the BRp
+        assert_eq!(send(&mut stub, "bc"), "S05");
+        assert_eq!(send(&mut stub, "p1"), "0200");
+        assert_eq!(send(&mut stub, "bs"), "S05");
+        assert_eq!(send(&mut stub, "p8"), "06600000");
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut stub = stub();
+        assert!(send(&mut stub, "qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        let first = send(&mut stub, "qXfer:features:read:target.xml:0,20");
+        assert_eq!(first, format!("m{}", &TARGET_XML[..0x20]));
+        let rest = send(&mut stub, &format!("qXfer:features:read:target.xml:20,{:x}", TARGET_XML.len()));
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x20..]));
+        assert_eq!(send(&mut stub, "vCont?"), "vCont;c;C;s;S");
+        assert_eq!(send(&mut stub, "qUnknownThing"), "");
+        assert_eq!(stub.handle("k", &mut || false), Reply::Close);
+    }
+
+    #[test]
+    fn test_serve_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut exchange = |packet: &str| {
+                write!(stream, "${}#{:02x}", packet, checksum(packet.as_bytes())).unwrap();
+                let mut reply = Vec::new();
+                let mut byte = [0u8];
+                while byte[0] != b'#' {
+                    stream.read_exact(&mut byte).unwrap();
+                    reply.push(byte[0]);
+                }
+                let mut sum = [0u8; 2];
+                stream.read_exact(&mut sum).unwrap();
+                String::from_utf8(reply).unwrap()
+            };
+            let replies = [exchange("qSupported"), exchange("m6000,2"), exchange("c"), exchange("D")];
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        serve(stream, stub().debugger).unwrap();
+        let replies = client.join().unwrap();
+        assert!(replies[0].starts_with("+$PacketSize="));
+        assert_eq!(replies[1], "+$0422#");
+        assert_eq!(replies[2], "+$W00#");
+        assert_eq!(replies[3], "+$OK#");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+
+use crate::lc3::hardware::Memory::{MemoryAccess, MemoryMappedReg};
+use crate::lc3::hardware::Reg::Registers;
+use crate::lc3::vm::{StepInfo, LC3};
+
+/// Instructions the front-ends keep history for, about 60MB at most.
+pub const DEFAULT_HISTORY: usize = 1_000_000;
+
+/// A word an instruction stored, and what was there before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Store {
+    pub address: u16,
+    pub old: u16,
+    pub value: u16,
+}
+
+impl Store {
+    pub fn access(&self) -> MemoryAccess {
+        MemoryAccess::Write { address: self.address, old: self.old, value: self.value }
+    }
+}
+
+/// One executed instruction and what it takes to undo it.
+#[derive(Clone)]
+pub struct Entry {
+    /// Where the instruction was fetched from.
+    pub pc: u16,
+    /// The whole register file in front of the instruction; at 14 words it is cheaper
+    /// to keep than to work out which registers changed.
+    pub registers: Registers,
+    /// The machine's instruction count in front of the instruction, which undoing puts
+    /// back so snapshots and replayed input see the same count as a fresh run.
+    pub instructions: u64,
+    /// Words the instruction stored, along with any trap or interrupt entry that came
+    /// with it, in order.
+    pub stores: Vec<Store>,
+}
+
+/// The most recent recorded store to an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastWrite {
+    /// Instructions executed since the one that stored; 0 for the latest.
+    pub ago: usize,
+    pub pc: u16,
+    pub store: Store,
+}
+
+/// An undo log of the last `limit` instructions, oldest first.
+///
+/// Memory-mapped devices only have their registers put back, not their internal state,
+/// and console input and output are not taken back. Stores to DDR are output rather
+/// than state, so they are left out.
+pub struct History {
+    entries: VecDeque<Entry>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        History { entries: VecDeque::new(), limit }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Record the instruction `info` describes; `registers` is the register file it
+    /// started from and `instructions` the instruction count in front of it. The oldest
+    /// entry goes once the log is full.
+    pub fn push(&mut self, registers: Registers, instructions: u64, info: &StepInfo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        let stores = info
+            .memory
+            .iter()
+            .filter_map(|access| match *access {
+                MemoryAccess::Write { address, old, value } if address != MemoryMappedReg::Ddr as u16 => {
+                    Some(Store { address, old, value })
+                }
+                _ => None,
+            })
+            .collect();
+        self.entries.push_back(Entry { pc: info.pc_before, registers, instructions, stores });
+    }
+
+    /// Undo the latest instruction on `vm`, returning what it was.
+    pub fn undo(&mut self, vm: &mut LC3) -> Option<Entry> {
+        let entry = self.entries.pop_back()?;
+        for store in entry.stores.iter().rev() {
+            vm.memory_mut().restore(store.address, store.old);
+        }
+        *vm.registers_mut() = entry.registers.clone();
+        vm.set_instructions_executed(entry.instructions);
+        Some(entry)
+    }
+
+    /// The latest recorded store to `address`.
+    pub fn last_write(&self, address: u16) -> Option<LastWrite> {
+        self.entries.iter().rev().enumerate().find_map(|(ago, entry)| {
+            let store = entry.stores.iter().rev().find(|store| store.address == address)?;
+            Some(LastWrite { ago, pc: entry.pc, store: *store })
+        })
+    }
+}
@@ -0,0 +1,15 @@
+//! Interactive debugging: `Debugger` adds watchpoints, step/next/finish/continue and,
+//! with a `history`, stepping backward to an `LC3`. `Repl` is the command line
+//! `demoVM debug` puts in front of it, and `gdb` serves it to GDB and other front-ends
+//! over the remote serial protocol; `dap` serves it to editors over the Debug Adapter
+//! Protocol.
+
+pub mod command;
+pub mod dap;
+pub mod debugger;
+pub mod gdb;
+pub mod history;
+pub mod repl;
+
+pub use debugger::Debugger;
+pub use repl::Repl;
@@ -0,0 +1,381 @@
+use std::io::{self, Write};
+
+use super::command::{parse, Command, Place, COMMANDS};
+use super::debugger::{Debugger, Stop};
+use super::history::DEFAULT_HISTORY;
+use crate::lc3::cpu::disasm::disassemble;
+use crate::lc3::hardware::Flag::ConditionFlags;
+use crate::lc3::hardware::Memory::MemoryAccess;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::sys::symbols::SymbolTable;
+use crate::lc3::vm::{ExitReason, Snapshot};
+
+/// The `demoVM debug` command loop: parses lines, runs them on a `Debugger` and
+/// prints the results.
+pub struct Repl {
+    debugger: Debugger,
+    /// Repeated by a blank line, if it is one of the execution commands.
+    last: Option<Command>,
+}
+
+impl Repl {
+    pub fn new(debugger: Debugger) -> Self {
+        Repl { debugger, last: None }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Show the instruction execution stands in front of.
+    pub fn show_location(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.debugger.vm().registers().read(RegisterEnum::PC);
+        self.examine(pc, 1, out)
+    }
+
+    /// Run one command line, printing its results to `out`. Returns `false` once the
+    /// user quits.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let command = match parse(line, self.debugger.vm().symbols()) {
+            Ok(Some(command)) => command,
+            Ok(None) => match &self.last {
+                Some(command) => command.clone(),
+                None => return Ok(true),
+            },
+            Err(e) => {
+                writeln!(out, "{}", e)?;
+                return Ok(true);
+            }
+        };
+        self.last = match command {
+            Command::Step(_)
+            | Command::Next
+            | Command::Finish
+            | Command::Continue
+            | Command::ReverseStep(_)
+            | Command::ReverseContinue => Some(command.clone()),
+            _ => None,
+        };
+
+        match command {
+            Command::Break(address) => {
+                self.debugger.vm_mut().add_breakpoint(address);
+                writeln!(out, "Breakpoint at {}", self.describe(address))?;
+            }
+            Command::Delete(address) => {
+                let removed = self.debugger.vm_mut().remove_breakpoint(address) | self.debugger.unwatch(address);
+                if removed {
+                    writeln!(out, "Deleted {}", self.describe(address))?;
+                } else {
+                    writeln!(out, "Nothing set at {}", self.describe(address))?;
+                }
+            }
+            Command::Watch(address, kind) => {
+                self.debugger.watch(address, kind);
+                writeln!(out, "Watching {} ({})", self.describe(address), kind)?;
+            }
+            Command::Step(count) => {
+                let stop = self.debugger.step(count);
+                self.report(stop, out)?;
+            }
+            Command::Next => {
+                let stop = self.debugger.next();
+                self.report(stop, out)?;
+            }
+            Command::Finish => {
+                let stop = self.debugger.finish();
+                self.report(stop, out)?;
+            }
+            Command::Continue => {
+                let stop = self.debugger.cont();
+                self.report(stop, out)?;
+            }
+            Command::ReverseStep(_) | Command::ReverseContinue if self.debugger.history().is_none() => {
+                writeln!(out, "Not recording, try 'record on'")?;
+            }
+            Command::ReverseStep(count) => {
+                let stop = self.debugger.step_back(count);
+                self.report(stop, out)?;
+            }
+            Command::ReverseContinue => {
+                let stop = self.debugger.reverse_cont();
+                self.report(stop, out)?;
+            }
+            Command::LastWrite(address) => match self.debugger.last_write(address) {
+                Some(write) => writeln!(
+                    out,
+                    "{} was set to x{:04X} (was x{:04X}) by {}, {} instruction(s) ago",
+                    self.describe(address),
+                    write.store.value,
+                    write.store.old,
+                    self.describe(write.pc),
+                    write.ago + 1
+                )?,
+                None => writeln!(out, "No recorded store to {}", self.describe(address))?,
+            },
+            Command::Record(on) => {
+                self.debugger.record(on.then_some(DEFAULT_HISTORY));
+                match on {
+                    true => writeln!(out, "Recording the last {} instructions", DEFAULT_HISTORY)?,
+                    false => writeln!(out, "Not recording")?,
+                }
+            }
+            Command::Print(None) => self.print_registers(out)?,
+            Command::Print(Some(place)) => self.print(place, out)?,
+            Command::Set(place, value) => {
+                place.write(self.debugger.vm_mut(), value);
+                self.print(place, out)?;
+            }
+            Command::Examine(address, count) => self.examine(address, count, out)?,
+            Command::Save(path) => match self.debugger.vm().snapshot().save(&path) {
+                Ok(()) => writeln!(out, "Saved the machine to {}", path)?,
+                Err(e) => writeln!(out, "Could not save {}: {}", path, e)?,
+            },
+            Command::Load(path) => match Snapshot::load(&path) {
+                Ok(snapshot) => {
+                    self.debugger.restore(&snapshot);
+                    writeln!(out, "Restored the machine from {}", path)?;
+                    self.show_location(out)?;
+                }
+                Err(e) => writeln!(out, "Could not load {}: {}", path, e)?,
+            },
+            Command::Info => self.info(out)?,
+            Command::Help => {
+                for (name, short, usage, description) in COMMANDS {
+                    let short = if short == name { String::new() } else { format!("({})", short) };
+                    writeln!(out, "  {:<38} {:<6} {}", usage, short, description)?;
+                }
+                writeln!(out, "Addresses and values: x3000, #-1, b101, 12, LABEL or LABEL+2. A blank line repeats step, next, finish, continue and their reverse forms.")?;
+            }
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Say why execution stopped, then where.
+    fn report(&self, stop: Stop, out: &mut dyn Write) -> io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Paused => writeln!(out, "Paused")?,
+            Stop::HistoryStart => writeln!(out, "Start of the recorded history")?,
+            Stop::Breakpoint(pc) => writeln!(out, "Breakpoint at {}", self.describe(pc))?,
+            Stop::Watchpoint { pc, access: MemoryAccess::Read { address, value } } => {
+                writeln!(out, "Watchpoint: x{:04X} read x{:04X} from {}", pc, value, self.describe(address))?
+            }
+            Stop::Watchpoint { pc, access: MemoryAccess::Write { address, old, value } } => writeln!(
+                out,
+                "Watchpoint: x{:04X} wrote x{:04X} to {} (was x{:04X})",
+                pc,
+                value,
+                self.describe(address),
+                old
+            )?,
+            Stop::Exited(ExitReason::Halted) => return writeln!(out, "Program halted"),
+            Stop::Exited(reason) => writeln!(out, "Program stopped: {}", reason)?,
+        }
+        self.show_location(out)
+    }
+
+    fn print_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        let registers = self.debugger.vm().registers();
+        for row in [0..4, 4..8] {
+            let line: Vec<String> = row
+                .map(|i| format!("R{} x{:04X}", i, registers.data[i]))
+                .collect();
+            writeln!(out, "{}", line.join("  "))?;
+        }
+        let mode = if registers.is_user_mode() { "user" } else { "supervisor" };
+        writeln!(
+            out,
+            "PC x{:04X}  PSR x{:04X} ({})  COND {}",
+            registers.read(RegisterEnum::PC),
+            registers.psr(),
+            mode,
+            condition(registers.read(RegisterEnum::COND))
+        )
+    }
+
+    fn print(&self, place: Place, out: &mut dyn Write) -> io::Result<()> {
+        let vm = self.debugger.vm();
+        match place {
+            Place::Register(RegisterEnum::COND) => {
+                let cond = vm.registers().read(RegisterEnum::COND);
+                writeln!(out, "COND = x{:04X} ({})", cond, condition(cond))
+            }
+            Place::Register(register) => {
+                let value = vm.registers().read(register);
+                writeln!(out, "{:?} = x{:04X} (#{})", register, value, value as i16)
+            }
+            Place::Psr => writeln!(out, "PSR = x{:04X}", vm.registers().psr()),
+            Place::Memory(address) => {
+                let value = vm.memory().peek(address as usize);
+                writeln!(out, "{} = x{:04X} (#{})", self.describe(address), value, value as i16)
+            }
+        }
+    }
+
+    /// Disassemble `count` words from `address`, with label lines like `demoVM disasm`.
+    fn examine(&self, address: u16, count: u16, out: &mut dyn Write) -> io::Result<()> {
+        let vm = self.debugger.vm();
+        for i in 0..count {
+            let address = address.wrapping_add(i);
+            if let Some(label) = vm.symbols().name_at(address) {
+                writeln!(out, "{}:", label)?;
+            }
+            let word = vm.memory().peek(address as usize);
+            let instruction = disassemble(word, address);
+            writeln!(out, "x{:04X}  {:04X}  {}", address, word, instruction.with_symbols(vm.symbols()))?;
+        }
+        Ok(())
+    }
+
+    fn info(&self, out: &mut dyn Write) -> io::Result<()> {
+        let breakpoints = self.debugger.vm().breakpoints();
+        let watchpoints: Vec<_> = self.debugger.watchpoints().collect();
+        if breakpoints.is_empty() && watchpoints.is_empty() {
+            writeln!(out, "No breakpoints or watchpoints")?;
+        }
+        for address in breakpoints {
+            writeln!(out, "Breakpoint {}", self.describe(address))?;
+        }
+        for (address, kind) in watchpoints {
+            writeln!(out, "Watchpoint {} ({})", self.describe(address), kind)?;
+        }
+        if let Some(history) = self.debugger.history() {
+            writeln!(out, "History: {} of the last {} instructions recorded", history.len(), history.limit())?;
+        }
+        Ok(())
+    }
+
+    /// `x3004 (LOOP+1)`, or just `x3004` with no label nearby.
+    fn describe(&self, address: u16) -> String {
+        describe(self.debugger.vm().symbols(), address)
+    }
+}
+
+pub(super) fn describe(symbols: &SymbolTable, address: u16) -> String {
+    match symbols.nearest(address) {
+        Some(_) => format!("x{:04X} ({})", address, symbols.describe(address)),
+        None => format!("x{:04X}", address),
+    }
+}
+
+/// `ZRO`, `NEG | POS`, or `none` for the condition codes in `cond`.
+pub(super) fn condition(cond: u16) -> String {
+    match ConditionFlags::from_bits_truncate(cond).to_debug_string() {
+        flags if flags.is_empty() => "none".to_string(),
+        flags => flags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::LC3;
+
+    /// Run `lines` through a fresh REPL on `source` and return everything printed.
+    fn session(source: &str, lines: &[&str]) -> String {
+        let assembly = assemble(source).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+        for (name, address) in &assembly.symbols {
+            vm.symbols_mut().insert(name, *address);
+        }
+        let mut repl = Repl::new(Debugger::new(vm));
+        let mut out = Vec::new();
+        repl.show_location(&mut out).unwrap();
+        for line in lines {
+            writeln!(out, "{}", format!("(lc3) {}", line).trim_end()).unwrap();
+            if !repl.execute(line, &mut out).unwrap() {
+                break;
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+LOOP    ADD R0, R0, #-1
+        ST  R0, VALUE
+        BRnp LOOP
+        HALT
+VALUE   .FILL #2
+        .END
+    ";
+
+    #[test]
+    fn test_session() {
+        let output = session(
+            PROGRAM,
+            &["break LOOP", "c", "p cond", "set R0 #1", "watch VALUE", "c", "", "info", "x VALUE", "q", "s"],
+        );
+        let expected = "\
+x3000  5020  AND R0, R0, #0
+(lc3) break LOOP
+Breakpoint at x3001 (LOOP)
+(lc3) c
+Breakpoint at x3001 (LOOP)
+LOOP:
+x3001  103F  ADD R0, R0, #-1
+(lc3) p cond
+COND = x0002 (ZRO)
+(lc3) set R0 #1
+R0 = x0001 (#1)
+(lc3) watch VALUE
+Watching x3005 (VALUE) (write)
+(lc3) c
+Watchpoint: x3002 wrote x0000 to x3005 (VALUE) (was x0002)
+x3003  0BFD  BRnp LOOP
+(lc3)
+Program halted
+(lc3) info
+Breakpoint x3001 (LOOP)
+Watchpoint x3005 (VALUE) (write)
+(lc3) x VALUE
+VALUE:
+x3005  0000  NOP
+(lc3) q
+";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_registers_and_errors() {
+        let output = session(PROGRAM, &["s 2", "p", "b NOWHERE", "frobnicate", "step now"]);
+        assert!(output.contains("R0 xFFFF  R1 x0000  R2 x0000  R3 x0000\n"));
+        assert!(output.contains("PC x3002  PSR x0004 (supervisor)  COND NEG\n"));
+        assert!(output.contains("no label or address 'NOWHERE'\n"));
+        assert!(output.contains("unknown command 'frobnicate', try 'help'\n"));
+        assert!(output.contains("bad value 'now'\n"));
+    }
+
+    #[test]
+    fn test_reverse_commands() {
+        let output = session(PROGRAM, &["rs", "record on", "s 3", "lw VALUE", "rs 2", "", "p R0", "lw VALUE", "info"]);
+        assert!(output.contains("(lc3) rs\nNot recording, try 'record on'\n"));
+        assert!(output.contains("x3005 (VALUE) was set to xFFFF (was x0002) by x3002 (LOOP+1), 1 instruction(s) ago\n"));
+        // The blank line repeats `rs 2`, which runs out after one instruction
+        assert!(output.contains("(lc3)\nStart of the recorded history\nx3000  5020  AND R0, R0, #0\n"));
+        assert!(output.contains("(lc3) p R0\nR0 = x0000 (#0)\n"));
+        assert!(output.contains("No recorded store to x3005 (VALUE)\n"));
+        assert!(output.ends_with("No breakpoints or watchpoints\nHistory: 0 of the last 1000000 instructions recorded\n"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("demovm-repl-{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let save = format!("save {}", path);
+        let load = format!("load {}", path);
+        let output = session(PROGRAM, &["s 3", &save, "c", &load, "p R0", "x VALUE", "load nowhere.snap"]);
+        std::fs::remove_file(path).unwrap();
+        assert!(output.contains(&format!("Saved the machine to {}\n(lc3) c\nProgram halted\n", path)));
+        assert!(output.contains(&format!("Restored the machine from {}\nx3003  0BFD  BRnp LOOP\n", path)));
+        assert!(output.contains("(lc3) p R0\nR0 = xFFFF (#-1)\n"));
+        assert!(output.contains("x3005  FFFF"));
+        assert!(output.contains("Could not load nowhere.snap: "));
+    }
+}
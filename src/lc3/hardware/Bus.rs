@@ -0,0 +1,153 @@
+use std::ops::RangeInclusive;
+
+use super::Device::Device;
+use crate::lc3::cpu::interrupt::InterruptRequest;
+
+/// A device and the addresses it answers for.
+struct Mapping {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Device>,
+}
+
+/// Routes addresses to memory-mapped devices. Addresses no device claims belong to RAM.
+pub struct Bus {
+    mappings: Vec<Mapping>,
+    /// Lowest address any device claims, so ordinary RAM accesses skip the lookup.
+    lowest: u16,
+}
+
+impl Bus {
+    /// Creates a bus with no devices attached.
+    pub fn new() -> Self {
+        Bus { mappings: Vec::new(), lowest: u16::MAX }
+    }
+
+    /// Attach `device` to `range`. Existing devices overlapping the range are detached.
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+        self.mappings
+            .retain(|m| m.range.end() < range.start() || m.range.start() > range.end());
+        self.mappings.push(Mapping { range, device });
+        self.update_lowest();
+    }
+
+    /// Detach whatever device claims `address`. Returns `true` if one did.
+    pub fn unmap(&mut self, address: u16) -> bool {
+        let before = self.mappings.len();
+        self.mappings.retain(|m| !m.range.contains(&address));
+        self.update_lowest();
+        self.mappings.len() != before
+    }
+
+    /// Lowest address any device claims (`u16::MAX` with none attached).
+    pub fn lowest(&self) -> u16 {
+        self.lowest
+    }
+
+    /// The device mapped at `address`, if any.
+    #[inline]
+    pub fn device_at(&mut self, address: u16) -> Option<&mut dyn Device> {
+        if address < self.lowest {
+            return None;
+        }
+        self.mappings
+            .iter_mut()
+            .find(|m| m.range.contains(&address))
+            .map(|m| m.device.as_mut() as &mut dyn Device)
+    }
+
+    /// Shared-reference counterpart of `device_at`.
+    pub fn device_ref(&self, address: u16) -> Option<&dyn Device> {
+        if address < self.lowest {
+            return None;
+        }
+        self.mappings
+            .iter()
+            .find(|m| m.range.contains(&address))
+            .map(|m| m.device.as_ref())
+    }
+
+    fn update_lowest(&mut self) {
+        self.lowest = self
+            .mappings
+            .iter()
+            .map(|m| *m.range.start())
+            .min()
+            .unwrap_or(u16::MAX);
+    }
+
+    /// The highest-priority interrupt any device is asserting.
+    pub fn interrupt_request(&self) -> Option<InterruptRequest> {
+        self.mappings
+            .iter()
+            .filter_map(|m| m.device.interrupt_request())
+            .max_by_key(|request| request.priority)
+    }
+
+    /// Whether any device may start asserting an interrupt.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.mappings.iter().any(|m| m.device.interrupts_enabled())
+    }
+
+    /// Every device's `save` state, keyed by the first address it answers for.
+    pub fn save(&self) -> Vec<(u16, Vec<u16>)> {
+        self.mappings.iter().map(|m| (*m.range.start(), m.device.save())).collect()
+    }
+
+    /// Hand each device the state saved under its first address; devices with none
+    /// saved are left alone.
+    pub fn load(&mut self, states: &[(u16, Vec<u16>)]) {
+        for mapping in &mut self.mappings {
+            if let Some((_, state)) = states.iter().find(|(address, _)| address == mapping.range.start()) {
+                mapping.device.load(state);
+            }
+        }
+    }
+
+    /// Advance every attached device by one instruction.
+    pub fn tick(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.device.tick();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Register(u16);
+
+    impl Device for Register {
+        fn read(&mut self, _address: u16) -> u16 {
+            self.0
+        }
+        fn write(&mut self, _address: u16, value: u16) {
+            self.0 = value;
+        }
+        fn peek(&self, _address: u16) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_dispatch_by_range() {
+        let mut bus = Bus::new();
+        bus.map(0x4000..=0x4001, Box::new(Register(7)));
+
+        assert!(bus.device_at(0x3FFF).is_none());
+        assert_eq!(bus.device_at(0x4001).unwrap().read(0x4001), 7);
+        assert!(bus.device_at(0x4002).is_none());
+    }
+
+    #[test]
+    fn test_map_replaces_overlapping_device() {
+        let mut bus = Bus::new();
+        bus.map(0x4000..=0x4003, Box::new(Register(1)));
+        bus.map(0x4002..=0x4002, Box::new(Register(2)));
+
+        assert!(bus.device_at(0x4000).is_none());
+        assert_eq!(bus.device_ref(0x4002).unwrap().peek(0x4002), 2);
+        assert!(bus.unmap(0x4002));
+        assert!(bus.device_at(0x4002).is_none());
+    }
+}
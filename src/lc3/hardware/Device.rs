@@ -0,0 +1,308 @@
+use super::Memory::{MemoryMappedReg, DSR_READY, KBSR_READY, MCR_CLOCK_ENABLE};
+use crate::lc3::cpu::interrupt::{InterruptRequest, KEYBOARD_PRIORITY, KEYBOARD_VECTOR};
+use crate::lc3::sys::console::SharedConsole;
+
+/// KBSR bit 14: the keyboard may raise an interrupt when a key arrives.
+pub const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+
+/// A memory-mapped peripheral.
+///
+/// A device is registered on the `Bus` for a range of addresses and receives every
+/// load and store into that range instead of RAM. Addresses are passed through
+/// unchanged, so a device spanning several registers can tell them apart.
+pub trait Device {
+    /// Handle a load from `address`. May have side effects (e.g. consuming input).
+    fn read(&mut self, address: u16) -> u16;
+
+    /// Handle a store of `value` to `address`.
+    fn write(&mut self, address: u16, value: u16);
+
+    /// Return what `read` would, without side effects. Used by debuggers and tracing.
+    fn peek(&self, address: u16) -> u16;
+
+    /// Called once after every executed instruction.
+    fn tick(&mut self) {}
+
+    /// The interrupt this device is asserting right now, if any.
+    fn interrupt_request(&self) -> Option<InterruptRequest> {
+        None
+    }
+
+    /// Whether the device may start asserting an interrupt on a later tick. Devices that
+    /// can interrupt must say so, or the JIT may take their interrupts a block late.
+    fn interrupts_enabled(&self) -> bool {
+        false
+    }
+
+    /// Internal state for a machine snapshot, as words. Devices without any keep the
+    /// default.
+    fn save(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Take back state `save` returned. Missing words count as zero.
+    fn load(&mut self, _state: &[u16]) {}
+}
+
+/// Word `i` of saved device state, or 0 if the state is shorter.
+fn word(state: &[u16], i: usize) -> u16 {
+    state.get(i).copied().unwrap_or(0)
+}
+
+/// With interrupts enabled, the keyboard checks for input every this many instructions.
+pub const KEYBOARD_POLL_INTERVAL: u32 = 64;
+
+/// The keyboard behind KBSR (0xFE00) and KBDR (0xFE02), fed from a console.
+pub struct Keyboard {
+    console: SharedConsole,
+    status: u16,
+    data: u16,
+    /// Instructions since the last background poll.
+    ticks: u32,
+}
+
+impl Keyboard {
+    pub fn new(console: SharedConsole) -> Self {
+        Keyboard { console, status: 0, data: 0, ticks: 0 }
+    }
+
+    /// Latch `key` into KBDR and raise the ready bit, as if it had just been typed.
+    pub fn press(&mut self, key: u8) {
+        self.data = key as u16;
+        self.status |= KBSR_READY;
+    }
+
+    /// Latch the next pending key, unless one is already waiting to be read.
+    fn poll(&mut self) {
+        if self.status & KBSR_READY != 0 {
+            return;
+        }
+        // A console error looks like no key to the program
+        let key = self.console.borrow_mut().poll_byte();
+        if let Ok(Some(key)) = key {
+            self.press(key);
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, address: u16) -> u16 {
+        match address {
+            a if a == MemoryMappedReg::Kbsr as u16 => {
+                self.poll();
+                self.status
+            }
+            a if a == MemoryMappedReg::Kbdr as u16 => {
+                // Reading the data register consumes the latched key
+                self.status &= !KBSR_READY;
+                self.data
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        // Only the interrupt-enable bit of KBSR is writable; KBDR is read-only
+        if address == MemoryMappedReg::Kbsr as u16 {
+            self.status = (self.status & !KBSR_INTERRUPT_ENABLE) | (value & KBSR_INTERRUPT_ENABLE);
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            a if a == MemoryMappedReg::Kbsr as u16 => self.status,
+            a if a == MemoryMappedReg::Kbdr as u16 => self.data,
+            _ => 0,
+        }
+    }
+
+    /// Interrupt-driven programs never read KBSR, so look for keys in the background.
+    fn tick(&mut self) {
+        if self.status & KBSR_INTERRUPT_ENABLE == 0 {
+            return;
+        }
+        self.ticks += 1;
+        if self.ticks >= KEYBOARD_POLL_INTERVAL {
+            self.ticks = 0;
+            self.poll();
+        }
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.status, self.data, self.ticks as u16]
+    }
+
+    fn load(&mut self, state: &[u16]) {
+        self.status = word(state, 0);
+        self.data = word(state, 1);
+        self.ticks = word(state, 2) as u32;
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.status & KBSR_INTERRUPT_ENABLE != 0
+    }
+
+    fn interrupt_request(&self) -> Option<InterruptRequest> {
+        let asserted = KBSR_READY | KBSR_INTERRUPT_ENABLE;
+        (self.status & asserted == asserted).then_some(InterruptRequest {
+            vector: KEYBOARD_VECTOR,
+            priority: KEYBOARD_PRIORITY,
+        })
+    }
+}
+
+/// The display behind DSR (0xFE04) and DDR (0xFE06), writing to a console.
+pub struct Display {
+    console: SharedConsole,
+    data: u16,
+}
+
+impl Display {
+    pub fn new(console: SharedConsole) -> Self {
+        Display { console, data: 0 }
+    }
+}
+
+impl Device for Display {
+    fn read(&mut self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        if address == MemoryMappedReg::Ddr as u16 {
+            self.data = value;
+            let mut console = self.console.borrow_mut();
+            // A store has no way to report failure; a broken console is noticed by the next trap
+            let _ = console.write_bytes(&[value as u8]);
+            let _ = console.flush();
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            // Output is written synchronously, so the display is always ready
+            a if a == MemoryMappedReg::Dsr as u16 => DSR_READY,
+            a if a == MemoryMappedReg::Ddr as u16 => self.data,
+            _ => 0,
+        }
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.data]
+    }
+
+    fn load(&mut self, state: &[u16]) {
+        self.data = word(state, 0);
+    }
+}
+
+/// The Machine Control Register (0xFFFE). Clearing bit 15 stops the clock.
+pub struct MachineControl {
+    value: u16,
+}
+
+impl MachineControl {
+    pub fn new() -> Self {
+        // The machine powers up with its clock running
+        MachineControl { value: MCR_CLOCK_ENABLE }
+    }
+}
+
+impl Device for MachineControl {
+    fn read(&mut self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, _address: u16, value: u16) {
+        self.value = value;
+    }
+
+    fn peek(&self, _address: u16) -> u16 {
+        self.value
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.value]
+    }
+
+    fn load(&mut self, state: &[u16]) {
+        self.value = word(state, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const KBSR: u16 = MemoryMappedReg::Kbsr as u16;
+    const KBDR: u16 = MemoryMappedReg::Kbdr as u16;
+
+    #[test]
+    fn test_kbdr_read_clears_ready_bit() {
+        let mut keyboard = Keyboard::new(console::shared(BufferConsole::default()));
+        keyboard.press(b'a');
+
+        assert_eq!(keyboard.read(KBSR), KBSR_READY);
+        assert_eq!(keyboard.read(KBDR), 'a' as u16);
+        assert_eq!(keyboard.peek(KBSR) & KBSR_READY, 0);
+    }
+
+    #[test]
+    fn test_kbsr_only_interrupt_enable_is_writable() {
+        let mut keyboard = Keyboard::new(console::shared(BufferConsole::default()));
+        keyboard.write(KBSR, 0xFFFF);
+        assert_eq!(keyboard.peek(KBSR), KBSR_INTERRUPT_ENABLE);
+        keyboard.write(KBDR, 0x1234);
+        assert_eq!(keyboard.peek(KBDR), 0);
+    }
+
+    #[test]
+    fn test_keyboard_latches_console_input() {
+        let mut keyboard = Keyboard::new(console::shared(BufferConsole::new(b"q")));
+        assert_eq!(keyboard.read(KBSR), KBSR_READY);
+        // The latched key is kept until KBDR is read
+        assert_eq!(keyboard.read(KBSR), KBSR_READY);
+        assert_eq!(keyboard.read(KBDR), 'q' as u16);
+        assert_eq!(keyboard.read(KBSR), 0);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt_needs_enable_and_key() {
+        let mut keyboard = Keyboard::new(console::shared(BufferConsole::new(b"k")));
+        for _ in 0..KEYBOARD_POLL_INTERVAL {
+            keyboard.tick();
+        }
+        // Interrupts disabled: no background polling, no request
+        assert_eq!(keyboard.peek(KBSR), 0);
+        assert_eq!(keyboard.interrupt_request(), None);
+
+        keyboard.write(KBSR, KBSR_INTERRUPT_ENABLE);
+        for _ in 0..KEYBOARD_POLL_INTERVAL {
+            keyboard.tick();
+        }
+        assert_eq!(
+            keyboard.interrupt_request(),
+            Some(InterruptRequest { vector: KEYBOARD_VECTOR, priority: KEYBOARD_PRIORITY })
+        );
+        keyboard.read(KBDR);
+        assert_eq!(keyboard.interrupt_request(), None);
+    }
+
+    #[test]
+    fn test_dsr_reports_ready() {
+        let mut display = Display::new(console::shared(BufferConsole::default()));
+        assert_eq!(display.read(MemoryMappedReg::Dsr as u16) & DSR_READY, DSR_READY);
+    }
+
+    #[test]
+    fn test_ddr_writes_to_console() {
+        let buffer = Rc::new(RefCell::new(BufferConsole::default()));
+        let mut display = Display::new(buffer.clone());
+        display.write(MemoryMappedReg::Ddr as u16, 'h' as u16);
+        display.write(MemoryMappedReg::Ddr as u16, 'i' as u16);
+        assert_eq!(buffer.borrow().output_string(), "hi");
+    }
+}
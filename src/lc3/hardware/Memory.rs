@@ -1,13 +1,56 @@
 use std::fs::File;
 use std::io::Read;
+use std::ops::RangeInclusive;
 use std::path::Path;
 
+use super::Bus::Bus;
+use crate::lc3::cpu::decode::{decode, DecodeError, Instruction};
+use crate::lc3::cpu::interrupt::InterruptRequest;
+use super::Device::{Device, Display, Keyboard, MachineControl};
+use crate::lc3::sys::console::{self, SharedConsole, StdConsole};
 use crate::lc3::sys::file;
 pub const MEMORY_SIZE: usize = 1 << 16;
 
-#[derive(Copy)]
+/// The LC-3 address space: 64K words of RAM with memory-mapped devices on top.
 pub struct Memory {
     data: [u16; MEMORY_SIZE],
+    /// Devices that claim address ranges ahead of RAM.
+    bus: Bus,
+    /// Accesses recorded while logging is switched on, see `start_access_log`.
+    access_log: Option<Vec<MemoryAccess>>,
+    /// Set while a user-mode instruction executes; protected addresses are then refused.
+    user_mode: bool,
+    /// First protected address a user-mode access was refused for, see `take_violation`.
+    violation: Option<u16>,
+    /// Whether `fetch_decoded` keeps what it decodes, see `set_predecode`.
+    predecode: bool,
+    /// Decoded RAM words by address, allocated on first use. A write to an address
+    /// drops its entry, so self-modifying code is decoded afresh.
+    decoded: Vec<Option<Fetched>>,
+    /// One byte per address, nonzero where decoded code lives (in `decoded` or compiled by
+    /// the JIT). Allocated by `watch_code`.
+    #[cfg(feature = "jit")]
+    code_map: Vec<u8>,
+    /// Addresses in `code_map` written since the last `take_code_writes`.
+    #[cfg(feature = "jit")]
+    code_writes: Vec<u16>,
+    /// Set when a device was mapped or unmapped since the last `take_remapped`.
+    #[cfg(feature = "jit")]
+    remapped: bool,
+}
+
+/// An instruction word and what it decodes to, see `Memory::fetch_decoded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fetched {
+    pub word: u16,
+    pub instruction: Result<Instruction, DecodeError>,
+}
+
+/// A single memory access made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read { address: u16, value: u16 },
+    Write { address: u16, old: u16, value: u16 },
 }
 
 pub enum MemoryMappedReg {
@@ -15,30 +58,269 @@ pub enum MemoryMappedReg {
     Kbsr = 0xFE00, /* keyboard status */
     /// keyboard data: The KBDR identifies which key was pressed
     Kbdr = 0xFE02, /* keyboard data */
+    /// display status: The DSR indicates whether the display can accept a character
+    Dsr = 0xFE04, /* display status */
+    /// display data: A character stored to the DDR is written to the display
+    Ddr = 0xFE06, /* display data */
+    /// machine control: Clearing bit 15 of the MCR stops the clock
+    Mcr = 0xFFFE, /* machine control */
+}
+
+/// KBSR bit 15: a key is waiting in KBDR.
+pub const KBSR_READY: u16 = 1 << 15;
+/// DSR bit 15: the display is ready for the next character.
+pub const DSR_READY: u16 = 1 << 15;
+/// MCR bit 15: the clock is running.
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+
+/// First address user mode may access; everything below is system space.
+pub const USER_SPACE_START: u16 = 0x3000;
+/// Last address user mode may access; everything above is the device register page.
+pub const USER_SPACE_END: u16 = 0xFDFF;
+
+/// `true` for system space and the device registers, which only supervisor mode may access.
+pub fn is_protected(address: u16) -> bool {
+    !(USER_SPACE_START..=USER_SPACE_END).contains(&address)
 }
 
 impl Memory {
+    /// Creates zeroed RAM with the built-in keyboard, display and MCR attached,
+    /// the first two talking to stdin/stdout.
     pub fn new() -> Self {
-        Memory { data: [0; 65536] }
+        Self::with_console(console::shared(StdConsole))
+    }
+
+    /// Like `new`, with the keyboard and display attached to `console`.
+    pub fn with_console(console: SharedConsole) -> Self {
+        let mut bus = Bus::new();
+        bus.map(
+            MemoryMappedReg::Kbsr as u16..=MemoryMappedReg::Kbdr as u16,
+            Box::new(Keyboard::new(console.clone())),
+        );
+        bus.map(
+            MemoryMappedReg::Dsr as u16..=MemoryMappedReg::Ddr as u16,
+            Box::new(Display::new(console)),
+        );
+        bus.map(
+            MemoryMappedReg::Mcr as u16..=MemoryMappedReg::Mcr as u16,
+            Box::new(MachineControl::new()),
+        );
+        Memory {
+            data: [0; 65536],
+            bus,
+            access_log: None,
+            user_mode: false,
+            violation: None,
+            predecode: true,
+            decoded: Vec::new(),
+            #[cfg(feature = "jit")]
+            code_map: Vec::new(),
+            #[cfg(feature = "jit")]
+            code_writes: Vec::new(),
+            #[cfg(feature = "jit")]
+            remapped: false,
+        }
+    }
+
+    /// Attach a device to `range`, replacing any device already mapped there.
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+        self.bus.map(range, device);
+        self.forget_code();
+    }
+
+    /// Detach the device at `address`, handing the address back to RAM.
+    pub fn unmap_device(&mut self, address: u16) -> bool {
+        self.forget_code();
+        self.bus.unmap(address)
+    }
+
+    /// Drop everything decoded, after the device layout changed.
+    fn forget_code(&mut self) {
+        self.decoded.clear();
+        #[cfg(feature = "jit")]
+        {
+            self.code_map.fill(0);
+            self.remapped = true;
+        }
+    }
+
+    /// Turn the predecode cache used by `fetch_decoded` on or off (it is on by default).
+    pub fn set_predecode(&mut self, enabled: bool) {
+        self.predecode = enabled;
+        if !enabled {
+            self.decoded = Vec::new();
+        }
+    }
+
+    /// Advance every device by one instruction.
+    pub fn tick(&mut self) {
+        self.bus.tick();
+    }
+
+    /// The highest-priority interrupt a device is asserting.
+    pub fn interrupt_request(&self) -> Option<InterruptRequest> {
+        self.bus.interrupt_request()
+    }
+
+    /// Whether any device may start asserting an interrupt.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.bus.interrupts_enabled()
+    }
+
+    /// Start recording every read and write until `take_access_log` is called.
+    pub fn start_access_log(&mut self) {
+        self.access_log = Some(Vec::new());
+    }
+
+    /// Stop recording and return the accesses made since `start_access_log`.
+    pub fn take_access_log(&mut self) -> Vec<MemoryAccess> {
+        self.access_log.take().unwrap_or_default()
+    }
+
+    /// Enforce user-mode access control on the reads and writes that follow.
+    ///
+    /// A refused read returns 0 and a refused write is dropped; either way the address is
+    /// kept for `take_violation`, so the processor can undo the instruction and raise an
+    /// access control violation.
+    pub fn set_user_mode(&mut self, user_mode: bool) {
+        self.user_mode = user_mode;
+    }
+
+    /// The first address refused since the last call, if any.
+    pub fn take_violation(&mut self) -> Option<u16> {
+        self.violation.take()
+    }
+
+    /// Returns `true`, recording the violation, if the current mode may not access `address`.
+    #[inline]
+    fn refuse(&mut self, address: u16) -> bool {
+        if self.user_mode && is_protected(address) {
+            self.violation.get_or_insert(address);
+            return true;
+        }
+        false
+    }
+
+    /// Instruction fetch: the word at `address`, or `None` if `user_mode` may not execute
+    /// from there. Fetches are not recorded in the access log.
+    pub fn fetch(&mut self, address: usize, user_mode: bool) -> Option<u16> {
+        let effective_address = address & 0xFFFF;
+        if user_mode && is_protected(effective_address as u16) {
+            return None;
+        }
+        Some(match self.bus.device_at(effective_address as u16) {
+            Some(device) => device.read(effective_address as u16),
+            None => self.data[effective_address],
+        })
+    }
+
+    /// `fetch`, decoded. RAM words are decoded once and served from the predecode cache
+    /// until they are written to; device registers are decoded on every fetch.
+    pub fn fetch_decoded(&mut self, address: usize, user_mode: bool) -> Option<Fetched> {
+        let effective_address = address & 0xFFFF;
+        if user_mode && is_protected(effective_address as u16) {
+            return None;
+        }
+        // Only RAM words are cached, and the cache is cleared when devices are remapped
+        if let Some(Some(fetched)) = self.decoded.get(effective_address) {
+            return Some(*fetched);
+        }
+        let word = self.fetch(effective_address, false)?;
+        let fetched = Fetched { word, instruction: decode(word) };
+        if self.predecode && self.bus.device_ref(effective_address as u16).is_none() {
+            if self.decoded.is_empty() {
+                self.decoded = vec![None; MEMORY_SIZE];
+            }
+            self.decoded[effective_address] = Some(fetched);
+            #[cfg(feature = "jit")]
+            if let Some(mark) = self.code_map.get_mut(effective_address) {
+                *mark = 1;
+            }
+        }
+        Some(fetched)
+    }
+
+    /// Start keeping the code map the JIT reads, marking what is already decoded.
+    #[cfg(feature = "jit")]
+    pub(crate) fn watch_code(&mut self) {
+        if self.code_map.is_empty() {
+            self.code_map = vec![0; MEMORY_SIZE];
+            for (address, entry) in self.decoded.iter().enumerate() {
+                if entry.is_some() {
+                    self.code_map[address] = 1;
+                }
+            }
+        }
+    }
+
+    /// Mark `len` words from `start` as compiled code.
+    #[cfg(feature = "jit")]
+    pub(crate) fn mark_code(&mut self, start: u16, len: u16) {
+        for i in 0..len {
+            self.code_map[start.wrapping_add(i) as usize] = 1;
+        }
+    }
+
+    /// Addresses of decoded code written since the last call.
+    #[cfg(feature = "jit")]
+    pub(crate) fn take_code_writes(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.code_writes)
+    }
+
+    /// `true` if a device was mapped or unmapped since the last call.
+    #[cfg(feature = "jit")]
+    pub(crate) fn take_remapped(&mut self) -> bool {
+        std::mem::take(&mut self.remapped)
+    }
+
+    /// Lowest address a device answers for; everything below is RAM.
+    #[cfg(feature = "jit")]
+    pub(crate) fn device_floor(&self) -> u16 {
+        self.bus.lowest()
+    }
+
+    /// `true` if no device claims `address`.
+    #[cfg(feature = "jit")]
+    pub(crate) fn is_ram(&self, address: u16) -> bool {
+        self.bus.device_ref(address).is_none()
+    }
+
+    /// RAM and the code map, for compiled code to work on directly. Stores made through
+    /// the RAM pointer bypass `write`, so they must never hit an address in the code map.
+    #[cfg(feature = "jit")]
+    pub(crate) fn raw_parts(&mut self) -> (*mut u16, *const u8) {
+        (self.data.as_mut_ptr(), self.code_map.as_ptr())
+    }
+
+    /// Read a word without triggering device side effects or logging the access.
+    pub fn peek(&self, address: usize) -> u16 {
+        let effective_address = address & 0xFFFF;
+        match self.bus.device_ref(effective_address as u16) {
+            Some(device) => device.peek(effective_address as u16),
+            None => self.data[effective_address],
+        }
+    }
+
+    /// `false` once a program has cleared bit 15 of the MCR.
+    pub fn clock_enabled(&self) -> bool {
+        self.peek(MemoryMappedReg::Mcr as usize) & MCR_CLOCK_ENABLE != 0
     }
 
     pub fn read(&mut self, address: usize) -> u16 {
         let effective_address = address & 0xFFFF; // Wrap within 16-bit range
 
-        // match effective_address {
-        //     kbsr => {
-        //         if check_key() {
-        //             self.data[MemoryMappedReg::Kbsr as usize] = 1 << 15; // Set the high bit to indicate key press
-        //             self.data[MemoryMappedReg::Kbdr as usize] = 1;
-        //             // getchar::get_char() as u16; // Update keyboard data register
-        //         } else {
-        //             self.data[kbsr] = 0; // Clear the high bit if no key press
-        //         }
-        //     }
-        //     _ => {} // Normal memory access
-        // }
         if effective_address < self.data.len() {
-            self.data[effective_address]
+            if self.refuse(effective_address as u16) {
+                return 0;
+            }
+            let value = match self.bus.device_at(effective_address as u16) {
+                Some(device) => device.read(effective_address as u16),
+                None => self.data[effective_address],
+            };
+            if let Some(log) = &mut self.access_log {
+                log.push(MemoryAccess::Read { address: effective_address as u16, value });
+            }
+            value
         } else {
             panic!("Memory read out of bounds at address: {:#X}", address);
         }
@@ -47,26 +329,75 @@ impl Memory {
     pub fn write(&mut self, address: usize, value: u16) {
         let effective_address = address & 0xFFFF; // Wrap within 16-bit range
         if effective_address < self.data.len() {
-            self.data[effective_address] = value;
+            if self.refuse(effective_address as u16) {
+                return;
+            }
+            if self.access_log.is_some() {
+                let old = self.peek(effective_address);
+                if let Some(log) = &mut self.access_log {
+                    log.push(MemoryAccess::Write { address: effective_address as u16, old, value });
+                }
+            }
+            self.store(effective_address as u16, value);
         } else {
             panic!("Memory write out of bounds at address: {:#X}", address);
         }
     }
-}
 
-impl Clone for Memory {
-    fn clone(&self) -> Memory {
-        *self
+    /// RAM as a whole, including any words hidden under device registers.
+    pub fn ram(&self) -> &[u16] {
+        &self.data
     }
-}
 
-fn check_key()-> bool{
-   false
-}
+    /// Replace RAM as a whole, as restoring a snapshot does. Words that change lose
+    /// their decoded instructions. Panics unless `words` covers the address space.
+    pub fn load_ram(&mut self, words: &[u16]) {
+        assert_eq!(words.len(), MEMORY_SIZE, "RAM image must cover the address space");
+        for (address, &value) in words.iter().enumerate() {
+            if self.data[address] != value {
+                self.store_ram(address, value);
+            }
+        }
+    }
+
+    /// Device state for a snapshot, see `Bus::save`.
+    pub fn save_devices(&self) -> Vec<(u16, Vec<u16>)> {
+        self.bus.save()
+    }
+
+    pub fn load_devices(&mut self, states: &[(u16, Vec<u16>)]) {
+        self.bus.load(states);
+    }
+
+    /// Put back a word an earlier write replaced, for a debugger stepping backward.
+    /// Bypasses access control and the access log; device registers are written
+    /// through their `write`.
+    pub fn restore(&mut self, address: u16, value: u16) {
+        self.store(address, value);
+    }
 
-fn getchar() -> char {
-    '\0' 
+    fn store(&mut self, address: u16, value: u16) {
+        match self.bus.device_at(address) {
+            Some(device) => device.write(address, value),
+            None => self.store_ram(address as usize, value),
+        }
+    }
+
+    fn store_ram(&mut self, address: usize, value: u16) {
+        self.data[address] = value;
+        if let Some(entry) = self.decoded.get_mut(address) {
+            *entry = None;
+        }
+        #[cfg(feature = "jit")]
+        if let Some(mark) = self.code_map.get_mut(address) {
+            if *mark != 0 {
+                *mark = 0;
+                self.code_writes.push(address as u16);
+            }
+        }
+    }
 }
+
 #[cfg(test)]
 mod memory_test {
     use super::*;
@@ -96,6 +427,119 @@ mod memory_test {
         memory.write(0x0000, 42);
         assert_eq!(memory.read(0x1_0000), 42); 
     }
+
+    /// Counts the stores it receives.
+    struct StoreCounter(u16);
+
+    impl Device for StoreCounter {
+        fn read(&mut self, _address: u16) -> u16 {
+            self.0
+        }
+        fn write(&mut self, _address: u16, _value: u16) {
+            self.0 += 1;
+        }
+        fn peek(&self, _address: u16) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_custom_device_shadows_ram() {
+        let mut memory = Memory::new();
+        memory.write(0x5000, 42);
+        memory.map_device(0x5000..=0x5000, Box::new(StoreCounter(0)));
+
+        memory.write(0x5000, 1);
+        memory.write(0x5000, 1);
+        assert_eq!(memory.read(0x5000), 2);
+        assert_eq!(memory.read(0x5001), 0);
+
+        assert!(memory.unmap_device(0x5000));
+        assert_eq!(memory.read(0x5000), 42);
+    }
+
+    #[test]
+    fn test_dsr_reports_ready() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.read(MemoryMappedReg::Dsr as usize) & DSR_READY, DSR_READY);
+    }
+
+    #[test]
+    fn test_mcr_controls_clock() {
+        let mut memory = Memory::new();
+        assert!(memory.clock_enabled());
+        memory.write(MemoryMappedReg::Mcr as usize, 0);
+        assert!(!memory.clock_enabled());
+    }
+
+    #[test]
+    fn test_access_log() {
+        let mut memory = Memory::new();
+        memory.write(0x3000, 1);
+        memory.start_access_log();
+        memory.write(0x3000, 2);
+        memory.read(0x3000);
+
+        assert_eq!(
+            memory.take_access_log(),
+            vec![
+                MemoryAccess::Write { address: 0x3000, old: 1, value: 2 },
+                MemoryAccess::Read { address: 0x3000, value: 2 },
+            ]
+        );
+        memory.read(0x3000);
+        assert!(memory.take_access_log().is_empty());
+    }
+    #[test]
+    fn test_user_mode_access_control() {
+        let mut memory = Memory::new();
+        memory.write(0x0200, 7);
+        memory.set_user_mode(true);
+
+        assert_eq!(memory.read(0x0200), 0);
+        memory.write(0x0200, 9);
+        memory.write(MemoryMappedReg::Mcr as usize, 0);
+        memory.write(0x3000, 1);
+        // Only the first refused address is kept
+        assert_eq!(memory.take_violation(), Some(0x0200));
+        assert_eq!(memory.take_violation(), None);
+
+        memory.set_user_mode(false);
+        assert_eq!(memory.read(0x0200), 7);
+        assert!(memory.clock_enabled());
+        assert_eq!(memory.read(0x3000), 1);
+        assert_eq!(memory.fetch(0x0200, true), None);
+        assert_eq!(memory.fetch(0x3000, true), Some(1));
+    }
+
+    #[test]
+    fn test_predecode_cache_follows_writes() {
+        use crate::lc3::cpu::decode::encode;
+        use crate::lc3::hardware::Reg::RegisterEnum;
+
+        let mut memory = Memory::new();
+        let not = Instruction::Not { dr: RegisterEnum::R0, sr: RegisterEnum::R1 };
+        memory.write(0x3000, encode(&not));
+        assert_eq!(memory.fetch_decoded(0x3000, true).unwrap().instruction, Ok(not));
+        assert_eq!(memory.decoded[0x3000].unwrap().instruction, Ok(not));
+
+        // Overwriting the word drops the cached decoding
+        memory.write(0x3000, 0xD000);
+        assert_eq!(memory.decoded[0x3000], None);
+        let fetched = memory.fetch_decoded(0x3000, false).unwrap();
+        assert_eq!(fetched.word, 0xD000);
+        assert_eq!(fetched.instruction, Err(DecodeError::ReservedOpcode(0xD000)));
+
+        // Device registers are never cached, and user mode can't fetch them
+        assert_eq!(memory.fetch_decoded(MemoryMappedReg::Mcr as usize, true), None);
+        memory.fetch_decoded(MemoryMappedReg::Mcr as usize, false).unwrap();
+        assert_eq!(memory.decoded[MemoryMappedReg::Mcr as usize], None);
+
+        memory.set_predecode(false);
+        memory.fetch_decoded(0x3000, false).unwrap();
+        assert!(memory.decoded.is_empty());
+    }
+
     #[test]
     fn test_file_read() {
 
@@ -35,14 +35,84 @@ impl TryFrom<usize> for RegisterEnum {
     }
 }
 
+/// PSR bit 15: set while running in user mode, clear in supervisor mode.
+pub const PSR_USER_MODE: u16 = 1 << 15;
+/// PSR bits 10-8: priority level of the running program.
+pub const PSR_PRIORITY_MASK: u16 = 0x0700;
+/// PSR bits 2-0: the N, Z and P condition codes, kept in COND.
+pub const PSR_COND_MASK: u16 = 0x0007;
+
+#[derive(Clone)]
 pub struct Registers {
     pub data: [u16; 10], // R0-R7, PC (8), COND (9)
+    /// Privilege and priority bits of the PSR; its condition codes live in COND.
+    psr: u16,
+    /// R6 of supervisor mode while running in user mode.
+    pub saved_ssp: u16,
+    /// R6 of user mode while running in supervisor mode.
+    pub saved_usp: u16,
+    /// Bit `i` is set once register `i` has been written, see `take_written`.
+    written: u16,
 }
 
 impl Registers {
     /// Creates a new Registers instance with all registers initialized to 0.
+    /// The PSR starts out in supervisor mode at priority 0.
     pub fn new() -> Self {
-        Registers { data: [0; 10] }
+        Registers { data: [0; 10], psr: 0, saved_ssp: 0, saved_usp: 0, written: 0 }
+    }
+
+    /// The Processor Status Register: privilege (bit 15), priority (bits 10-8) and NZP (bits 2-0).
+    pub fn psr(&self) -> u16 {
+        self.psr | (self.data[RegisterEnum::COND as usize] & PSR_COND_MASK)
+    }
+
+    /// Replace the whole PSR, condition codes included. Does not swap stacks.
+    pub fn set_psr(&mut self, value: u16) {
+        self.psr = value & (PSR_USER_MODE | PSR_PRIORITY_MASK);
+        self.write(RegisterEnum::COND, value & PSR_COND_MASK);
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.psr & PSR_USER_MODE != 0
+    }
+
+    /// Priority level (0-7) of the running program.
+    pub fn priority(&self) -> u8 {
+        ((self.psr & PSR_PRIORITY_MASK) >> 8) as u8
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.psr = (self.psr & !PSR_PRIORITY_MASK) | (((priority as u16) << 8) & PSR_PRIORITY_MASK);
+    }
+
+    /// Switch to supervisor mode, moving R6 over to the supervisor stack if coming from user mode.
+    pub fn enter_supervisor_mode(&mut self) {
+        if self.is_user_mode() {
+            self.saved_usp = self.read(RegisterEnum::R6);
+            let ssp = self.saved_ssp;
+            self.write(RegisterEnum::R6, ssp);
+            self.psr &= !PSR_USER_MODE;
+        }
+    }
+
+    /// Switch to user mode, moving R6 over to the user stack if coming from supervisor mode.
+    pub fn enter_user_mode(&mut self) {
+        if !self.is_user_mode() {
+            self.saved_ssp = self.read(RegisterEnum::R6);
+            let usp = self.saved_usp;
+            self.write(RegisterEnum::R6, usp);
+            self.psr |= PSR_USER_MODE;
+        }
+    }
+
+    /// Returns the registers written since the last call, in index order, and clears the record.
+    pub fn take_written(&mut self) -> Vec<RegisterEnum> {
+        let written = std::mem::take(&mut self.written);
+        (0..self.data.len())
+            .filter(|i| written & (1 << i) != 0)
+            .filter_map(|i| RegisterEnum::try_from(i).ok())
+            .collect()
     }
 
     /// Reads a value from the specified register.
@@ -58,6 +128,7 @@ impl Registers {
         let index = reg as usize;
         if let Some(reg_val) = self.data.get_mut(index) {
             *reg_val = value;
+            self.written |= 1 << index;
         } else {
             panic!("Register write out of bounds: {}", index);
         }
@@ -77,6 +148,7 @@ impl Registers {
         let value = self.data[reg_index] as i16;
         let new_flags = ConditionFlags::update_from_value(value);
         self.data[RegisterEnum::COND as usize] = new_flags.bits() as u16; // Store the flags in the COND register (R9)
+        self.written |= 1 << RegisterEnum::COND as usize;
     }
 }
 
@@ -125,4 +197,50 @@ mod tests {
             ConditionFlags::NEG.bits() as u16
         );
     }
+
+    #[test]
+    fn test_psr_combines_mode_priority_and_cond() {
+        let mut regs = Registers::new();
+        regs.write(RegisterEnum::R0, 0);
+        regs.update_flags(RegisterEnum::R0);
+        regs.set_priority(4);
+        assert_eq!(regs.psr(), 0x0402);
+
+        regs.set_psr(0x8001);
+        assert!(regs.is_user_mode());
+        assert_eq!(regs.priority(), 0);
+        assert_eq!(regs.read(RegisterEnum::COND), ConditionFlags::POS.bits());
+    }
+
+    #[test]
+    fn test_stack_switch() {
+        let mut regs = Registers::new();
+        regs.saved_usp = 0xFE00;
+        regs.write(RegisterEnum::R6, 0x3000);
+
+        regs.enter_user_mode();
+        assert!(regs.is_user_mode());
+        assert_eq!(regs.read(RegisterEnum::R6), 0xFE00);
+        assert_eq!(regs.saved_ssp, 0x3000);
+
+        regs.write(RegisterEnum::R6, 0xFDF0);
+        regs.enter_supervisor_mode();
+        assert!(!regs.is_user_mode());
+        assert_eq!(regs.read(RegisterEnum::R6), 0x3000);
+        assert_eq!(regs.saved_usp, 0xFDF0);
+    }
+
+    #[test]
+    fn test_take_written() {
+        let mut regs = Registers::new();
+        regs.write(RegisterEnum::R3, 7);
+        regs.update_flags(RegisterEnum::R3);
+        regs.write(RegisterEnum::R1, 1);
+
+        assert_eq!(
+            regs.take_written(),
+            vec![RegisterEnum::R1, RegisterEnum::R3, RegisterEnum::COND]
+        );
+        assert!(regs.take_written().is_empty());
+    }
 }
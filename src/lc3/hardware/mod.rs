@@ -1,3 +1,5 @@
+pub mod Bus;
+pub mod Device;
 pub mod Flag;
 pub mod Memory;
 pub mod Reg;
@@ -0,0 +1,80 @@
+use std::io;
+use std::ptr;
+
+/// A fixed-size region of memory that is writable and executable, filled front to back.
+pub struct ExecBuffer {
+    base: *mut u8,
+    capacity: usize,
+    used: usize,
+}
+
+impl ExecBuffer {
+    /// Map `capacity` bytes of read/write/execute memory.
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        // SAFETY: an anonymous private mapping with no address hint; checked for failure below
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                capacity,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ExecBuffer { base: base as *mut u8, capacity, used: 0 })
+    }
+
+    /// Copy `code` in after what is already there and return its offset, or `None` if
+    /// it doesn't fit.
+    pub fn push(&mut self, code: &[u8]) -> Option<usize> {
+        if code.len() > self.capacity - self.used {
+            return None;
+        }
+        let offset = self.used;
+        // SAFETY: the range was checked to lie inside the mapping, which nothing else aliases
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(offset), code.len()) };
+        self.used += code.len();
+        Some(offset)
+    }
+
+    /// Forget everything pushed so far; previously returned offsets become invalid.
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+
+    /// Address of the code at `offset`.
+    pub fn at(&self, offset: usize) -> *const u8 {
+        debug_assert!(offset < self.used);
+        // SAFETY: offsets handed out by `push` are inside the mapping
+        unsafe { self.base.add(offset) }
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `base` and `capacity` describe the mapping made in `new`
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_call() {
+        let mut buffer = ExecBuffer::new(4096).unwrap();
+        // mov eax, 42 ; ret
+        let offset = buffer.push(&[0xB8, 42, 0, 0, 0, 0xC3]).unwrap();
+        // SAFETY: the bytes above are a complete function taking no arguments
+        let function: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(buffer.at(offset)) };
+        assert_eq!(function(), 42);
+        assert_eq!(buffer.push(&[0; 4096]), None);
+    }
+}
@@ -0,0 +1,353 @@
+//Differential tests: the same program on the interpreter and on the JIT must end in the same state
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lc3::asm::assemble;
+use crate::lc3::sys::console::{BufferConsole, ScriptedConsole};
+use crate::lc3::vm::{ExitReason, LC3};
+use crate::lc3::hardware::Reg::RegisterEnum;
+
+/// Everything a program can change, plus how it stopped.
+#[derive(Debug, PartialEq, Eq)]
+struct State {
+    exit: ExitReason,
+    registers: [u16; 10],
+    psr: u16,
+    memory: Vec<u16>,
+    output: String,
+}
+
+/// Run a machine set up by `setup` for at most `steps` instructions, with or without the JIT.
+fn run(setup: &dyn Fn(&mut LC3), steps: u64, jit: bool) -> (State, usize) {
+    let console = Rc::new(RefCell::new(BufferConsole::default()));
+    let mut vm = LC3::with_console(console.clone());
+    vm.set_jit(jit).unwrap();
+    setup(&mut vm);
+    let exit = vm.run_for(steps);
+    let blocks = vm.jit().map_or(0, |jit| jit.blocks_compiled());
+    let state = State {
+        exit,
+        registers: vm.registers().data,
+        psr: vm.registers().psr(),
+        memory: (0..=0xFFFF).map(|address| vm.memory().peek(address)).collect(),
+        output: console.borrow().output_string(),
+    };
+    (state, blocks)
+}
+
+/// Assert the interpreter and the JIT agree, and return how many blocks the JIT compiled.
+fn assert_same(setup: &dyn Fn(&mut LC3), steps: u64) -> usize {
+    let (interpreted, _) = run(setup, steps, false);
+    let (compiled, blocks) = run(setup, steps, true);
+    assert_eq!(interpreted.exit, compiled.exit);
+    assert_eq!(interpreted.registers, compiled.registers);
+    assert_eq!(interpreted.psr, compiled.psr);
+    assert_eq!(interpreted.output, compiled.output);
+    if let Some(address) = (0..interpreted.memory.len()).find(|&a| interpreted.memory[a] != compiled.memory[a]) {
+        panic!(
+            "memory differs at x{:04X}: interpreter {:04X}, jit {:04X}",
+            address, interpreted.memory[address], compiled.memory[address]
+        );
+    }
+    blocks
+}
+
+fn load_source(vm: &mut LC3, source: &str) {
+    let assembly = assemble(source).unwrap();
+    vm.load_words(assembly.origin, &assembly.words);
+}
+
+/// xorshift32, so the random programs are the same on every run.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: u32) -> u16 {
+        (self.next() % n) as u16
+    }
+}
+
+/// A loop of random operate, load and store instructions, counted down in R7.
+/// Bases and pointers are random too, so accesses land in code, device registers
+/// and system space as well as in data.
+fn random_loop(rng: &mut Rng) -> Vec<u16> {
+    let mut program = Vec::new();
+    let body = 4 + rng.below(24);
+    for _ in 0..body {
+        let dr = rng.below(6);
+        let sr = rng.below(8);
+        let word = match rng.below(12) {
+            0 => 0x1000 | dr << 9 | sr << 6 | rng.below(8),
+            1 => 0x1020 | dr << 9 | sr << 6 | rng.below(32),
+            2 => 0x5000 | dr << 9 | sr << 6 | rng.below(8),
+            3 => 0x5020 | dr << 9 | sr << 6 | rng.below(32),
+            4 => 0x903F | dr << 9 | sr << 6,
+            5 => 0x2000 | dr << 9 | rng.below(0x200),
+            6 => 0xA000 | dr << 9 | rng.below(0x200),
+            7 => 0x6000 | dr << 9 | sr << 6 | rng.below(64),
+            8 => 0xE000 | dr << 9 | rng.below(0x200),
+            9 => 0x3000 | sr << 9 | rng.below(0x200),
+            10 => 0xB000 | sr << 9 | rng.below(0x200),
+            _ => 0x7000 | sr << 9 | rng.below(8) << 6 | rng.below(64),
+        };
+        program.push(word);
+    }
+    // ADD R7, R7, #-1 ; BRp back to the top ; HALT
+    program.push(0x1FFF);
+    program.push(0x0200 | (-(body as i16) - 2) as u16 & 0x1FF);
+    program.push(0xF025);
+    program
+}
+
+#[test]
+fn differential_random_loops() {
+    let mut rng = Rng(0x2545_F491);
+    let mut blocks = 0;
+    for _ in 0..200 {
+        let program = random_loop(&mut rng);
+        let registers: Vec<u16> = (0..7).map(|_| rng.next() as u16).collect();
+        let data: Vec<u16> = (0..0x200).map(|_| rng.next() as u16).collect();
+        let setup = |vm: &mut LC3| {
+            vm.load_words(0x3000, &program);
+            vm.load_words(0x3100, &data);
+            for (i, &value) in registers.iter().enumerate() {
+                vm.registers_mut().write(RegisterEnum::try_from(i).unwrap(), value);
+            }
+            vm.registers_mut().write(RegisterEnum::R7, 40);
+        };
+        blocks += assert_same(&setup, 5_000);
+    }
+    assert!(blocks > 100, "only {} blocks compiled", blocks);
+}
+
+#[test]
+fn differential_subroutines_and_branches() {
+    let source = "
+        .ORIG x3000
+        LEA R6, STACK
+        AND R0, R0, #0
+        LD  R1, COUNT
+LOOP    JSR DOUBLE
+        ADD R1, R1, #-1
+        BRz DONE
+        LEA R2, DOUBLE
+        JSRR R2
+        NOT R3, R0
+        BRn LOOP
+        BRzp LOOP
+DONE    STI R0, OUTPUT
+        HALT
+DOUBLE  ADD R0, R0, R0
+        ADD R0, R0, #1
+        STR R0, R6, #0
+        RET
+COUNT   .FILL #500
+OUTPUT  .FILL x4000
+STACK   .BLKW #1
+        .END
+    ";
+    let blocks = assert_same(&|vm| load_source(vm, source), 100_000);
+    assert!(blocks > 0);
+}
+
+#[test]
+fn differential_self_modifying_code() {
+    // Rewrites its own increment every pass, from #1 up to #15
+    let source = "
+        .ORIG x3000
+        LD  R2, PASSES
+LOOP    ADD R0, R0, #1
+        LD  R1, PATCH
+        ADD R1, R1, R3
+        ST  R1, LOOP
+        ADD R3, R3, #1
+        AND R3, R3, #15
+        ADD R2, R2, #-1
+        BRp LOOP
+        HALT
+PATCH   .FILL x1021
+PASSES  .FILL #300
+        .END
+    ";
+    let blocks = assert_same(&|vm| load_source(vm, source), 100_000);
+    assert!(blocks > 0);
+}
+
+#[test]
+fn differential_device_registers_and_traps() {
+    // Writes through the display registers and OUT, then clears the MCR
+    let source = "
+        .ORIG x3000
+        LD  R1, COUNT
+LOOP    LDI R2, DSR
+        BRzp LOOP
+        LD  R0, CHAR
+        STI R0, DDR
+        OUT
+        ADD R1, R1, #-1
+        BRp LOOP
+        AND R0, R0, #0
+        STI R0, MCR
+        ADD R5, R5, #1
+DSR     .FILL xFE04
+DDR     .FILL xFE06
+MCR     .FILL xFFFE
+CHAR    .FILL x2A
+COUNT   .FILL #50
+        .END
+    ";
+    assert_same(&|vm| load_source(vm, source), 100_000);
+}
+
+#[test]
+fn differential_user_mode_access_violation() {
+    // Sums memory upwards from x3100 in user mode until it reaches the device registers
+    let source = "
+        .ORIG x3000
+        LD  R1, START
+LOOP    LDR R2, R1, #0
+        ADD R0, R0, R2
+        ADD R1, R1, #1
+        BRnzp LOOP
+START   .FILL x3100
+        .END
+    ";
+    let setup = |vm: &mut LC3| {
+        load_source(vm, source);
+        vm.registers_mut().set_psr(0x8002);
+    };
+    assert_same(&setup, 200_000);
+}
+
+#[test]
+fn differential_2048_with_scripted_input() {
+    let run = |jit: bool| {
+        let script = Rc::new(RefCell::new(ScriptedConsole::new().keys("nwasdwasdddsa")));
+        let mut vm = LC3::with_console(script.clone());
+        vm.set_jit(jit).unwrap();
+        vm.load_image("Static/2048.obj").unwrap();
+        let exit = vm.run_for(5_000_000);
+        let memory: Vec<u16> = (0..=0xFFFF).map(|address| vm.memory().peek(address)).collect();
+        let output = script.borrow().output_string();
+        (exit, vm.registers().data, memory, output)
+    };
+    assert!(run(false) == run(true));
+}
+
+#[test]
+fn differential_raised_interrupt() {
+    // The interrupt is raised while the hot loop runs compiled, and taken after the
+    // same instruction on both
+    let source = "
+        .ORIG x3000
+LOOP    ADD R2, R2, #1
+        ADD R3, R3, #1
+        BRnzp LOOP
+        .END
+    ";
+    let run = |jit: bool| {
+        let mut vm = LC3::with_console(Rc::new(RefCell::new(BufferConsole::default())));
+        vm.set_jit(jit).unwrap();
+        load_source(&mut vm, source);
+        vm.load_words(0x0180, &[0x0600]);
+        vm.load_words(0x0600, &[0xF025]); // HALT
+        vm.run_for(999);
+        vm.raise_interrupt(0x80, 4);
+        let exit = vm.run_for(10);
+        (exit, vm.registers().data, vm.registers().psr())
+    };
+    assert_eq!(run(false), run(true));
+}
+
+#[test]
+fn differential_keyboard_interrupts() {
+    // Counts in a hot loop; each key interrupts it and the handler notes the count
+    let source = "
+        .ORIG x3000
+        LD  R0, HANDLER
+        STI R0, VECTOR
+        LD  R0, ENABLE
+        STI R0, KBSR
+LOOP    ADD R2, R2, #1
+        ADD R3, R3, #-1
+        BRnzp LOOP
+KEY     LDI R0, KBDR
+        LEA R1, SEEN
+        ADD R1, R1, R4
+        STR R2, R1, #0
+        ADD R4, R4, #1
+        RTI
+HANDLER .FILL KEY
+VECTOR  .FILL x0180
+ENABLE  .FILL x4000
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+SEEN    .BLKW 4
+        .END
+    ";
+    let run = |jit: bool| {
+        let script = ScriptedConsole::new().idle(3).keys("a").idle(20).keys("bc");
+        let mut vm = LC3::with_console(Rc::new(RefCell::new(script)));
+        vm.set_jit(jit).unwrap();
+        load_source(&mut vm, source);
+        let exit = vm.run_for(20_000);
+        let memory: Vec<u16> = (0..=0xFFFF).map(|address| vm.memory().peek(address)).collect();
+        (exit, vm.registers().data, vm.registers().psr(), memory)
+    };
+    let interpreted = run(false);
+    assert_eq!(interpreted.1[RegisterEnum::R4 as usize], 3);
+    assert!(interpreted == run(true));
+}
+
+#[test]
+fn differential_run_to_halt() {
+    // `run` compiles the hot loop too, and stops at the same HALT
+    let source = "
+        .ORIG x3000
+        LD  R1, COUNT
+LOOP    ADD R2, R2, R1
+        ADD R1, R1, #-1
+        BRp LOOP
+        ST  R2, SUM
+        HALT
+COUNT   .FILL #500
+SUM     .BLKW 1
+        .END
+    ";
+    let run = |jit: bool| {
+        let console = Rc::new(RefCell::new(BufferConsole::default()));
+        let mut vm = LC3::with_console(console.clone());
+        vm.set_jit(jit).unwrap();
+        load_source(&mut vm, source);
+        let exit = vm.run();
+        let blocks = vm.jit().map_or(0, |jit| jit.blocks_compiled());
+        let memory: Vec<u16> = (0..=0xFFFF).map(|address| vm.memory().peek(address)).collect();
+        let output = console.borrow().output_string();
+        ((exit, vm.registers().data, vm.registers().psr(), memory, output), blocks)
+    };
+    let (interpreted, _) = run(false);
+    let (compiled, blocks) = run(true);
+    assert_eq!(interpreted.0, ExitReason::Halted);
+    assert!(blocks > 0);
+    assert!(interpreted == compiled);
+}
+
+#[test]
+fn jit_respects_step_limit() {
+    let source = "
+        .ORIG x3000
+LOOP    ADD R0, R0, #1
+        ADD R1, R1, #2
+        BRnzp LOOP
+        .END
+    ";
+    for steps in [1, 17, 100, 1_001, 10_000] {
+        assert_same(&|vm| load_source(vm, source), steps);
+    }
+}
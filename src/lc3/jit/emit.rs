@@ -0,0 +1,243 @@
+//! Just enough of an x86-64 assembler for the translator.
+//!
+//! Every memory operand is either 16-bit state at a fixed displacement from `rdi` (the
+//! block context), a word of LC-3 RAM at `rsi + rax * 2`, or a byte of the code map at
+//! `rdx + rax`. `eax` and `ecx` are the only scratch registers.
+
+/// A scratch register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gpr {
+    Eax = 0,
+    Ecx = 1,
+}
+
+/// A 64-bit pointer register loaded from the context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ptr {
+    Rdx = 2,
+    Rsi = 6,
+}
+
+/// Condition codes for `jcc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+/// A jump target, bound to a position with `Emitter::bind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// `rdi`, the base of every context operand.
+const RDI: u8 = 7;
+/// `rsi + rax * 2` as a SIB byte.
+const SIB_RAM: u8 = 0b01_000_110;
+
+#[derive(Default)]
+pub struct Emitter {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// Positions of rel32 fields waiting for their label.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ModRM for `[rdi + disp8]` with `reg` in the reg field.
+    fn context(&mut self, reg: u8, disp: u8) {
+        self.code.extend([0x40 | reg << 3 | RDI, disp]);
+    }
+
+    /// `mov ptr, [rdi + disp]`
+    pub fn load_ptr(&mut self, dst: Ptr, disp: u8) {
+        self.code.extend([0x48, 0x8B]);
+        self.context(dst as u8, disp);
+    }
+
+    /// `movzx dst, word [rdi + disp]`
+    pub fn load16(&mut self, dst: Gpr, disp: u8) {
+        self.code.extend([0x0F, 0xB7]);
+        self.context(dst as u8, disp);
+    }
+
+    /// `mov word [rdi + disp], src`
+    pub fn store16(&mut self, disp: u8, src: Gpr) {
+        self.code.extend([0x66, 0x89]);
+        self.context(src as u8, disp);
+    }
+
+    /// `mov word [rdi + disp], imm`
+    pub fn store16_imm(&mut self, disp: u8, imm: u16) {
+        self.code.extend([0x66, 0xC7]);
+        self.context(0, disp);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `mov dword [rdi + disp], imm`
+    pub fn store32_imm(&mut self, disp: u8, imm: u32) {
+        self.code.push(0xC7);
+        self.context(0, disp);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `add ax, word [rdi + disp]`
+    pub fn add16(&mut self, disp: u8) {
+        self.code.extend([0x66, 0x03]);
+        self.context(Gpr::Eax as u8, disp);
+    }
+
+    /// `and ax, word [rdi + disp]`
+    pub fn and16(&mut self, disp: u8) {
+        self.code.extend([0x66, 0x23]);
+        self.context(Gpr::Eax as u8, disp);
+    }
+
+    /// `add ax, imm`
+    pub fn add16_imm(&mut self, imm: u16) {
+        self.code.extend([0x66, 0x05]);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `and ax, imm`
+    pub fn and16_imm(&mut self, imm: u16) {
+        self.code.extend([0x66, 0x25]);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `not ax`
+    pub fn not16(&mut self) {
+        self.code.extend([0x66, 0xF7, 0xD0]);
+    }
+
+    /// `mov eax, imm`
+    pub fn mov32_imm(&mut self, imm: u32) {
+        self.code.push(0xB8);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `cmp eax, dword [rdi + disp]`
+    pub fn cmp32(&mut self, disp: u8) {
+        self.code.push(0x3B);
+        self.context(Gpr::Eax as u8, disp);
+    }
+
+    /// `test word [rdi + disp], imm`
+    pub fn test16_imm(&mut self, disp: u8, imm: u16) {
+        self.code.extend([0x66, 0xF7]);
+        self.context(0, disp);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// `movzx dst, word [rsi + rax * 2]`: load the RAM word at the address in `eax`.
+    pub fn load_ram(&mut self, dst: Gpr) {
+        self.code.extend([0x0F, 0xB7, (dst as u8) << 3 | 0b100, SIB_RAM]);
+    }
+
+    /// `mov word [rsi + rax * 2], src`: store to the RAM word at the address in `eax`.
+    pub fn store_ram(&mut self, src: Gpr) {
+        self.code.extend([0x66, 0x89, (src as u8) << 3 | 0b100, SIB_RAM]);
+    }
+
+    /// `cmp byte [rdx + rax], 0`: test the code map entry for the address in `eax`.
+    pub fn test_code_map(&mut self) {
+        self.code.extend([0x80, 0x3C, 0x02, 0x00]);
+    }
+
+    /// Store the LC-3 condition code for the value in `ax` to `[rdi + disp]`:
+    /// P = 1, Z = 2, N = 4. Clobbers `ecx`.
+    pub fn set_cond(&mut self, disp: u8) {
+        self.code.extend([
+            0xB9, 0x01, 0x00, 0x00, 0x00, // mov ecx, 1
+            0x66, 0x85, 0xC0, // test ax, ax
+            0x7F, 0x06, // jg store
+            0xB1, 0x02, // mov cl, 2
+            0x74, 0x02, // je store
+            0xB1, 0x04, // mov cl, 4
+        ]);
+        // store:
+        self.store16(disp, Gpr::Ecx);
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Point `label` at the next instruction emitted.
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// `jcc rel32`
+    pub fn jcc(&mut self, cond: Cond, target: Label) {
+        self.code.extend([0x0F, 0x80 | cond as u8]);
+        self.rel32(target);
+    }
+
+    /// `jmp rel32`
+    pub fn jmp(&mut self, target: Label) {
+        self.code.push(0xE9);
+        self.rel32(target);
+    }
+
+    fn rel32(&mut self, target: Label) {
+        self.fixups.push((self.code.len(), target));
+        self.code.extend([0; 4]);
+    }
+
+    /// The machine code, with every jump resolved.
+    pub fn finish(mut self) -> Vec<u8> {
+        for (at, label) in self.fixups {
+            let target = self.labels[label.0].expect("jump to an unbound label");
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut e = Emitter::new();
+        e.load_ptr(Ptr::Rsi, 32);
+        e.load16(Gpr::Ecx, 18);
+        e.store16_imm(16, 0x3000);
+        e.load_ram(Gpr::Eax);
+        e.store_ram(Gpr::Ecx);
+        assert_eq!(
+            e.finish(),
+            [
+                0x48, 0x8B, 0x77, 0x20, // mov rsi, [rdi+32]
+                0x0F, 0xB7, 0x4F, 0x12, // movzx ecx, word [rdi+18]
+                0x66, 0xC7, 0x47, 0x10, 0x00, 0x30, // mov word [rdi+16], 0x3000
+                0x0F, 0xB7, 0x04, 0x46, // movzx eax, word [rsi+rax*2]
+                0x66, 0x89, 0x0C, 0x46, // mov [rsi+rax*2], cx
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jumps_resolve() {
+        let mut e = Emitter::new();
+        let skip = e.label();
+        e.jcc(Cond::Equal, skip);
+        e.ret();
+        e.bind(skip);
+        e.jmp(skip);
+        assert_eq!(e.finish(), [0x0F, 0x84, 1, 0, 0, 0, 0xC3, 0xE9, 0xFB, 0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,178 @@
+//! Basic-block translator from LC-3 to x86-64, built with the `jit` cargo feature.
+//!
+//! `LC3::run_for` hands control to `Jit::run` in front of every instruction. Once a
+//! block start has been reached `HOT_THRESHOLD` times it is translated, and from then
+//! on runs natively until it branches or reaches something only the interpreter
+//! handles: TRAP, RTI, device registers, protected memory and stores to code.
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs an x86-64 Unix host");
+
+pub mod buffer;
+pub mod emit;
+pub mod translate;
+
+use std::io;
+
+use buffer::ExecBuffer;
+use translate::{translate, Context};
+
+use crate::lc3::cpu::decode::decode;
+use crate::lc3::hardware::Memory::{is_protected, Memory, MEMORY_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::lc3::hardware::Reg::{RegisterEnum, Registers};
+
+/// Times a block start is interpreted before it is translated.
+pub const HOT_THRESHOLD: u8 = 16;
+/// Size of the executable buffer; when it fills up every block is thrown away.
+pub const BUFFER_SIZE: usize = 4 << 20;
+
+/// What the JIT knows about an address as a block start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    /// Reached this many times so far.
+    Cold(u8),
+    /// Index into `Jit::blocks`.
+    Compiled(u32),
+    /// Starts with something the translator doesn't handle.
+    Uncompilable,
+}
+
+/// A translated block in the buffer.
+struct Block {
+    start: u16,
+    len: u16,
+    offset: usize,
+    live: bool,
+}
+
+pub struct Jit {
+    buffer: ExecBuffer,
+    blocks: Vec<Block>,
+    entries: Vec<Entry>,
+}
+
+type BlockFn = unsafe extern "sysv64" fn(*mut Context);
+
+impl Jit {
+    pub fn new() -> io::Result<Self> {
+        Ok(Jit {
+            buffer: ExecBuffer::new(BUFFER_SIZE)?,
+            blocks: Vec::new(),
+            entries: vec![Entry::Cold(0); MEMORY_SIZE],
+        })
+    }
+
+    /// Run the block at PC natively if it is hot, translating it first if needed.
+    ///
+    /// Returns how many instructions were executed, never more than `budget`. `0` means
+    /// the interpreter has to execute the next instruction itself.
+    pub fn run(&mut self, registers: &mut Registers, memory: &mut Memory, budget: u64) -> u64 {
+        self.sync(memory);
+        let pc = registers.read(RegisterEnum::PC);
+        let user_mode = registers.is_user_mode();
+        if user_mode && is_protected(pc) {
+            // The fetch raises an access control violation
+            return 0;
+        }
+
+        let index = match self.entries[pc as usize] {
+            Entry::Compiled(index) => index as usize,
+            Entry::Uncompilable => return 0,
+            Entry::Cold(heat) if heat + 1 < HOT_THRESHOLD => {
+                self.entries[pc as usize] = Entry::Cold(heat + 1);
+                return 0;
+            }
+            Entry::Cold(_) => match self.compile(pc, memory) {
+                Some(index) => index,
+                None => {
+                    self.entries[pc as usize] = Entry::Uncompilable;
+                    return 0;
+                }
+            },
+        };
+        let block = &self.blocks[index];
+        if block.len as u64 > budget {
+            return 0;
+        }
+
+        let floor = memory.device_floor() as u32;
+        let (low, high) = if user_mode {
+            (USER_SPACE_START as u32, floor.min(USER_SPACE_END as u32 + 1))
+        } else {
+            (0, floor)
+        };
+        let (ram, code_map) = memory.raw_parts();
+        let mut context = Context { registers: registers.data, low, high, executed: 0, memory: ram, code_map };
+        // SAFETY: the block was produced by `translate` for this context layout, and only
+        // touches RAM inside `low..high` at addresses the code map leaves unmarked
+        unsafe {
+            let function: BlockFn = std::mem::transmute(self.buffer.at(block.offset));
+            function(&mut context);
+        }
+        registers.data = context.registers;
+        context.executed as u64
+    }
+
+    /// Drop blocks whose code has been written to since the last call.
+    fn sync(&mut self, memory: &mut Memory) {
+        memory.watch_code();
+        if memory.take_remapped() {
+            self.flush();
+        }
+        for address in memory.take_code_writes() {
+            self.invalidate(address);
+        }
+    }
+
+    /// Translate the block at `start` and return its index.
+    fn compile(&mut self, start: u16, memory: &mut Memory) -> Option<usize> {
+        let protected = is_protected(start);
+        let translation = translate(start, |address| {
+            if !memory.is_ram(address) || is_protected(address) != protected {
+                return None;
+            }
+            decode(memory.peek(address as usize)).ok()
+        })?;
+
+        let offset = match self.buffer.push(&translation.code) {
+            Some(offset) => offset,
+            None => {
+                self.flush();
+                self.buffer.push(&translation.code)?
+            }
+        };
+        memory.mark_code(start, translation.len);
+        self.blocks.push(Block { start, len: translation.len, offset, live: true });
+        let index = self.blocks.len() - 1;
+        self.entries[start as usize] = Entry::Compiled(index as u32);
+        Some(index)
+    }
+
+    /// Forget every block covering `address`.
+    fn invalidate(&mut self, address: u16) {
+        for block in self.blocks.iter_mut().filter(|block| block.live) {
+            if address.wrapping_sub(block.start) < block.len {
+                block.live = false;
+                self.entries[block.start as usize] = Entry::Cold(0);
+            }
+        }
+        if self.entries[address as usize] == Entry::Uncompilable {
+            self.entries[address as usize] = Entry::Cold(0);
+        }
+    }
+
+    /// Blocks translated so far, including ones since invalidated.
+    pub fn blocks_compiled(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Throw every block away.
+    pub fn flush(&mut self) {
+        self.buffer.clear();
+        self.blocks.clear();
+        self.entries.fill(Entry::Cold(0));
+    }
+}
+
+#[cfg(test)]
+mod differential_tests;
@@ -0,0 +1,224 @@
+use std::mem::offset_of;
+
+use super::emit::{Cond, Emitter, Gpr, Label, Ptr};
+use crate::lc3::cpu::decode::{Instruction, Operand};
+use crate::lc3::hardware::Reg::RegisterEnum;
+
+/// Instructions in the longest block `translate` produces.
+pub const MAX_BLOCK_LEN: u16 = 64;
+
+/// What a compiled block reads and writes. Blocks are called as
+/// `extern "sysv64" fn(*mut Context)` and address the fields by offset.
+#[repr(C)]
+pub struct Context {
+    /// R0-R7, PC and COND, laid out like `Registers::data`.
+    pub registers: [u16; 10],
+    /// Loads and stores outside `low..high` are left to the interpreter.
+    pub low: u32,
+    pub high: u32,
+    /// Written by the block: how many of its instructions completed.
+    pub executed: u32,
+    /// The 64K words of RAM.
+    pub memory: *mut u16,
+    /// One byte per address, nonzero where decoded code lives; stores there are left
+    /// to the interpreter.
+    pub code_map: *const u8,
+}
+
+const LOW: u8 = offset_of!(Context, low) as u8;
+const HIGH: u8 = offset_of!(Context, high) as u8;
+const EXECUTED: u8 = offset_of!(Context, executed) as u8;
+const MEMORY: u8 = offset_of!(Context, memory) as u8;
+const CODE_MAP: u8 = offset_of!(Context, code_map) as u8;
+
+/// Displacement of a register in `Context::registers`.
+fn reg(r: RegisterEnum) -> u8 {
+    offset_of!(Context, registers) as u8 + 2 * r as u8
+}
+
+const PC: u8 = 2 * RegisterEnum::PC as u8;
+const COND: u8 = 2 * RegisterEnum::COND as u8;
+
+/// A compiled basic block.
+pub struct Translation {
+    pub code: Vec<u8>,
+    /// Instruction words the block covers, starting at its address.
+    pub len: u16,
+}
+
+/// Translate the basic block at `start` into x86-64.
+///
+/// `fetch` returns the instruction at an address, or `None` where the block has to
+/// stop (device registers, a change of protection, the reserved opcode). The block
+/// also stops after a branch or jump, and in front of TRAP and RTI. Returns `None`
+/// if not even the first instruction can be compiled.
+///
+/// The block leaves through a side exit, in front of the instruction and with its
+/// effects not yet applied, whenever a load or store would leave RAM the running
+/// mode may access or a store would hit decoded code.
+pub fn translate(start: u16, mut fetch: impl FnMut(u16) -> Option<Instruction>) -> Option<Translation> {
+    let mut e = Emitter::new();
+    // Side exits, bound after the block body: label, address, instructions completed
+    let mut exits: Vec<(Label, u16, u32)> = Vec::new();
+
+    e.load_ptr(Ptr::Rsi, MEMORY);
+    e.load_ptr(Ptr::Rdx, CODE_MAP);
+
+    let mut len: u16 = 0;
+    let mut terminated = false;
+    while len < MAX_BLOCK_LEN && !terminated {
+        let address = start.wrapping_add(len);
+        let Some(instruction) = fetch(address) else { break };
+        if matches!(instruction, Instruction::Trap { .. } | Instruction::Rti) {
+            break;
+        }
+
+        let index = len as u32;
+        let next = address.wrapping_add(1);
+        let relative = |offset: i16| next.wrapping_add(offset as u16);
+        let mut exit = |e: &mut Emitter| {
+            let label = e.label();
+            exits.push((label, address, index));
+            label
+        };
+        len += 1;
+
+        match instruction {
+            Instruction::Add { dr, sr1, src } | Instruction::And { dr, sr1, src } => {
+                let is_add = matches!(instruction, Instruction::Add { .. });
+                e.load16(Gpr::Eax, reg(sr1));
+                match (src, is_add) {
+                    (Operand::Register(sr2), true) => e.add16(reg(sr2)),
+                    (Operand::Register(sr2), false) => e.and16(reg(sr2)),
+                    (Operand::Immediate(imm5), true) => e.add16_imm(imm5 as u16),
+                    (Operand::Immediate(imm5), false) => e.and16_imm(imm5 as u16),
+                }
+                e.store16(reg(dr), Gpr::Eax);
+                e.set_cond(COND);
+            }
+            Instruction::Not { dr, sr } => {
+                e.load16(Gpr::Eax, reg(sr));
+                e.not16();
+                e.store16(reg(dr), Gpr::Eax);
+                e.set_cond(COND);
+            }
+            // Never branches
+            Instruction::Br { nzp: 0, .. } => {}
+            Instruction::Br { nzp, offset } => {
+                let not_taken = e.label();
+                e.test16_imm(COND, nzp as u16);
+                e.jcc(Cond::Equal, not_taken);
+                e.store16_imm(PC, relative(offset));
+                leave(&mut e, len as u32);
+                e.bind(not_taken);
+                e.store16_imm(PC, next);
+                terminated = true;
+            }
+            Instruction::Jmp { base } => {
+                e.load16(Gpr::Eax, reg(base));
+                e.store16(PC, Gpr::Eax);
+                terminated = true;
+            }
+            Instruction::Jsr { offset } => {
+                e.store16_imm(reg(RegisterEnum::R7), next);
+                e.store16_imm(PC, relative(offset));
+                terminated = true;
+            }
+            Instruction::Jsrr { base } => {
+                e.load16(Gpr::Eax, reg(base));
+                e.store16_imm(reg(RegisterEnum::R7), next);
+                e.store16(PC, Gpr::Eax);
+                terminated = true;
+            }
+            Instruction::Ld { dr, offset } => {
+                e.mov32_imm(relative(offset) as u32);
+                let side_exit = exit(&mut e);
+                check_access(&mut e, side_exit);
+                load(&mut e, dr);
+            }
+            Instruction::Ldi { dr, offset } => {
+                let side_exit = exit(&mut e);
+                e.mov32_imm(relative(offset) as u32);
+                check_access(&mut e, side_exit);
+                e.load_ram(Gpr::Eax);
+                check_access(&mut e, side_exit);
+                load(&mut e, dr);
+            }
+            Instruction::Ldr { dr, base, offset } => {
+                e.load16(Gpr::Eax, reg(base));
+                e.add16_imm(offset as u16);
+                let side_exit = exit(&mut e);
+                check_access(&mut e, side_exit);
+                load(&mut e, dr);
+            }
+            Instruction::Lea { dr, offset } => {
+                e.mov32_imm(relative(offset) as u32);
+                e.store16(reg(dr), Gpr::Eax);
+                e.set_cond(COND);
+            }
+            Instruction::St { sr, offset } => {
+                e.mov32_imm(relative(offset) as u32);
+                let side_exit = exit(&mut e);
+                store(&mut e, sr, side_exit);
+            }
+            Instruction::Sti { sr, offset } => {
+                let side_exit = exit(&mut e);
+                e.mov32_imm(relative(offset) as u32);
+                check_access(&mut e, side_exit);
+                e.load_ram(Gpr::Eax);
+                store(&mut e, sr, side_exit);
+            }
+            Instruction::Str { sr, base, offset } => {
+                e.load16(Gpr::Eax, reg(base));
+                e.add16_imm(offset as u16);
+                let side_exit = exit(&mut e);
+                store(&mut e, sr, side_exit);
+            }
+            Instruction::Trap { .. } | Instruction::Rti => unreachable!("stopped in front of"),
+        }
+    }
+
+    if len == 0 {
+        return None;
+    }
+    if !terminated {
+        e.store16_imm(PC, start.wrapping_add(len));
+    }
+    leave(&mut e, len as u32);
+    for (label, address, index) in exits {
+        e.bind(label);
+        e.store16_imm(PC, address);
+        leave(&mut e, index);
+    }
+    Some(Translation { code: e.finish(), len })
+}
+
+/// Return to the interpreter with `executed` instructions completed.
+fn leave(e: &mut Emitter, executed: u32) {
+    e.store32_imm(EXECUTED, executed);
+    e.ret();
+}
+
+/// Take `side_exit` unless the address in `eax` is in `low..high`.
+fn check_access(e: &mut Emitter, side_exit: Label) {
+    e.cmp32(LOW);
+    e.jcc(Cond::Below, side_exit);
+    e.cmp32(HIGH);
+    e.jcc(Cond::AboveOrEqual, side_exit);
+}
+
+/// Load the word at the address in `eax` into `dr` and set the condition codes.
+fn load(e: &mut Emitter, dr: RegisterEnum) {
+    e.load_ram(Gpr::Eax);
+    e.store16(reg(dr), Gpr::Eax);
+    e.set_cond(COND);
+}
+
+/// Store `sr` to the address in `eax`, unless it is out of bounds or decoded code.
+fn store(e: &mut Emitter, sr: RegisterEnum, side_exit: Label) {
+    check_access(e, side_exit);
+    e.test_code_map();
+    e.jcc(Cond::NotEqual, side_exit);
+    e.load16(Gpr::Ecx, reg(sr));
+    e.store_ram(Gpr::Ecx);
+}
@@ -1,4 +1,8 @@
+pub mod asm; // Assembler: LC-3 source to object images
 pub mod cpu; // CPU-related functionality (instruction execution, decoding)
+pub mod debug; // Breakpoints, watchpoints and the debugger command line
 pub mod hardware; // Hardware-related functionality (memory, registers, flags)
+#[cfg(feature = "jit")]
+pub mod jit; // Basic-block translator to x86-64
 pub mod vm;
 pub mod sys;
\ No newline at end of file
@@ -0,0 +1,234 @@
+//! Character I/O for the VM.
+//!
+//! Traps and the keyboard/display devices talk to a `Console` instead of stdin/stdout,
+//! so the machine can run headless, be fed canned input and have its output captured.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::lc3::sys::terminal;
+
+/// A byte-oriented terminal the VM reads keystrokes from and writes characters to.
+pub trait Console {
+    /// Block until a byte is available. Returns `Ok(None)` once input is exhausted.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+
+    /// Return a byte if one is available right now, without blocking.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// A console shared between the VM, its traps and its devices.
+pub type SharedConsole = Rc<RefCell<dyn Console>>;
+
+/// Wrap `console` for sharing with an `LC3`.
+pub fn shared<C: Console + 'static>(console: C) -> SharedConsole {
+    Rc::new(RefCell::new(console))
+}
+
+/// The process's stdin and stdout.
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        terminal::get_char()
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        if terminal::check_key() {
+            terminal::get_char()
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Input from a fixed buffer, output collected in memory.
+#[derive(Default)]
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Queue more input behind whatever has not been read yet.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+
+    /// Whether any input is left to read.
+    pub fn has_input(&self) -> bool {
+        !self.input.is_empty()
+    }
+
+    /// Remove and return the output written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The output so far, with invalid UTF-8 replaced.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One entry of a `ScriptedConsole` script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// Keystrokes delivered one at a time.
+    Keys(Vec<u8>),
+    /// Answer this many non-blocking polls with "no key" before moving on.
+    Idle(u32),
+}
+
+/// Keystrokes that arrive over time, for programs that poll the keyboard.
+///
+/// Blocking reads skip idle periods (a real user would eventually type the key),
+/// while polls see them, so a game loop can run frames between keys. Output is
+/// collected in memory.
+#[derive(Default)]
+pub struct ScriptedConsole {
+    script: VecDeque<ScriptEvent>,
+    output: Vec<u8>,
+}
+
+impl ScriptedConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append keystrokes to the script.
+    pub fn keys(mut self, keys: &str) -> Self {
+        self.script.push_back(ScriptEvent::Keys(keys.bytes().collect()));
+        self
+    }
+
+    /// Append a pause lasting `polls` keyboard polls.
+    pub fn idle(mut self, polls: u32) -> Self {
+        self.script.push_back(ScriptEvent::Idle(polls));
+        self
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The output so far, with invalid UTF-8 replaced.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    /// Take the next key, passing over idle periods when `blocking`.
+    fn next_key(&mut self, blocking: bool) -> Option<u8> {
+        loop {
+            match self.script.front_mut()? {
+                ScriptEvent::Keys(keys) if keys.is_empty() => {}
+                ScriptEvent::Keys(keys) => return Some(keys.remove(0)),
+                ScriptEvent::Idle(0) => {}
+                ScriptEvent::Idle(_) if blocking => {}
+                ScriptEvent::Idle(polls) => {
+                    *polls -= 1;
+                    return None;
+                }
+            }
+            self.script.pop_front();
+        }
+    }
+}
+
+impl Console for ScriptedConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.next_key(true))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.next_key(false))
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_console() {
+        let mut console = BufferConsole::new(b"ab");
+        assert_eq!(console.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(console.poll_byte().unwrap(), Some(b'b'));
+        assert_eq!(console.read_byte().unwrap(), None);
+
+        console.write_bytes(b"hi").unwrap();
+        assert_eq!(console.output_string(), "hi");
+        assert_eq!(console.take_output(), b"hi");
+        assert_eq!(console.output_string(), "");
+    }
+
+    #[test]
+    fn test_scripted_console_polls_see_idle_periods() {
+        let mut console = ScriptedConsole::new().keys("a").idle(2).keys("b");
+        assert_eq!(console.poll_byte().unwrap(), Some(b'a'));
+        assert_eq!(console.poll_byte().unwrap(), None);
+        assert_eq!(console.poll_byte().unwrap(), None);
+        assert_eq!(console.poll_byte().unwrap(), Some(b'b'));
+        assert_eq!(console.poll_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn test_scripted_console_blocking_read_skips_idle() {
+        let mut console = ScriptedConsole::new().idle(100).keys("x");
+        assert_eq!(console.read_byte().unwrap(), Some(b'x'));
+        assert_eq!(console.read_byte().unwrap(), None);
+    }
+}
@@ -3,10 +3,10 @@
 //after which the rest of the data can be read from the file into memory starting at the origin adress
 
  
-use byteorder::{BigEndian, ReadBytesExt,ByteOrder};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt, ByteOrder};
 use std::{
     fs::File,
-    io::{self, BufReader, Read},
+    io::{self, BufReader, BufWriter, Read, Write},
 };
 
 use crate::lc3::hardware::Memory::{Memory,MEMORY_SIZE};
@@ -17,7 +17,49 @@ pub fn read_image(name: &str) -> io::Result<Memory> {
     read_image_file(reader)
 }
 
-fn read_image_file<R>(mut reader: R) -> io::Result<Memory>
+/// Reads an image file and returns its origin and words without placing them in a `Memory`,
+/// so callers can copy just the loaded range.
+pub fn read_image_words(name: &str) -> io::Result<(u16, Vec<u16>)> {
+    let file = File::open(name)?;
+    let reader = BufReader::new(file);
+    read_words(reader)
+}
+
+/// Writes `words` as an image that loads at `origin`, in the format `read_image` reads.
+pub fn write_image(name: &str, origin: u16, words: &[u16]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(name)?);
+    write_words(&mut writer, origin, words)?;
+    writer.flush()
+}
+
+fn write_words<W>(mut writer: W, origin: u16, words: &[u16]) -> io::Result<()>
+where
+    W: Write,
+{
+    writer.write_u16::<BigEndian>(origin)?;
+    for &word in words {
+        writer.write_u16::<BigEndian>(word)?;
+    }
+    Ok(())
+}
+
+fn read_image_file<R>(reader: R) -> io::Result<Memory>
+where
+    R: Read,
+{
+    let (origin, words) = read_words(reader)?;
+
+    let mut memory = Memory::new();
+    // Write each word into memory starting at `origin`
+    for (i, &word) in words.iter().enumerate() {
+            memory.write(origin as usize + i, word);
+        }
+
+
+    Ok(memory)
+}
+
+fn read_words<R>(mut reader: R) -> io::Result<(u16, Vec<u16>)>
 where
     R: Read,
 {
@@ -41,7 +83,6 @@ where
     let mut words = vec![0u16; num_words];
     BigEndian::read_u16_into(&buffer, &mut words);
 
-    let mut memory = Memory::new();
     //check if the memory is well sized 
     if origin + num_words > MEMORY_SIZE {
         return Err(io::Error::new(
@@ -50,11 +91,32 @@ where
         ));
     }
 
-    // Write each word into memory starting at `origin`
-    for (i, &word) in words.iter().enumerate() {
-            memory.write(origin + i, word);
-        }
+    Ok((origin as u16, words))
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(memory)
+    #[test]
+    fn test_read_words() {
+        let bytes = [0x30, 0x00, 0x12, 0x34, 0xF0, 0x25];
+        let (origin, words) = read_words(&bytes[..]).unwrap();
+        assert_eq!(origin, 0x3000);
+        assert_eq!(words, vec![0x1234, 0xF025]);
+    }
+
+    #[test]
+    fn test_write_words_round_trip() {
+        let mut bytes = Vec::new();
+        write_words(&mut bytes, 0x3000, &[0x1234, 0xF025]).unwrap();
+        assert_eq!(bytes, [0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]);
+        assert_eq!(read_words(&bytes[..]).unwrap(), (0x3000, vec![0x1234, 0xF025]));
+    }
+
+    #[test]
+    fn test_read_words_rejects_odd_length() {
+        let bytes = [0x30, 0x00, 0x12];
+        assert!(read_words(&bytes[..]).is_err());
+    }
 }
@@ -1 +1,6 @@
+pub mod console;
 pub mod file;
+pub mod json;
+pub mod replay;
+pub mod symbols;
+pub mod terminal;
@@ -0,0 +1,244 @@
+//! Recording console input and playing it back.
+//!
+//! `RecordingConsole` passes input through from another console and logs each byte with
+//! the instruction count it was delivered at; `ReplayConsole` delivers the same bytes at
+//! the same counts, so the run goes exactly as it did. The GETC and IN traps read the
+//! console straight away, while KBSR polls only see a byte once its count comes round.
+//!
+//! A log is plain text, one byte per line: the count, a space and the byte, which is
+//! written as itself if it is printable and not a space, and as `xHH` otherwise. Lines
+//! starting with `#` are comments.
+//!
+//! ```text
+//! # lc3 input log
+//! 10452 w
+//! 18871 a
+//! 25130 x0A
+//! ```
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+use crate::lc3::sys::console::Console;
+
+/// A shared instruction count, see `LC3::share_clock`.
+pub type Clock = Rc<Cell<u64>>;
+
+/// A byte delivered to the program, and the instruction count it was delivered at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub at: u64,
+    pub byte: u8,
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.byte {
+            byte @ 0x21..=0x7E => write!(f, "{} {}", self.at, byte as char),
+            byte => write!(f, "{} x{:02X}", self.at, byte),
+        }
+    }
+}
+
+/// Parse a log in the format `InputEvent` displays in.
+pub fn parse_log(text: &str) -> io::Result<Vec<InputEvent>> {
+    let mut events = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("line {}: bad input event '{}'", i + 1, line));
+        let (at, byte) = line.split_once(' ').ok_or_else(invalid)?;
+        let at = at.parse().map_err(|_| invalid())?;
+        let byte = match byte.as_bytes() {
+            &[byte] if byte.is_ascii_graphic() => byte,
+            [b'x', _, _] => u8::from_str_radix(&byte[1..], 16).map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        events.push(InputEvent { at, byte });
+    }
+    Ok(events)
+}
+
+/// Passes everything through to `inner`, logging each input byte to `log`.
+pub struct RecordingConsole<C, W> {
+    inner: C,
+    clock: Clock,
+    log: W,
+}
+
+impl<C: Console> RecordingConsole<C, BufWriter<File>> {
+    /// Record to a new log file at `path`.
+    pub fn create(inner: C, clock: Clock, path: &str) -> io::Result<Self> {
+        let mut log = BufWriter::new(File::create(path)?);
+        writeln!(log, "# lc3 input log: instruction count, then the byte")?;
+        Ok(RecordingConsole::new(inner, clock, log))
+    }
+}
+
+impl<C: Console, W: Write> RecordingConsole<C, W> {
+    pub fn new(inner: C, clock: Clock, log: W) -> Self {
+        RecordingConsole { inner, clock, log }
+    }
+
+    /// Log `byte`, flushing so the log survives the VM being killed.
+    fn record(&mut self, byte: Option<u8>) -> io::Result<Option<u8>> {
+        if let Some(byte) = byte {
+            writeln!(self.log, "{}", InputEvent { at: self.clock.get(), byte })?;
+            self.log.flush()?;
+        }
+        Ok(byte)
+    }
+}
+
+impl<C: Console, W: Write> Console for RecordingConsole<C, W> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.inner.read_byte()?;
+        self.record(byte)
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.inner.poll_byte()?;
+        self.record(byte)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_bytes(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Delivers recorded input at the recorded instruction counts; output goes to `inner`,
+/// whose input is never read. Once the log runs out, input is at its end.
+pub struct ReplayConsole<C> {
+    inner: C,
+    clock: Clock,
+    events: VecDeque<InputEvent>,
+}
+
+impl<C: Console> ReplayConsole<C> {
+    pub fn new(inner: C, clock: Clock, events: Vec<InputEvent>) -> Self {
+        ReplayConsole { inner, clock, events: events.into() }
+    }
+
+    /// Replay the log file at `path`.
+    pub fn open(inner: C, clock: Clock, path: &str) -> io::Result<Self> {
+        let events = parse_log(&fs::read_to_string(path)?)?;
+        Ok(ReplayConsole::new(inner, clock, events))
+    }
+
+    /// Bytes left to deliver.
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+}
+
+impl<C: Console> Console for ReplayConsole<C> {
+    /// A blocking read was also the one that got the byte when recording, so it takes
+    /// the next byte whatever its count.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.events.pop_front().map(|event| event.byte))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.events.front() {
+            Some(event) if event.at <= self.clock.get() => Ok(self.events.pop_front().map(|event| event.byte)),
+            _ => Ok(None),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_bytes(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::hardware::Reg::RegisterEnum;
+    use crate::lc3::sys::console::{self, BufferConsole, ScriptedConsole};
+    use crate::lc3::vm::{ExitReason, LC3};
+
+    #[test]
+    fn test_log_format() {
+        let events = vec![
+            InputEvent { at: 0, byte: b'w' },
+            InputEvent { at: 12, byte: b' ' },
+            InputEvent { at: 345, byte: b'\n' },
+            InputEvent { at: 6789, byte: b'x' },
+        ];
+        let text: String = events.iter().map(|event| format!("{}\n", event)).collect();
+        assert_eq!(text, "0 w\n12 x20\n345 x0A\n6789 x\n");
+        assert_eq!(parse_log(&format!("# comment\n\n{}", text)).unwrap(), events);
+
+        let error = parse_log("1 w\n2 xZZ\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "line 2: bad input event '2 xZZ'");
+    }
+
+    /// Polls KBSR for two keys, counting polls in R2 and echoing each key.
+    const POLLING: &str = "
+        .ORIG x3000
+        AND R3, R3, #0
+        ADD R3, R3, #2
+POLL    ADD R2, R2, #1
+        LDI R1, KBSR
+        BRzp POLL
+        LDI R0, KBDR
+        OUT
+        ADD R3, R3, #-1
+        BRp POLL
+        HALT
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+        .END
+    ";
+
+    fn machine(console: impl Console + 'static, clock: Clock) -> LC3 {
+        let assembly = assemble(POLLING).unwrap();
+        let mut vm = LC3::with_console(console::shared(console));
+        vm.share_clock(clock);
+        vm.load_words(assembly.origin, &assembly.words);
+        vm
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("demovm-replay-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let clock = Clock::default();
+        let live = ScriptedConsole::new().idle(7).keys("a").idle(30).keys("b");
+        let recorder = RecordingConsole::create(live, clock.clone(), path).unwrap();
+        let mut vm = machine(recorder, clock);
+        assert_eq!(vm.run(), ExitReason::Halted);
+        let polls = vm.registers().read(RegisterEnum::R2);
+        assert_eq!(polls, 8 + 31);
+
+        let log = fs::read_to_string(path).unwrap();
+        assert_eq!(parse_log(&log).unwrap().iter().map(|event| event.byte).collect::<Vec<_>>(), b"ab");
+
+        // The replay has no live input at all, yet polls the same number of times
+        let clock = Clock::default();
+        let replay = ReplayConsole::open(BufferConsole::new(b"zz"), clock.clone(), path).unwrap();
+        let mut replayed = machine(replay, clock);
+        fs::remove_file(path).unwrap();
+        assert_eq!(replayed.run(), ExitReason::Halted);
+        assert_eq!(replayed.registers().read(RegisterEnum::R2), polls);
+        assert_eq!(replayed.registers().read(RegisterEnum::R0), b'b' as u16);
+        assert_eq!(replayed.instructions_executed(), vm.instructions_executed());
+    }
+}
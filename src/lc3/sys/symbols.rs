@@ -0,0 +1,182 @@
+//! Label names for addresses, read from and written to lc3as-style `.sym` files:
+//!
+//! ```text
+//! // Symbol table
+//! // Scope level 0:
+//! //	Symbol Name       Page Address
+//! //	----------------  ------------
+//! //	LOOP              3004
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Addresses further than this past the nearest label are shown as plain hex.
+pub const MAX_SYMBOL_OFFSET: u16 = 0x100;
+
+/// Labels and their addresses, searchable both ways.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    /// Every label in the order it was added.
+    entries: Vec<(String, u16)>,
+    addresses: HashMap<String, u16>,
+    /// First label added for each address.
+    names: BTreeMap<u16, usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `name` at `address`, replacing an earlier definition of the same name.
+    pub fn insert(&mut self, name: &str, address: u16) {
+        if self.addresses.insert(name.to_string(), address).is_some() {
+            self.entries.retain(|(n, _)| n != name);
+            self.reindex();
+        }
+        self.entries.push((name.to_string(), address));
+        self.names.entry(address).or_insert(self.entries.len() - 1);
+    }
+
+    /// Add every symbol of `other`.
+    pub fn extend(&mut self, other: &SymbolTable) {
+        for (name, address) in other.iter() {
+            self.insert(name, address);
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.names.clear();
+        for (i, (_, address)) in self.entries.iter().enumerate() {
+            self.names.entry(*address).or_insert(i);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Labels in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.entries.iter().map(|(name, address)| (name.as_str(), *address))
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    /// The label at exactly `address`.
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(|&i| self.entries[i].0.as_str())
+    }
+
+    /// The closest label at or below `address`, with the distance to it.
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        let (&base, &i) = self.names.range(..=address).next_back()?;
+        let offset = address - base;
+        (offset <= MAX_SYMBOL_OFFSET).then(|| (self.entries[i].0.as_str(), offset))
+    }
+
+    /// `LOOP`, `LOOP+3`, or `x3007` when no label is close enough.
+    pub fn describe(&self, address: u16) -> String {
+        match self.nearest(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    /// Parse the contents of a `.sym` file. Header and separator lines are skipped.
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.trim();
+            let line = line.strip_prefix("//").unwrap_or(line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, address] = fields[..] else { continue };
+            let is_label = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_label {
+                continue;
+            }
+            // "Symbol Table" and the like have the right shape but no hex address
+            let Ok(address) = u16::from_str_radix(address.trim_start_matches(['x', 'X']), 16) else {
+                continue;
+            };
+            table.insert(name, address);
+        }
+        table
+    }
+
+    /// The table in lc3as `.sym` format.
+    pub fn to_sym_string(&self) -> String {
+        let mut out = String::from("// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n");
+        for (name, address) in self.iter() {
+            let _ = writeln!(out, "//\t{:<16}  {:04X}", name, address);
+        }
+        out.push('\n');
+        out
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<SymbolTable> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_sym_string())
+    }
+}
+
+impl<S: AsRef<str>> FromIterator<(S, u16)> for SymbolTable {
+    fn from_iter<I: IntoIterator<Item = (S, u16)>>(iter: I) -> Self {
+        let mut table = SymbolTable::new();
+        for (name, address) in iter {
+            table.insert(name.as_ref(), address);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let table: SymbolTable = [("START", 0x3000), ("LOOP", 0x3004)].into_iter().collect();
+        assert_eq!(table.describe(0x3000), "START");
+        assert_eq!(table.describe(0x3002), "START+2");
+        assert_eq!(table.describe(0x3007), "LOOP+3");
+        assert_eq!(table.describe(0x2FFF), "x2FFF");
+        assert_eq!(table.describe(0x3004 + MAX_SYMBOL_OFFSET + 1), "x3105");
+        assert_eq!(table.address_of("LOOP"), Some(0x3004));
+        assert_eq!(table.name_at(0x3004), Some("LOOP"));
+    }
+
+    #[test]
+    fn test_sym_format_round_trip() {
+        let table: SymbolTable = [("MAIN", 0x3000), ("DATA_1", 0x30A2)].into_iter().collect();
+        let text = table.to_sym_string();
+        assert!(text.contains("//\tDATA_1            30A2\n"));
+        assert_eq!(SymbolTable::parse(&text), table);
+    }
+
+    #[test]
+    fn test_redefinition_replaces() {
+        let mut table = SymbolTable::new();
+        table.insert("A", 0x3000);
+        table.insert("B", 0x3000);
+        table.insert("A", 0x4000);
+        assert_eq!(table.name_at(0x3000), Some("B"));
+        assert_eq!(table.name_at(0x4000), Some("A"));
+        assert_eq!(table.len(), 2);
+    }
+}
@@ -1,21 +1,30 @@
 use std::{
     error::Error,
-    io::{Read, Write},
+    io::{self, Read, Write},
     process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
     thread,
 };
 
 use libc::STDIN_FILENO;
 use signal_hook::{iterator::Signals, SIGINT};
 use termios::{
-    cfmakeraw, tcsetattr, Termios, TCSANOW,
+    tcsetattr, Termios, ECHO, ICANON, TCSANOW,
 };
 
+/// Terminal settings in effect before `turn_off_canonical_and_echo_modes`.
+static ORIGINAL_SETTINGS: OnceLock<Termios> = OnceLock::new();
 
+/// Switch stdin to unbuffered, no-echo input so single keypresses reach the VM.
+/// `ISIG` is left alone so `Ctrl+C` still raises SIGINT.
 pub fn turn_off_canonical_and_echo_modes() -> Result<(), Box<dyn Error>> {
     let fd = STDIN_FILENO;
     let mut termios = Termios::from_fd(fd)?;
-    cfmakeraw(&mut termios); 
+    let _ = ORIGINAL_SETTINGS.set(termios);
+    termios.c_lflag &= !(ICANON | ECHO);
     tcsetattr(fd, TCSANOW, &termios)?;
     Ok(())
 }
@@ -23,12 +32,68 @@ pub fn turn_off_canonical_and_echo_modes() -> Result<(), Box<dyn Error>> {
 
 pub fn restore_terminal_settings() -> Result<(), Box<dyn Error>> {
     let fd = STDIN_FILENO;
-    let mut termios = Termios::from_fd(fd)?;
-    termios.c_lflag |= termios::ICANON | termios::ECHO;
+    let termios = match ORIGINAL_SETTINGS.get() {
+        Some(original) => *original,
+        None => {
+            let mut termios = Termios::from_fd(fd)?;
+            termios.c_lflag |= ICANON | ECHO;
+            termios
+        }
+    };
     tcsetattr(fd, TCSANOW, &termios)?;
     Ok(())
 }
 
+/// Non-blocking check for pending input on stdin.
+pub fn check_key() -> bool {
+    let mut fds = libc::pollfd {
+        fd: STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fds` is a valid pollfd and the count matches; a zero timeout never blocks.
+    let ready = unsafe { libc::poll(&mut fds, 1, 0) };
+    ready > 0 && fds.revents & libc::POLLIN != 0
+}
+
+/// Read a single byte straight from the stdin file descriptor, blocking until one arrives.
+/// Returns `Ok(None)` at end of input.
+///
+/// This bypasses std's buffered `Stdin` on purpose: bytes sitting in that buffer would be
+/// invisible to `check_key`.
+pub fn get_char() -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    loop {
+        // SAFETY: reads at most one byte into a live, writable one-byte buffer.
+        let n = unsafe { libc::read(STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        match n {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Read one line from stdin through `get_char`, without the trailing newline, so bytes
+/// after it are left for the program being debugged. Returns `Ok(None)` at end of input.
+pub fn read_line() -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        match get_char()? {
+            Some(b'\n') => break,
+            Some(byte) => line.push(byte),
+            None if line.is_empty() => return Ok(None),
+            None => break,
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string()))
+}
+
 /// Cleanly handle `Ctrl+C` (SIGINT).
 /// - Restore the terminal settings.
 /// - Print a message and exit with code 130.
@@ -48,3 +113,19 @@ pub fn spawn_control_c_handler() -> Result<(), Box<dyn Error>> {
     });
     Ok(())
 }
+
+/// Like `spawn_control_c_handler`, but the first `Ctrl+C` only sets the returned flag so
+/// the caller can wind down; a second one exits straight away.
+pub fn spawn_control_c_flag() -> Result<Arc<AtomicBool>, Box<dyn Error>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let signals = Signals::new(&[SIGINT])?;
+    let raised = Arc::clone(&flag);
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            if raised.swap(true, Ordering::SeqCst) {
+                handle_control_c(sig);
+            }
+        }
+    });
+    Ok(flag)
+}
@@ -0,0 +1,84 @@
+//! Instruction throughput measurement behind `demoVM bench`.
+
+use std::time::{Duration, Instant};
+
+use crate::lc3::vm::{ExitReason, LC3};
+
+/// A compute-heavy loop that never halts: sums and doubles a table of 64 words over
+/// and over. Mostly ALU work, with a load and a store per element.
+pub const WORKLOAD: &str = "
+        .ORIG x3000
+START   AND R5, R5, #0
+OUTER   LEA R1, TABLE
+        LD  R2, COUNT
+        AND R3, R3, #0
+SUM     LDR R4, R1, #0
+        ADD R3, R3, R4
+        ADD R4, R4, #1
+        ADD R4, R4, R4
+        STR R4, R1, #0
+        ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp SUM
+        NOT R3, R3
+        ADD R5, R5, R3
+        BRnzp OUTER
+COUNT   .FILL #64
+TABLE   .BLKW #64
+        .END
+";
+
+/// The outcome of `measure`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    /// Instructions executed.
+    pub instructions: u64,
+    pub elapsed: Duration,
+    /// Why the run ended; `StepLimitReached` if the program used up the whole budget.
+    pub exit: ExitReason,
+}
+
+impl Measurement {
+    /// Millions of instructions per second.
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON) / 1e6
+    }
+}
+
+/// Run `vm` for at most `limit` instructions and time it.
+pub fn measure(vm: &mut LC3, limit: u64) -> Measurement {
+    let before = vm.instructions_executed();
+    let start = Instant::now();
+    let exit = vm.run_for(limit);
+    let elapsed = start.elapsed();
+    Measurement { instructions: vm.instructions_executed() - before, elapsed, exit }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+
+    #[test]
+    fn test_measure_workload() {
+        let assembly = assemble(WORKLOAD).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+
+        let measurement = measure(&mut vm, 10_000);
+        assert_eq!(measurement.instructions, 10_000);
+        assert_eq!(measurement.exit, ExitReason::StepLimitReached);
+    }
+
+    #[test]
+    fn test_measure_counts_up_to_halt() {
+        // ADD R0, R0, #1 (x3) ; HALT
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(0x3000, &[0x1021, 0x1021, 0x1021, 0xF025]);
+
+        let measurement = measure(&mut vm, 100);
+        assert_eq!(measurement.exit, ExitReason::Halted);
+        assert_eq!(measurement.instructions, 4);
+    }
+}
@@ -0,0 +1,259 @@
+//! Code coverage: which words ran, which were read or written as data, and which ways
+//! each conditional branch went, mapped back to source lines through an `Assembly`.
+//!
+//! A conditional BR (one with some but not all of `n`, `z` and `p`) has two directions:
+//! taken, when the condition codes match, and falling through. Reports come as text for
+//! people and as lcov tracefiles for `genhtml` and other viewers; in lcov each branch is
+//! block 0, with branch 0 taken and branch 1 falling through.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::lc3::asm::Assembly;
+use crate::lc3::cpu::disasm::disassemble;
+use crate::lc3::hardware::Memory::{MemoryAccess, MEMORY_SIZE};
+use crate::lc3::sys::symbols::SymbolTable;
+use crate::lc3::vm::StepInfo;
+
+const READ: u8 = 1;
+const WRITTEN: u8 = 2;
+
+/// How often a conditional branch went each way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Branch {
+    pub taken: u64,
+    pub fell_through: u64,
+}
+
+/// Collects coverage from the instructions a machine executes, see `LC3::set_coverage`.
+pub struct Coverage {
+    executed: Vec<u64>,
+    /// `READ` and `WRITTEN` bits per address.
+    accessed: Vec<u8>,
+    branches: HashMap<u16, Branch>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `word` is a BR that can go either way.
+fn is_conditional_branch(word: u16) -> bool {
+    let nzp = (word >> 9) & 0x7;
+    word >> 12 == 0 && nzp != 0 && nzp != 0x7
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { executed: vec![0; MEMORY_SIZE], accessed: vec![0; MEMORY_SIZE], branches: HashMap::new() }
+    }
+
+    /// Count the instruction `info` describes; `cond` is the COND register after it.
+    pub fn record(&mut self, info: &StepInfo, cond: u16) {
+        self.executed[info.pc_before as usize] += 1;
+        for access in &info.memory {
+            match *access {
+                MemoryAccess::Read { address, .. } => self.accessed[address as usize] |= READ,
+                MemoryAccess::Write { address, .. } => self.accessed[address as usize] |= WRITTEN,
+            }
+        }
+        // BR leaves COND alone, unless an interrupt was entered right after it
+        if is_conditional_branch(info.instr) && info.interrupt.is_none() {
+            let branch = self.branches.entry(info.pc_before).or_default();
+            match (info.instr >> 9) & cond & 0x7 {
+                0 => branch.fell_through += 1,
+                _ => branch.taken += 1,
+            }
+        }
+    }
+
+    /// Times the word at `address` ran as an instruction.
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executed[address as usize]
+    }
+
+    pub fn was_read(&self, address: u16) -> bool {
+        self.accessed[address as usize] & READ != 0
+    }
+
+    pub fn was_written(&self, address: u16) -> bool {
+        self.accessed[address as usize] & WRITTEN != 0
+    }
+
+    /// Which ways the conditional branch at `address` went.
+    pub fn branch(&self, address: u16) -> Branch {
+        self.branches.get(&address).copied().unwrap_or_default()
+    }
+
+    /// An lcov tracefile record for the source at `path`, assembled into `assembly`.
+    pub fn lcov(&self, path: &str, assembly: &Assembly) -> String {
+        let mut out = format!("TN:\nSF:{}\n", path);
+        let (mut branches, mut branches_hit) = (0, 0);
+        for &(line, address) in &assembly.lines {
+            if !is_conditional_branch(word_at(assembly, address)) {
+                continue;
+            }
+            let branch = self.branch(address);
+            for (number, count) in [(0, branch.taken), (1, branch.fell_through)] {
+                match self.executions(address) {
+                    0 => _ = writeln!(out, "BRDA:{},0,{},-", line, number),
+                    _ => _ = writeln!(out, "BRDA:{},0,{},{}", line, number, count),
+                }
+                branches += 1;
+                branches_hit += (count > 0) as usize;
+            }
+        }
+        let _ = writeln!(out, "BRF:{}\nBRH:{}", branches, branches_hit);
+        for &(line, address) in &assembly.lines {
+            let _ = writeln!(out, "DA:{},{}", line, self.executions(address));
+        }
+        let lines_hit = assembly.lines.iter().filter(|&&(_, address)| self.executions(address) > 0).count();
+        let _ = writeln!(out, "LF:{}\nLH:{}\nend_of_record", assembly.lines.len(), lines_hit);
+        out
+    }
+
+    /// A summary for the source at `path`, listing the lines never executed, the
+    /// branches that only went one way and the data never read or written.
+    pub fn report(&self, path: &str, assembly: &Assembly) -> String {
+        let symbols: SymbolTable = assembly.symbols.iter().map(|(name, address)| (name, *address)).collect();
+        let source = |address: u16| disassemble(word_at(assembly, address), address).with_symbols(&symbols).to_string();
+        let percent = |hit: usize, of: usize| 100.0 * hit as f64 / of.max(1) as f64;
+
+        let unexecuted: Vec<_> = assembly.lines.iter().filter(|&&(_, address)| self.executions(address) == 0).collect();
+        let mut one_way = Vec::new();
+        let mut directions = (0, 0);
+        for &(line, address) in &assembly.lines {
+            if !is_conditional_branch(word_at(assembly, address)) {
+                continue;
+            }
+            let branch = self.branch(address);
+            directions.0 += (branch.taken > 0) as usize + (branch.fell_through > 0) as usize;
+            directions.1 += 2;
+            match (self.executions(address), branch.taken, branch.fell_through) {
+                (0, ..) => {}
+                (_, 0, _) => one_way.push((line, address, "never took the branch")),
+                (_, _, 0) => one_way.push((line, address, "never fell through")),
+                _ => {}
+            }
+        }
+        let untouched: Vec<_> = assembly
+            .data
+            .iter()
+            .filter(|&&(_, address, length)| {
+                (0..length).all(|i| !self.was_read(address.wrapping_add(i)) && !self.was_written(address.wrapping_add(i)))
+            })
+            .collect();
+
+        let lines_hit = assembly.lines.len() - unexecuted.len();
+        let data_hit = assembly.data.len() - untouched.len();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}: {} of {} lines executed ({:.1}%), {} of {} branch directions taken ({:.1}%), {} of {} data lines read or written",
+            path,
+            lines_hit,
+            assembly.lines.len(),
+            percent(lines_hit, assembly.lines.len()),
+            directions.0,
+            directions.1,
+            percent(directions.0, directions.1),
+            data_hit,
+            assembly.data.len()
+        );
+        if !unexecuted.is_empty() {
+            let _ = writeln!(out, "Never executed:");
+            for &&(line, address) in &unexecuted {
+                let _ = writeln!(out, "  line {}: {}", line, source(address));
+            }
+        }
+        if !one_way.is_empty() {
+            let _ = writeln!(out, "Branches that only went one way:");
+            for (line, address, missing) in one_way {
+                let _ = writeln!(out, "  line {}: {} {}", line, source(address), missing);
+            }
+        }
+        if !untouched.is_empty() {
+            let _ = writeln!(out, "Data never read or written:");
+            for &&(line, address, length) in &untouched {
+                let _ = writeln!(out, "  line {}: {} ({} words)", line, symbols.describe(address), length);
+            }
+        }
+        out
+    }
+}
+
+/// The word `assembly` put at `address`.
+fn word_at(assembly: &Assembly, address: u16) -> u16 {
+    assembly.words[address.wrapping_sub(assembly.origin) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::{ExitReason, LC3};
+
+    /// Counts COUNT down to zero; the negative branch and the error path never run.
+    const PROGRAM: &str = ".ORIG x3000
+        LD R0, COUNT
+LOOP    ADD R0, R0, #-1
+        BRn ERROR
+        BRp LOOP
+        ST R0, RESULT
+        HALT
+ERROR   HALT
+COUNT   .FILL #3
+RESULT  .BLKW 1
+SPARE   .BLKW 2
+        .END";
+
+    fn covered() -> (Assembly, Coverage) {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+        vm.set_coverage(Some(Coverage::new()));
+        assert_eq!(vm.run(), ExitReason::Halted);
+        (assembly, vm.set_coverage(None).unwrap())
+    }
+
+    #[test]
+    fn test_counts() {
+        let (assembly, coverage) = covered();
+        let brn = assembly.instruction_at_line(4).unwrap().1;
+        let brp = assembly.instruction_at_line(5).unwrap().1;
+        assert_eq!(coverage.executions(brn), 3);
+        assert_eq!(coverage.branch(brn), Branch { taken: 0, fell_through: 3 });
+        assert_eq!(coverage.branch(brp), Branch { taken: 2, fell_through: 1 });
+        let count = assembly.symbol("COUNT").unwrap();
+        let result = assembly.symbol("RESULT").unwrap();
+        assert!(coverage.was_read(count) && !coverage.was_written(count));
+        assert!(coverage.was_written(result) && !coverage.was_read(result));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (assembly, coverage) = covered();
+        assert_eq!(
+            coverage.lcov("count.asm", &assembly),
+            "TN:\nSF:count.asm\n\
+             BRDA:4,0,0,0\nBRDA:4,0,1,3\nBRDA:5,0,0,2\nBRDA:5,0,1,1\nBRF:4\nBRH:3\n\
+             DA:2,1\nDA:3,3\nDA:4,3\nDA:5,3\nDA:6,1\nDA:7,1\nDA:8,0\n\
+             LF:7\nLH:6\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let (assembly, coverage) = covered();
+        assert_eq!(
+            coverage.report("count.asm", &assembly),
+            "count.asm: 6 of 7 lines executed (85.7%), 3 of 4 branch directions taken (75.0%), 2 of 3 data lines read or written\n\
+             Never executed:\n  line 8: HALT\n\
+             Branches that only went one way:\n  line 4: BRn ERROR never took the branch\n\
+             Data never read or written:\n  line 11: SPARE (2 words)\n"
+        );
+    }
+}
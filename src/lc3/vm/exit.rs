@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+
+/// Why `LC3::run` handed control back to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The program executed `TRAP x25` (HALT).
+    Halted,
+    /// The instruction word `instr` fetched from `pc` has no defined behaviour, and no
+    /// illegal opcode handler is installed.
+    IllegalOpcode(u16, u16),
+    /// RTI was executed in user mode at `pc`, and no privilege mode violation handler is installed.
+    PrivilegeViolation(u16),
+    /// The user-mode instruction at `pc` touched the protected `address`, and no access
+    /// control violation handler is installed.
+    AccessViolation(u16, u16),
+    /// A TRAP was executed with a vector that has no service routine.
+    UnknownTrap(u8),
+    /// The configured step limit ran out before the program stopped.
+    StepLimitReached,
+    /// Execution stopped in front of the instruction at a breakpoint address.
+    Breakpoint(u16),
+    /// The console could not be read from or written to.
+    IoError(io::ErrorKind),
+}
+
+impl ExitReason {
+    /// Address of the instruction the machine stopped at, for reasons that have one.
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            ExitReason::IllegalOpcode(pc, _)
+            | ExitReason::PrivilegeViolation(pc)
+            | ExitReason::AccessViolation(pc, _)
+            | ExitReason::Breakpoint(pc) => Some(pc),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ExitReason {
+    fn from(err: io::Error) -> Self {
+        ExitReason::IoError(err.kind())
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Halted => write!(f, "halted"),
+            ExitReason::IllegalOpcode(pc, instr) => {
+                write!(f, "illegal opcode {:#06X} at {:#06X}", instr, pc)
+            }
+            ExitReason::PrivilegeViolation(pc) => {
+                write!(f, "privilege mode violation at {:#06X}", pc)
+            }
+            ExitReason::AccessViolation(pc, address) => {
+                write!(f, "access control violation at {:#06X} (address {:#06X})", pc, address)
+            }
+            ExitReason::UnknownTrap(vector) => write!(f, "unknown TRAP vector {:#04X}", vector),
+            ExitReason::StepLimitReached => write!(f, "step limit reached"),
+            ExitReason::Breakpoint(addr) => write!(f, "breakpoint at {:#06X}", addr),
+            ExitReason::IoError(kind) => write!(f, "console I/O error: {}", kind),
+        }
+    }
+}
@@ -1,4 +1,14 @@
+pub mod bench;
+pub mod coverage;
+pub mod exit;
+pub mod profile;
+pub mod snapshot;
+pub mod step;
+pub mod trace;
 pub mod vm;
 
 // Re-export the LC3 struct
+pub use exit::ExitReason;
+pub use snapshot::Snapshot;
+pub use step::StepInfo;
 pub use vm::LC3;
@@ -0,0 +1,379 @@
+//! Where the instructions go: counts per address and per opcode, and a call graph.
+//!
+//! Calls are JSR and JSRR; a JMP (RET included) or RTI that lands on the return address
+//! of a routine on the call stack returns from it, and from anything it called. Each
+//! instruction counts toward the routine it ran in (exclusive) and toward every routine
+//! on the stack below it (inclusive), recursive routines only once. The routine the
+//! profile started in is the root of the call graph.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::lc3::cpu::disasm::disassemble;
+use crate::lc3::cpu::opcode::OpCode;
+use crate::lc3::hardware::Memory::MEMORY_SIZE;
+use crate::lc3::sys::symbols::SymbolTable;
+use crate::lc3::vm::{StepInfo, LC3};
+
+/// What a routine cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RoutineProfile {
+    /// Entry address.
+    pub address: u16,
+    pub calls: u64,
+    /// Instructions run in the routine and everything it called.
+    pub inclusive: u64,
+    /// Instructions run in the routine itself.
+    pub exclusive: u64,
+}
+
+/// Calls from one routine to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CallEdge {
+    pub caller: u16,
+    pub callee: u16,
+    pub calls: u64,
+    /// Instructions the callee ran, inclusive, when called from here.
+    pub instructions: u64,
+}
+
+/// A routine on the call stack.
+struct Frame {
+    routine: u16,
+    /// Where the call returns to; `None` for the root.
+    return_address: Option<u16>,
+    /// Instructions counted before the routine's first one.
+    entered: u64,
+}
+
+/// A node of the call tree folded stacks are counted in.
+struct Node {
+    routine: u16,
+    parent: usize,
+    children: BTreeMap<u16, usize>,
+    /// Instructions run with exactly this stack.
+    count: u64,
+}
+
+/// Collects a profile from the instructions a machine executes, see `LC3::set_profiler`.
+pub struct Profiler {
+    total: u64,
+    by_address: Vec<u64>,
+    by_opcode: [u64; 16],
+    routines: BTreeMap<u16, RoutineProfile>,
+    edges: BTreeMap<(u16, u16), CallEdge>,
+    stack: Vec<Frame>,
+    /// Activations of each routine and each call edge on the stack, so recursion
+    /// counts once.
+    active: HashMap<u16, u32>,
+    active_edges: HashMap<(u16, u16), u32>,
+    nodes: Vec<Node>,
+    /// The call tree node of the running routine.
+    current: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            total: 0,
+            by_address: vec![0; MEMORY_SIZE],
+            by_opcode: [0; 16],
+            routines: BTreeMap::new(),
+            edges: BTreeMap::new(),
+            stack: Vec::new(),
+            active: HashMap::new(),
+            active_edges: HashMap::new(),
+            nodes: Vec::new(),
+            current: 0,
+        }
+    }
+
+    /// Count the instruction `info` describes.
+    pub fn record(&mut self, info: &StepInfo) {
+        let pc = info.pc_before;
+        if self.stack.is_empty() {
+            self.nodes.push(Node { routine: pc, parent: 0, children: BTreeMap::new(), count: 0 });
+            self.enter(pc, None);
+        }
+        self.total += 1;
+        self.by_address[pc as usize] += 1;
+        self.by_opcode[(info.instr >> 12) as usize] += 1;
+        let routine = self.stack.last().map_or(pc, |frame| frame.routine);
+        self.routines.entry(routine).or_default().exclusive += 1;
+        self.nodes[self.current].count += 1;
+
+        // With an interrupt taken PC is its service routine, and the call is lost
+        if info.exit.is_some() || info.interrupt.is_some() {
+            return;
+        }
+        match OpCode::get(info.instr >> 12) {
+            Ok(OpCode::Jsr) => {
+                let edge = self.edges.entry((routine, info.pc_after)).or_default();
+                edge.calls += 1;
+                self.routines.entry(info.pc_after).or_default().calls += 1;
+                self.enter(info.pc_after, Some(pc.wrapping_add(1)));
+            }
+            Ok(OpCode::Jmp | OpCode::Rti) => {
+                let returned = self.stack.iter().rposition(|frame| frame.return_address == Some(info.pc_after));
+                if let Some(depth) = returned {
+                    while self.stack.len() > depth {
+                        self.leave();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, routine: u16, return_address: Option<u16>) {
+        *self.active.entry(routine).or_default() += 1;
+        if let Some(caller) = self.stack.last() {
+            *self.active_edges.entry((caller.routine, routine)).or_default() += 1;
+        }
+        self.routines.entry(routine).or_default().address = routine;
+        if return_address.is_some() {
+            let next = self.nodes.len();
+            let child = *self.nodes[self.current].children.entry(routine).or_insert(next);
+            if child == next {
+                self.nodes.push(Node { routine, parent: self.current, children: BTreeMap::new(), count: 0 });
+            }
+            self.current = child;
+        }
+        self.stack.push(Frame { routine, return_address, entered: self.total });
+    }
+
+    fn leave(&mut self) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        let spent = self.total - frame.entered;
+        let active = self.active.entry(frame.routine).or_default();
+        *active -= 1;
+        if *active == 0 {
+            self.routines.entry(frame.routine).or_default().inclusive += spent;
+        }
+        if let Some(caller) = self.stack.last() {
+            let edge = (caller.routine, frame.routine);
+            let active = self.active_edges.entry(edge).or_default();
+            *active -= 1;
+            if *active == 0 {
+                self.edges.entry(edge).or_default().instructions += spent;
+            }
+        }
+        self.current = self.nodes[self.current].parent;
+    }
+
+    /// Instructions counted.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Times the instruction at `address` ran.
+    pub fn count_at(&self, address: u16) -> u64 {
+        self.by_address[address as usize]
+    }
+
+    pub fn opcode_count(&self, opcode: OpCode) -> u64 {
+        self.by_opcode[opcode as usize]
+    }
+
+    /// Every routine seen, by address. Routines still running count up to now.
+    pub fn routines(&self) -> Vec<RoutineProfile> {
+        let mut routines = self.routines.clone();
+        let mut seen = HashSet::new();
+        for frame in &self.stack {
+            if seen.insert(frame.routine) {
+                routines.entry(frame.routine).or_default().inclusive += self.total - frame.entered;
+            }
+        }
+        routines.into_values().collect()
+    }
+
+    /// Every caller and callee pair seen. Calls still running count up to now.
+    pub fn calls(&self) -> Vec<CallEdge> {
+        let mut edges = self.edges.clone();
+        let mut seen = HashSet::new();
+        for pair in self.stack.windows(2) {
+            let edge = (pair[0].routine, pair[1].routine);
+            if seen.insert(edge) {
+                edges.entry(edge).or_default().instructions += self.total - pair[1].entered;
+            }
+        }
+        edges.into_iter().map(|((caller, callee), edge)| CallEdge { caller, callee, ..edge }).collect()
+    }
+
+    /// One line per call stack, `ROOT;CALLER;CALLEE count`, counting the instructions run
+    /// with exactly that stack: the folded format flame graph tools read.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        let mut pending = match self.nodes.is_empty() {
+            true => vec![],
+            false => vec![(0, symbols.describe(self.nodes[0].routine))],
+        };
+        while let Some((node, stack)) = pending.pop() {
+            let node = &self.nodes[node];
+            if node.count > 0 {
+                let _ = writeln!(out, "{} {}", stack, node.count);
+            }
+            for &child in node.children.values().rev() {
+                pending.push((child, format!("{};{}", stack, symbols.describe(self.nodes[child].routine))));
+            }
+        }
+        out
+    }
+
+    /// A text report on `vm`'s run: opcodes, the `top` hottest addresses, routines and
+    /// calls, labelled from the machine's symbols.
+    pub fn report(&self, vm: &LC3, top: usize) -> String {
+        let symbols = vm.symbols();
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(out, "{} instructions", self.total);
+
+        let _ = writeln!(out, "\nOpcodes");
+        let mut opcodes: Vec<(OpCode, u64)> =
+            (0..16).filter_map(|op| OpCode::get(op).ok()).map(|op| (op, self.opcode_count(op))).collect();
+        opcodes.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        for (opcode, count) in opcodes.into_iter().filter(|&(_, count)| count > 0) {
+            let name = format!("{:?}", opcode).to_uppercase();
+            let _ = writeln!(out, "  {:<6} {:>12} {:>6.1}%", name, count, percent(count));
+        }
+
+        let _ = writeln!(out, "\nHot spots");
+        let mut addresses: Vec<u16> = (0..=u16::MAX).filter(|&address| self.count_at(address) > 0).collect();
+        addresses.sort_by_key(|&address| std::cmp::Reverse(self.count_at(address)));
+        for address in addresses.into_iter().take(top) {
+            let count = self.count_at(address);
+            let word = vm.memory().peek(address as usize);
+            let instruction = disassemble(word, address);
+            let _ = writeln!(
+                out,
+                "  x{:04X}  {:<16} {:>12} {:>6.1}%  {}",
+                address,
+                symbols.describe(address),
+                count,
+                percent(count),
+                instruction.with_symbols(symbols)
+            );
+        }
+
+        let _ = writeln!(out, "\nRoutines                         calls    inclusive    exclusive");
+        let mut routines = self.routines();
+        routines.sort_by_key(|routine| std::cmp::Reverse(routine.inclusive));
+        for routine in routines {
+            let _ = writeln!(
+                out,
+                "  {:<24} {:>10} {:>12} {:>12}",
+                symbols.describe(routine.address),
+                routine.calls,
+                routine.inclusive,
+                routine.exclusive
+            );
+        }
+
+        let _ = writeln!(out, "\nCalls                                    calls instructions");
+        for edge in self.calls() {
+            let pair = format!("{} -> {}", symbols.describe(edge.caller), symbols.describe(edge.callee));
+            let _ = writeln!(out, "  {:<32} {:>10} {:>12}", pair, edge.calls, edge.instructions);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::asm::assemble;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::ExitReason;
+
+    /// MAIN calls TWICE twice, which calls ONCE each time; FACT recurses three deep.
+    const PROGRAM: &str = "
+        .ORIG x3000
+MAIN    JSR TWICE
+        JSR TWICE
+        AND R0, R0, #0
+        ADD R0, R0, #3
+        JSR FACT
+        HALT
+TWICE   ST R7, SAVE
+        JSR ONCE
+        JSR ONCE
+        LD R7, SAVE
+        RET
+ONCE    ADD R1, R1, #1
+        RET
+FACT    ADD R6, R6, #-1
+        STR R7, R6, #0
+        ADD R0, R0, #-1
+        BRz DONE
+        JSR FACT
+DONE    LDR R7, R6, #0
+        ADD R6, R6, #1
+        RET
+SAVE    .BLKW 1
+        .END
+    ";
+
+    fn profiled() -> (LC3, Profiler) {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(assembly.origin, &assembly.words);
+        for (name, address) in &assembly.symbols {
+            vm.symbols_mut().insert(name, *address);
+        }
+        vm.set_profiler(Some(Profiler::new()));
+        assert_eq!(vm.run(), ExitReason::Halted);
+        let profiler = vm.set_profiler(None).unwrap();
+        (vm, profiler)
+    }
+
+    fn routine(profiler: &Profiler, vm: &LC3, name: &str) -> RoutineProfile {
+        let address = vm.symbols().address_of(name).unwrap();
+        profiler.routines().into_iter().find(|routine| routine.address == address).unwrap()
+    }
+
+    #[test]
+    fn test_counts() {
+        let (vm, profiler) = profiled();
+        // MAIN 6, TWICE 5 x 2, ONCE 2 x 4, FACT 8 + 8 + 7
+        assert_eq!(profiler.total(), 6 + 10 + 8 + 23);
+        assert_eq!(profiler.count_at(vm.symbols().address_of("ONCE").unwrap()), 4);
+        assert_eq!(profiler.opcode_count(OpCode::Jsr), 3 + 4 + 2);
+
+        let once = routine(&profiler, &vm, "ONCE");
+        assert_eq!((once.calls, once.inclusive, once.exclusive), (4, 8, 8));
+        let twice = routine(&profiler, &vm, "TWICE");
+        assert_eq!((twice.calls, twice.inclusive, twice.exclusive), (2, 18, 10));
+        // Recursion counts each instruction once
+        let fact = routine(&profiler, &vm, "FACT");
+        assert_eq!((fact.calls, fact.inclusive, fact.exclusive), (3, 23, 23));
+        let main = routine(&profiler, &vm, "MAIN");
+        assert_eq!((main.inclusive, main.exclusive), (profiler.total(), 6));
+
+        let recursion = profiler.calls().into_iter().find(|edge| edge.caller == fact.address).unwrap();
+        assert_eq!((recursion.callee, recursion.calls, recursion.instructions), (fact.address, 2, 8 + 7));
+        let twice_once = profiler.calls().into_iter().find(|edge| edge.callee == once.address).unwrap();
+        assert_eq!((twice_once.caller, twice_once.calls, twice_once.instructions), (twice.address, 4, 8));
+    }
+
+    #[test]
+    fn test_folded_and_report() {
+        let (vm, profiler) = profiled();
+        assert_eq!(
+            profiler.folded(vm.symbols()),
+            "MAIN 6\nMAIN;TWICE 10\nMAIN;TWICE;ONCE 8\nMAIN;FACT 8\nMAIN;FACT;FACT 8\nMAIN;FACT;FACT;FACT 7\n"
+        );
+        let report = profiler.report(&vm, 3);
+        assert!(report.starts_with("47 instructions\n\nOpcodes\n  ADD "));
+        assert!(report.contains("\nHot spots\n  x300B  ONCE "));
+        assert!(report.contains("  FACT -> FACT                              2           15\n"));
+        assert!(report.contains("  TWICE -> ONCE                             4            8\n"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Whole-machine snapshots, in memory and on disk.
+//!
+//! A snapshot holds everything `run` depends on: the register file and PSR, both saved
+//! stack pointers, all of RAM, the internal state of the memory-mapped devices, pending
+//! interrupts, the trap mode and the instruction counter. Breakpoints, symbols, the step
+//! limit and the console are the host's business and stay as they are on restore.
+//!
+//! On disk a snapshot is a sequence of big-endian words, like an image file:
+//!
+//! ```text
+//! "LC3SNAP\0"  magic
+//! u16          format version (1)
+//! u64          instructions executed
+//! u16          trap mode: 0 fast, 1 os, 2 legacy-os
+//! u16 x 13     R0-R7, PC, COND, PSR, saved SSP, saved USP
+//! u16          pending interrupt count, then (vector, priority) per interrupt
+//! u16          device count, then (address, length, words...) per device
+//! u16 x 65536  RAM
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::lc3::cpu::interrupt::InterruptRequest;
+use crate::lc3::cpu::trap::TrapMode;
+use crate::lc3::hardware::Memory::MEMORY_SIZE;
+use crate::lc3::hardware::Reg::Registers;
+
+const MAGIC: &[u8; 8] = b"LC3SNAP\0";
+const VERSION: u16 = 1;
+
+/// The state of a machine at one point in time, see `LC3::snapshot`.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub registers: Registers,
+    /// All of RAM, including words hidden under device registers.
+    pub ram: Vec<u16>,
+    /// Device state keyed by the first address the device answers for.
+    pub devices: Vec<(u16, Vec<u16>)>,
+    /// Interrupts the host raised that have not been taken yet.
+    pub interrupts: Vec<InterruptRequest>,
+    pub trap_mode: TrapMode,
+    pub instructions: u64,
+}
+
+impl Snapshot {
+    /// Read a snapshot file written by `save`.
+    pub fn load(path: &str) -> io::Result<Snapshot> {
+        Snapshot::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_u64::<BigEndian>(self.instructions)?;
+        writer.write_u16::<BigEndian>(match self.trap_mode {
+            TrapMode::Fast => 0,
+            TrapMode::Os => 1,
+            TrapMode::LegacyOs => 2,
+        })?;
+        for &word in &self.registers.data {
+            writer.write_u16::<BigEndian>(word)?;
+        }
+        writer.write_u16::<BigEndian>(self.registers.psr())?;
+        writer.write_u16::<BigEndian>(self.registers.saved_ssp)?;
+        writer.write_u16::<BigEndian>(self.registers.saved_usp)?;
+        writer.write_u16::<BigEndian>(self.interrupts.len() as u16)?;
+        for request in &self.interrupts {
+            writer.write_u16::<BigEndian>(request.vector as u16)?;
+            writer.write_u16::<BigEndian>(request.priority as u16)?;
+        }
+        writer.write_u16::<BigEndian>(self.devices.len() as u16)?;
+        for (address, state) in &self.devices {
+            writer.write_u16::<BigEndian>(*address)?;
+            writer.write_u16::<BigEndian>(state.len() as u16)?;
+            for &word in state {
+                writer.write_u16::<BigEndian>(word)?;
+            }
+        }
+        for &word in &self.ram {
+            writer.write_u16::<BigEndian>(word)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not an LC-3 snapshot"));
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", version)));
+        }
+        let instructions = reader.read_u64::<BigEndian>()?;
+        let trap_mode = match reader.read_u16::<BigEndian>()? {
+            0 => TrapMode::Fast,
+            1 => TrapMode::Os,
+            2 => TrapMode::LegacyOs,
+            mode => return Err(invalid(&format!("unknown trap mode {}", mode))),
+        };
+
+        let mut data = [0; 10];
+        reader.read_u16_into::<BigEndian>(&mut data)?;
+        let mut registers = Registers::new();
+        registers.set_psr(reader.read_u16::<BigEndian>()?);
+        registers.data = data;
+        registers.saved_ssp = reader.read_u16::<BigEndian>()?;
+        registers.saved_usp = reader.read_u16::<BigEndian>()?;
+        registers.take_written();
+
+        let count = reader.read_u16::<BigEndian>()?;
+        let mut interrupts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let vector = reader.read_u16::<BigEndian>()? as u8;
+            let priority = reader.read_u16::<BigEndian>()? as u8 & 0x7;
+            interrupts.push(InterruptRequest { vector, priority });
+        }
+
+        let count = reader.read_u16::<BigEndian>()?;
+        let mut devices = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let address = reader.read_u16::<BigEndian>()?;
+            let mut state = vec![0; reader.read_u16::<BigEndian>()? as usize];
+            reader.read_u16_into::<BigEndian>(&mut state)?;
+            devices.push((address, state));
+        }
+
+        let mut ram = vec![0; MEMORY_SIZE];
+        reader.read_u16_into::<BigEndian>(&mut ram)?;
+        Ok(Snapshot { registers, ram, devices, interrupts, trap_mode, instructions })
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::hardware::Memory::MemoryMappedReg;
+    use crate::lc3::hardware::Reg::RegisterEnum;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::LC3;
+
+    /// Adds R1 into R0 five times, then halts: R0 ends up at 5 * R1.
+    const PROGRAM: [u16; 5] = [
+        0x5020, // AND R0, R0, #0
+        0x1001, // ADD R0, R0, R1
+        0x14BF, // ADD R2, R2, #-1
+        0x03FD, // BRp -3
+        0xF025, // HALT
+    ];
+
+    fn machine() -> LC3 {
+        let mut vm = LC3::with_console(console::shared(BufferConsole::new(b"")));
+        vm.load_words(0x3000, &PROGRAM);
+        vm.registers_mut().write(RegisterEnum::R1, 3);
+        vm.registers_mut().write(RegisterEnum::R2, 5);
+        vm
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = machine();
+        vm.run_for(4);
+        vm.raise_interrupt(0x81, 2);
+        let snapshot = vm.snapshot();
+
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        let read = Snapshot::read_from(&bytes[..]).unwrap();
+        assert_eq!(read.registers.data, snapshot.registers.data);
+        assert_eq!(read.registers.psr(), snapshot.registers.psr());
+        assert_eq!(read.ram, snapshot.ram);
+        assert_eq!(read.devices, snapshot.devices);
+        assert_eq!(read.interrupts, vec![InterruptRequest { vector: 0x81, priority: 2 }]);
+        assert_eq!(read.instructions, 4);
+
+        bytes[0] = b'X';
+        let error = Snapshot::read_from(&bytes[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_restore_resumes() {
+        let mut vm = machine();
+        vm.run_for(6);
+        let snapshot = vm.snapshot();
+        vm.run();
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 15);
+
+        // A fresh machine picks up where the first one was, and a what-if run forks off
+        let mut fork = LC3::with_console(console::shared(BufferConsole::new(b"")));
+        fork.restore(&snapshot);
+        assert_eq!(fork.instructions_executed(), 6);
+        fork.registers_mut().write(RegisterEnum::R1, 10);
+        fork.run();
+        assert_eq!(fork.registers().read(RegisterEnum::R0), 2 * 3 + 3 * 10);
+
+        vm.restore(&snapshot);
+        vm.run();
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 15);
+        assert_eq!(vm.instructions_executed(), fork.instructions_executed());
+    }
+
+    #[test]
+    fn test_keyboard_latch() {
+        let mut vm = LC3::with_console(console::shared(BufferConsole::new(b"a")));
+        vm.memory_mut().read(MemoryMappedReg::Kbsr as usize);
+        let snapshot = vm.snapshot();
+
+        let mut other = LC3::with_console(console::shared(BufferConsole::new(b"")));
+        other.restore(&snapshot);
+        assert_eq!(other.memory_mut().read(MemoryMappedReg::Kbsr as usize), 0x8000);
+        assert_eq!(other.memory_mut().read(MemoryMappedReg::Kbdr as usize), b'a' as u16);
+    }
+}
@@ -0,0 +1,41 @@
+use crate::lc3::cpu::interrupt::InterruptRequest;
+use crate::lc3::hardware::Memory::MemoryAccess;
+use crate::lc3::hardware::Reg::RegisterEnum;
+use crate::lc3::vm::ExitReason;
+
+/// What a single fetch/decode/execute cycle did, as reported by `LC3::step`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepInfo {
+    /// The instruction word that was executed.
+    pub instr: u16,
+    /// Address the instruction was fetched from.
+    pub pc_before: u16,
+    /// PC after the instruction completed.
+    pub pc_after: u16,
+    /// Every register written by the instruction (the PC increment included), with its new value.
+    pub registers_written: Vec<(RegisterEnum, u16)>,
+    /// Memory reads and writes made by the instruction, in order. The fetch itself is not listed.
+    pub memory: Vec<MemoryAccess>,
+    /// Interrupt taken after the instruction completed; `pc_after` is then its service routine.
+    pub interrupt: Option<InterruptRequest>,
+    /// Set when the instruction stopped the machine.
+    pub exit: Option<ExitReason>,
+}
+
+impl StepInfo {
+    /// Addresses written by the instruction.
+    pub fn writes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.memory.iter().filter_map(|access| match access {
+            MemoryAccess::Write { address, .. } => Some(*address),
+            MemoryAccess::Read { .. } => None,
+        })
+    }
+
+    /// Addresses read by the instruction.
+    pub fn reads(&self) -> impl Iterator<Item = u16> + '_ {
+        self.memory.iter().filter_map(|access| match access {
+            MemoryAccess::Read { address, .. } => Some(*address),
+            MemoryAccess::Write { .. } => None,
+        })
+    }
+}
@@ -0,0 +1,274 @@
+//! Execution traces: one record per instruction, for diffing runs against each other or
+//! against other simulators.
+//!
+//! A record holds the instruction's cycle (the instruction count in front of it), PC,
+//! word and disassembly, the registers it wrote, its memory reads and writes, and the
+//! condition codes after it. JSON Lines puts one object on each line:
+//!
+//! ```text
+//! {"cycle":0,"pc":12288,"word":20512,"asm":"AND R0, R0, #0","regs":{"R0":0,"PC":12289,"COND":2},"mem":[],"cond":"Z"}
+//! ```
+//!
+//! `"mem"` lists `{"op":"read","address":..,"value":..}` and
+//! `{"op":"write","address":..,"value":..,"old":..}` in order. An `"interrupt"` vector is
+//! added when one was taken after the instruction, and `"exit"` when it stopped the
+//! machine.
+//!
+//! The binary format is the same records as big-endian words behind an `LC3TRACE` magic
+//! and a u16 version (2), leaving out the disassembly and the exit reason:
+//!
+//! ```text
+//! u64 cycle, u16 pc, u16 word, u8 cond (NZP bits), u8 flags (1 interrupt, 2 exit),
+//! u16 register count, u16 access count,
+//! per register: u8 index (R0-R7, 8 PC, 9 COND), u16 value
+//! per access:   u8 kind (0 read, 1 write), u16 address, u16 value, u16 old (writes only)
+//! u8 interrupt vector, if flagged
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::lc3::cpu::disasm::disassemble;
+use crate::lc3::hardware::Flag::ConditionFlags;
+use crate::lc3::hardware::Memory::MemoryAccess;
+use crate::lc3::sys::json::Json;
+use crate::lc3::vm::StepInfo;
+
+const MAGIC: &[u8; 8] = b"LC3TRACE";
+const VERSION: u16 = 2;
+
+/// How a `Tracer` writes its records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    JsonLines,
+    Binary,
+}
+
+/// Writes a record for every instruction the machine it is attached to executes, see
+/// `LC3::set_tracer`.
+pub struct Tracer {
+    format: TraceFormat,
+    out: Box<dyn Write>,
+    /// The first write that failed; nothing more is written after it.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, out: Box<dyn Write>) -> Self {
+        let mut tracer = Tracer { format, out, error: None };
+        if format == TraceFormat::Binary {
+            let header = tracer.out.write_all(MAGIC).and_then(|()| tracer.out.write_u16::<BigEndian>(VERSION));
+            tracer.error = header.err();
+        }
+        tracer
+    }
+
+    /// Trace to a new file at `path`.
+    pub fn create(format: TraceFormat, path: &str) -> io::Result<Self> {
+        Ok(Tracer::new(format, Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    /// Write the record for the instruction `info` describes; `cycle` is the instruction
+    /// count in front of it and `cond` the COND register after it.
+    pub fn record(&mut self, cycle: u64, info: &StepInfo, cond: u16) {
+        if self.error.is_some() {
+            return;
+        }
+        let written = match self.format {
+            TraceFormat::JsonLines => writeln!(self.out, "{}", json(cycle, info, cond)),
+            TraceFormat::Binary => write_binary(&mut self.out, cycle, info, cond),
+        };
+        self.error = written.err();
+    }
+
+    /// Flush the trace, reporting the first write that failed.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.out.flush(),
+        }
+    }
+}
+
+fn json(cycle: u64, info: &StepInfo, cond: u16) -> Json {
+    let registers = info.registers_written.iter().map(|(reg, value)| (format!("{:?}", reg), Json::from(*value)));
+    let memory = info.memory.iter().map(|access| match *access {
+        MemoryAccess::Read { address, value } => {
+            Json::object([("op", Json::from("read")), ("address", address.into()), ("value", value.into())])
+        }
+        MemoryAccess::Write { address, old, value } => Json::object([
+            ("op", Json::from("write")),
+            ("address", address.into()),
+            ("value", value.into()),
+            ("old", old.into()),
+        ]),
+    });
+    let mut members = vec![
+        ("cycle", Json::from(cycle)),
+        ("pc", info.pc_before.into()),
+        ("word", info.instr.into()),
+        ("asm", disassemble(info.instr, info.pc_before).to_string().into()),
+        ("regs", Json::object(registers)),
+        ("mem", Json::Array(memory.collect())),
+        ("cond", nzp(cond).into()),
+    ];
+    if let Some(interrupt) = info.interrupt {
+        members.push(("interrupt", interrupt.vector.into()));
+    }
+    if let Some(exit) = &info.exit {
+        members.push(("exit", exit.to_string().into()));
+    }
+    Json::object(members)
+}
+
+/// `N`, `Z` or `P` for the condition codes set in `cond`.
+fn nzp(cond: u16) -> String {
+    [(ConditionFlags::NEG, 'N'), (ConditionFlags::ZRO, 'Z'), (ConditionFlags::POS, 'P')]
+        .into_iter()
+        .filter(|(flag, _)| cond & flag.bits() != 0)
+        .map(|(_, letter)| letter)
+        .collect()
+}
+
+fn write_binary(out: &mut dyn Write, cycle: u64, info: &StepInfo, cond: u16) -> io::Result<()> {
+    out.write_u64::<BigEndian>(cycle)?;
+    out.write_u16::<BigEndian>(info.pc_before)?;
+    out.write_u16::<BigEndian>(info.instr)?;
+    out.write_u8(cond as u8 & 0x7)?;
+    out.write_u8(info.interrupt.is_some() as u8 | (info.exit.is_some() as u8) << 1)?;
+    // PUTS and PUTSP log a read per character, so the counts can pass 255
+    out.write_u16::<BigEndian>(info.registers_written.len() as u16)?;
+    out.write_u16::<BigEndian>(info.memory.len() as u16)?;
+    for &(reg, value) in &info.registers_written {
+        out.write_u8(reg as u8)?;
+        out.write_u16::<BigEndian>(value)?;
+    }
+    for access in &info.memory {
+        match *access {
+            MemoryAccess::Read { address, value } => {
+                out.write_u8(0)?;
+                out.write_u16::<BigEndian>(address)?;
+                out.write_u16::<BigEndian>(value)?;
+            }
+            MemoryAccess::Write { address, old, value } => {
+                out.write_u8(1)?;
+                out.write_u16::<BigEndian>(address)?;
+                out.write_u16::<BigEndian>(value)?;
+                out.write_u16::<BigEndian>(old)?;
+            }
+        }
+    }
+    if let Some(interrupt) = info.interrupt {
+        out.write_u8(interrupt.vector)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::sys::console::{self, BufferConsole};
+    use crate::lc3::vm::{ExitReason, LC3};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A `Write` the test keeps a handle on after giving it to a tracer.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const PROGRAM: [u16; 3] = [
+        0x5020, // AND R0, R0, #0
+        0x3001, // ST R0, #1
+        0xF025, // HALT
+    ];
+
+    fn traced(format: TraceFormat) -> Vec<u8> {
+        let out = Shared::default();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(0x3000, &PROGRAM);
+        vm.memory_mut().write(0x3003, 7);
+        vm.set_tracer(Some(Tracer::new(format, Box::new(out.clone()))));
+        assert_eq!(vm.run(), ExitReason::Halted);
+        vm.set_tracer(None).unwrap().finish().unwrap();
+        let bytes = out.0.borrow().clone();
+        bytes
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let trace = String::from_utf8(traced(TraceFormat::JsonLines)).unwrap();
+        let lines: Vec<Json> = trace.lines().map(|line| Json::parse(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            trace.lines().next().unwrap(),
+            r#"{"cycle":0,"pc":12288,"word":20512,"asm":"AND R0, R0, #0","regs":{"R0":0,"PC":12289,"COND":2},"mem":[],"cond":"Z"}"#
+        );
+        let store = &lines[1].get("mem").unwrap().as_array().unwrap()[0];
+        assert_eq!(store.get("op").unwrap().as_str(), Some("write"));
+        assert_eq!(store.get("address").unwrap().as_i64(), Some(0x3003));
+        assert_eq!(store.get("old").unwrap().as_i64(), Some(7));
+        assert_eq!(lines[2].get("cycle").unwrap().as_i64(), Some(2));
+        assert_eq!(lines[2].get("exit").unwrap().as_str(), Some("halted"));
+    }
+
+    /// The (pc, access count) of each record in a binary trace, checking every record
+    /// is whole.
+    fn parse_binary(trace: &[u8]) -> Vec<(u16, usize)> {
+        let word = |at: usize| u16::from_be_bytes([trace[at], trace[at + 1]]);
+        let mut records = Vec::new();
+        let mut at = 10;
+        while at < trace.len() {
+            let (pc, flags) = (word(at + 8), trace[at + 13]);
+            let (registers, accesses) = (word(at + 14) as usize, word(at + 16) as usize);
+            at += 18 + 3 * registers;
+            for _ in 0..accesses {
+                at += if trace[at] == 1 { 7 } else { 5 };
+            }
+            at += (flags & 1) as usize;
+            records.push((pc, accesses));
+        }
+        assert_eq!(at, trace.len());
+        records
+    }
+
+    #[test]
+    fn test_binary_long_string() {
+        // PUTS reads 300 characters and the terminator in a single record
+        let mut program = vec![0xE002, 0xF022, 0xF025]; // LEA R0, #2; PUTS; HALT
+        program.extend([b'x' as u16; 300]);
+        program.push(0);
+        let out = Shared::default();
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_words(0x3000, &program);
+        vm.set_tracer(Some(Tracer::new(TraceFormat::Binary, Box::new(out.clone()))));
+        assert_eq!(vm.run(), ExitReason::Halted);
+        vm.set_tracer(None).unwrap().finish().unwrap();
+        let trace = out.0.borrow().clone();
+        assert_eq!(parse_binary(&trace), vec![(0x3000, 0), (0x3001, 301), (0x3002, 0)]);
+    }
+
+    #[test]
+    fn test_binary() {
+        let trace = traced(TraceFormat::Binary);
+        assert_eq!(&trace[..10], b"LC3TRACE\x00\x02");
+        // AND R0, R0, #0: cycle 0 at x3000, COND Z, R0, PC and COND written, no accesses
+        let first: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 0, 0x30, 0x00, 0x50, 0x20, 2, 0, 0, 3, 0, 0, 0, 0, 0, 8, 0x30, 0x01, 9, 0, 2];
+        assert_eq!(&trace[10..10 + first.len()], first);
+        // ST R0, #1: a single write of 0 over 7 at x3003
+        let second = &trace[10 + first.len()..];
+        assert_eq!(&second[14..18], &[0, 1, 0, 1]);
+        assert_eq!(&second[21..28], &[1, 0x30, 0x03, 0, 0, 0, 7]);
+    }
+}
@@ -1,65 +1,766 @@
+use std::collections::HashSet;
 use std::io::{self};
+use std::path::Path;
 
 use crate::lc3::cpu::instruction::Instructions;
-use crate::lc3::cpu::decode::execute_instruction;
-use crate::lc3::hardware::Memory::{Memory,MEMORY_SIZE};
+use crate::lc3::cpu::decode::execute_decoded;
+use crate::lc3::cpu::interrupt::{self, Exception, InterruptController, InterruptRequest};
+use crate::lc3::cpu::trap::TrapMode;
+use crate::lc3::hardware::Memory::{Fetched, Memory, MEMORY_SIZE};
 use crate::lc3::hardware::Reg::{Registers,RegisterEnum};
 use crate::lc3::hardware::Flag::ConditionFlags;
-use crate::lc3::sys::file::read_image;
-// use  crate::lc3::
-// use  crate::lc3::
-// use  crate::lc3::
+#[cfg(feature = "jit")]
+use crate::lc3::jit::Jit;
+use crate::lc3::sys::console::{self, SharedConsole, StdConsole};
+use crate::lc3::sys::file::read_image_words;
+use crate::lc3::sys::replay::Clock;
+use crate::lc3::sys::symbols::SymbolTable;
+use crate::lc3::vm::coverage::Coverage;
+use crate::lc3::vm::snapshot::Snapshot;
+use crate::lc3::vm::profile::Profiler;
+use crate::lc3::vm::trace::Tracer;
+use crate::lc3::vm::{ExitReason, StepInfo};
+
+/// Address user programs are loaded at and started from.
+pub const PC_START: u16 = 0x3000;
+/// Initial supervisor stack pointer; the stack grows down from just below user space.
+pub const SUPERVISOR_STACK_START: u16 = 0x3000;
+/// Initial user stack pointer; the stack grows down from just below the device registers.
+pub const USER_STACK_START: u16 = 0xFE00;
+
 pub struct LC3 {
     memory: Memory,
     registers: Registers,
+    /// Terminal used by the traps and the keyboard/display devices.
+    console: SharedConsole,
+    /// Interrupts raised by the host, arbitrated together with device requests.
+    interrupts: InterruptController,
+    /// Whether TRAPs run the built-in routines or the ones in the trap vector table.
+    trap_mode: TrapMode,
+    /// Labels picked up from `.sym` files next to loaded images.
+    symbols: SymbolTable,
+    /// Addresses `run` stops in front of.
+    breakpoints: HashSet<u16>,
+    /// Maximum number of instructions a single `run` may execute.
+    step_limit: Option<u64>,
+    /// Breakpoint we last stopped at, so the next `run` steps over it.
+    stopped_at: Option<u16>,
+    /// Instructions executed since the machine was created; shared with consoles that
+    /// timestamp input, see `share_clock`.
+    instructions: Clock,
+    /// Writes a record per instruction while set; runs then go through `step`.
+    tracer: Option<Tracer>,
+    /// Counts every instruction while set, like `tracer`.
+    profiler: Option<Profiler>,
+    /// Marks the code, data and branch directions used while set, like `tracer`.
+    coverage: Option<Coverage>,
+    /// Translator `run_for` runs hot blocks with, if enabled.
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
 }
 
 impl LC3 {
-    /// Create a new LC3 instance
+    /// Create a new LC3 instance talking to stdin/stdout
     pub fn new() -> Self {
+        Self::with_console(console::shared(StdConsole))
+    }
+
+    /// Create a new LC3 instance whose traps and devices use `console`
+    pub fn with_console(console: SharedConsole) -> Self {
+        let mut registers = Registers::new();
+        registers.write(RegisterEnum::COND, ConditionFlags::ZRO.bits());
+        registers.write(RegisterEnum::PC, PC_START);
+        // Programs start in supervisor mode, on the supervisor stack, so an exception
+        // taken before they set up R6 has somewhere to push its frame
+        registers.write(RegisterEnum::R6, SUPERVISOR_STACK_START);
+        registers.saved_ssp = SUPERVISOR_STACK_START;
+        registers.saved_usp = USER_STACK_START;
+
         Self {
-            memory: Memory::new(),
-            registers: Registers::new(),
+            memory: Memory::with_console(console.clone()),
+            registers,
+            console,
+            interrupts: InterruptController::new(),
+            trap_mode: TrapMode::Fast,
+            symbols: SymbolTable::new(),
+            breakpoints: HashSet::new(),
+            step_limit: None,
+            stopped_at: None,
+            instructions: Clock::default(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            #[cfg(feature = "jit")]
+            jit: Jit::new().ok(),
         }
     }
 
+    /// Load an image, along with the symbol table in a sibling `.sym` file if there is one.
     pub fn load_image(&mut self, image_path: &str)->io::Result<()>{
-        //load the file into a new Memory instance
-        let mut loaded_memory = read_image(image_path)?;
+        let (origin, words) = read_image_words(image_path)?;
+        self.load_words(origin, &words);
 
-        // Merge the loaded memory into the VM's existing memory
-        for address in 0..MEMORY_SIZE {
-            let value = loaded_memory.read(address);
-            self.memory.write(address, value);
+        let sym_path = Path::new(image_path).with_extension("sym");
+        if sym_path.is_file() {
+            self.symbols.extend(&SymbolTable::load(sym_path)?);
         }
+        Ok(())
+    }
+
+    /// Copy `words` into memory starting at `origin`.
+    pub fn load_words(&mut self, origin: u16, words: &[u16]) {
+        // Copy only the loaded range, so images don't clobber each other or the device registers
+        for (i, &word) in words.iter().enumerate() {
+            self.memory.write(origin as usize + i, word);
+        }
+    }
 
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    /// Load an operating system image and service TRAPs through its trap vector table
+    /// (`mode` is `TrapMode::Os` or `TrapMode::LegacyOs`). Execution still starts at the
+    /// user program, in supervisor mode.
+    pub fn load_os(&mut self, image_path: &str, mode: TrapMode) -> io::Result<()> {
+        self.load_image(image_path)?;
+        self.trap_mode = mode;
         Ok(())
     }
-    /// Run the VM main loop
-    pub fn run(&mut self) {
-        // Initialize registers
-        self.registers.write(RegisterEnum::COND, ConditionFlags::ZRO.bits());
-        self.registers.write(RegisterEnum::PC, 0x3000);
-        println!("Registers Init");
 
-        let running = true;
+    /// Turn the predecode cache on or off; it is on by default. Results are the same
+    /// either way, the cache only saves decoding the same words over and over.
+    pub fn set_predecode(&mut self, enabled: bool) {
+        self.memory.set_predecode(enabled);
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit(&self) -> Option<&Jit> {
+        self.jit.as_ref()
+    }
+
+    /// Turn the JIT on or off; it is on by default. `run` and `run_for` use it, and only
+    /// while no breakpoints are set.
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, enabled: bool) -> io::Result<()> {
+        self.jit = match (enabled, self.jit.take()) {
+            (true, Some(jit)) => Some(jit),
+            (true, None) => Some(Jit::new()?),
+            (false, _) => None,
+        };
+        Ok(())
+    }
 
-        let instructions = Instructions;
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn console(&self) -> &SharedConsole {
+        &self.console
+    }
+
+    /// Request an interrupt through `vector` at `priority` (0-7). It is taken after the
+    /// next instruction whose running priority is below `priority`.
+    pub fn raise_interrupt(&mut self, vector: u8, priority: u8) {
+        self.interrupts.raise(InterruptRequest { vector, priority: priority & 0x7 });
+    }
+
+    /// Limit every subsequent `run` to at most `limit` instructions (`None` removes the limit).
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
 
-        while running {
+    /// Returns `true` if a breakpoint was set at `address`.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    /// Breakpoint addresses in ascending order.
+    pub fn breakpoints(&self) -> Vec<u16> {
+        let mut addresses: Vec<u16> = self.breakpoints.iter().copied().collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    /// Execute exactly one fetch/decode/execute cycle and report what it did.
+    ///
+    /// Breakpoints and the step limit are not consulted; a later `run` resumes from here
+    /// without stopping on a breakpoint at the new PC.
+    pub fn step(&mut self) -> StepInfo {
+        let info = self.cycle();
+        self.stopped_at = Some(info.pc_after);
+        info
+    }
+
+    /// `step`, without the note for `run` to step over a breakpoint.
+    fn cycle(&mut self) -> StepInfo {
+        let cycle = self.instructions.get();
+        let pc_before = self.registers.read(RegisterEnum::PC);
+        self.registers.take_written();
+        self.memory.start_access_log();
+        let fetched = self.fetch();
+        let result = self.execute_fetched(fetched);
+        self.memory.tick();
+        self.instructions.set(self.instructions.get() + 1);
+        let interrupt = match result {
+            Ok(()) => self.interrupts.service(&mut self.registers, &mut self.memory),
+            Err(_) => None,
+        };
+
+        let memory = self.memory.take_access_log();
+        let registers_written = self
+            .registers
+            .take_written()
+            .into_iter()
+            .map(|reg| (reg, self.registers.read(reg)))
+            .collect();
+        let pc_after = self.registers.read(RegisterEnum::PC);
+        let exit = match result {
+            Err(reason) => Some(reason),
+            Ok(()) if !self.memory.clock_enabled() => Some(ExitReason::Halted),
+            Ok(()) => None,
+        };
+
+        let info = StepInfo {
+            instr: fetched.map_or(0, |fetched| fetched.word),
+            pc_before,
+            pc_after,
+            registers_written,
+            memory,
+            interrupt,
+            exit,
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(cycle, &info, self.registers.read(RegisterEnum::COND));
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(&info);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(&info, self.registers.read(RegisterEnum::COND));
+        }
+        info
+    }
+
+    /// Instructions executed since the machine was created, by `step` and every kind of
+    /// run, including any that stopped the machine.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions.get()
+    }
+
+    /// Wind the instruction count back, for undoing instructions. Consoles sharing the
+    /// clock see the change.
+    pub fn set_instructions_executed(&mut self, instructions: u64) {
+        self.instructions.set(instructions);
+    }
+
+    /// Keep the instruction count in `clock` from now on, carrying the count so far
+    /// over, so a console can tell which instruction it is serving.
+    pub fn share_clock(&mut self, clock: Clock) {
+        clock.set(self.instructions.get());
+        self.instructions = clock;
+    }
+
+    /// Capture the machine's state, see `Snapshot` for what that covers.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            ram: self.memory.ram().to_vec(),
+            devices: self.memory.save_devices(),
+            interrupts: self.interrupts.pending().to_vec(),
+            trap_mode: self.trap_mode,
+            instructions: self.instructions.get(),
+        }
+    }
+
+    /// Put the machine back into the state `snapshot` captured. Breakpoints, symbols and
+    /// the console are kept, and the next `run` starts fresh rather than stepping over a
+    /// breakpoint at the restored PC.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers.clone();
+        self.memory.load_ram(&snapshot.ram);
+        self.memory.load_devices(&snapshot.devices);
+        self.interrupts = InterruptController::new();
+        for &request in &snapshot.interrupts {
+            self.interrupts.raise(request);
+        }
+        self.trap_mode = snapshot.trap_mode;
+        self.instructions.set(snapshot.instructions);
+        self.stopped_at = None;
+    }
+
+    /// Trace every instruction from now on to `tracer`, or stop tracing with `None`.
+    /// Returns the tracer replaced, to be finished by the caller.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Profile every instruction from now on with `profiler`, or stop with `None`.
+    /// Returns the profiler replaced.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Collect coverage of every instruction from now on into `coverage`, or stop with
+    /// `None`. Returns the coverage replaced.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Run the VM main loop until the program stops, and report why.
+    pub fn run(&mut self) -> ExitReason {
+        match self.execute(self.step_limit, |_| false, true) {
+            Ok(()) => unreachable!("run has no stop condition"),
+            Err(reason) => reason,
+        }
+    }
+
+    /// Run at most `n` instructions; `StepLimitReached` means all `n` were executed.
+    pub fn run_for(&mut self, n: u64) -> ExitReason {
+        match self.execute(Some(n), |_| false, true) {
+            Ok(()) => unreachable!("run_for has no stop condition"),
+            Err(reason) => reason,
+        }
+    }
+
+    /// Run until `predicate` holds for the machine state in front of the next instruction.
+    ///
+    /// Returns `Ok(())` once it does, or `Err` if the machine stopped first. The predicate
+    /// is checked before every instruction, including the first.
+    pub fn run_until<F>(&mut self, predicate: F) -> Result<(), ExitReason>
+    where
+        F: FnMut(&LC3) -> bool,
+    {
+        self.execute(self.step_limit, predicate, false)
+    }
+
+    /// Shared entry to `run`, `run_for` and `run_until`: picks the loop with or without
+    /// tracing and profiling, so a run without them pays nothing for them.
+    fn execute<F>(&mut self, limit: Option<u64>, predicate: F, jit: bool) -> Result<(), ExitReason>
+    where
+        F: FnMut(&LC3) -> bool,
+    {
+        match self.tracer.is_some() || self.profiler.is_some() || self.coverage.is_some() {
+            true => self.run_loop::<F, true>(limit, predicate, jit),
+            false => self.run_loop::<F, false>(limit, predicate, jit),
+        }
+    }
+
+    /// The main loop. With `jit`, hot blocks run natively, as long as no interrupt could
+    /// come due inside one: nothing is pending or asserted and no device has interrupts
+    /// enabled. Device ticks then wait for the end of the block. With `OBSERVE`, every
+    /// instruction goes through `cycle` for the tracer, profiler and coverage, and the
+    /// JIT is not used.
+    #[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+    fn run_loop<F, const OBSERVE: bool>(&mut self, limit: Option<u64>, mut predicate: F, jit: bool) -> Result<(), ExitReason>
+    where
+        F: FnMut(&LC3) -> bool,
+    {
+        let mut executed: u64 = 0;
+        // Resuming from a breakpoint must not stop on it again straight away.
+        let mut resume_pc = self.stopped_at.take();
+
+        loop {
+            // A program halts the machine by clearing bit 15 of the MCR
+            if !self.memory.clock_enabled() {
+                return Err(ExitReason::Halted);
+            }
+            if predicate(self) {
+                return Ok(());
+            }
+            let pc = self.registers.read(RegisterEnum::PC);
+            if resume_pc.take() != Some(pc) && self.breakpoints.contains(&pc) {
+                self.stopped_at = Some(pc);
+                return Err(ExitReason::Breakpoint(pc));
+            }
+            if limit.is_some_and(|limit| executed >= limit) {
+                return Err(ExitReason::StepLimitReached);
+            }
+            #[cfg(feature = "jit")]
+            if let (true, true, true, Some(compiler)) =
+                (jit && !OBSERVE, self.breakpoints.is_empty(), self.interrupts_quiet(), &mut self.jit)
+            {
+                let budget = limit.map_or(u64::MAX, |limit| limit - executed);
+                let n = compiler.run(&mut self.registers, &mut self.memory, budget);
+                if n > 0 {
+                    for _ in 0..n {
+                        self.memory.tick();
+                    }
+                    self.interrupts.service(&mut self.registers, &mut self.memory);
+                    executed += n;
+                    self.instructions.set(self.instructions.get() + n);
+                    continue;
+                }
+            }
+            if OBSERVE {
+                executed += 1;
+                match self.cycle().exit {
+                    Some(reason) => return Err(reason),
+                    None => continue,
+                }
+            }
+            let fetched = self.fetch();
+            // Decode and execute the instruction
+            let result = self.execute_fetched(fetched);
+            self.memory.tick();
+            self.instructions.set(self.instructions.get() + 1);
+            result?;
+            // Interrupts are taken between instructions
+            self.interrupts.service(&mut self.registers, &mut self.memory);
+            executed += 1;
+        }
+    }
+
+    /// Whether no interrupt can be taken before the next RTI or device access: the JIT
+    /// services interrupts only between blocks.
+    #[cfg(feature = "jit")]
+    fn interrupts_quiet(&self) -> bool {
+        self.interrupts.pending().is_empty()
+            && self.memory.interrupt_request().is_none()
+            && !self.memory.interrupts_enabled()
+    }
+
+    /// Fetch and decode the instruction at PC and increment the PC past it.
+    /// Returns `None` if user mode may not execute from PC.
+    fn fetch(&mut self) -> Option<Fetched> {
         // Fetch the program counter (PC)
         let pc = self.registers.read(RegisterEnum::PC);
-        // Fetch the instruction from memory
-        let instr = self.memory.read(pc as usize);
+        // Fetch the instruction from memory, decoded by the predecode cache
+        let instr = self.memory.fetch_decoded(pc as usize, self.registers.is_user_mode());
         // Increment the PC
         self.registers.write(RegisterEnum::PC, pc.wrapping_add(1));
-        // Print the instruction being executed
-        println!("Executing instruction: {}", instr);
-        // Decode and execute the instruction
-        execute_instruction(instr, &mut self.registers, &mut self.memory);
+        instr
+    }
+
+    /// Execute what `fetch` returned; a refused fetch is an access control violation.
+    fn execute_fetched(&mut self, fetched: Option<Fetched>) -> Result<(), ExitReason> {
+        match fetched {
+            Some(fetched) => execute_decoded(
+                fetched.instruction,
+                &mut self.registers,
+                &mut self.memory,
+                &self.console,
+                self.trap_mode,
+            ),
+            None => {
+                let pc = self.registers.read(RegisterEnum::PC).wrapping_sub(1);
+                interrupt::raise(
+                    &mut self.registers,
+                    &mut self.memory,
+                    Exception::AccessControlViolation,
+                    ExitReason::AccessViolation(pc, pc),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::hardware::Memory::MemoryAccess;
+    use crate::lc3::sys::console::{BufferConsole, ScriptedConsole};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Loads `program` at `PC_START`, with console output discarded.
+    fn vm_with_program(program: &[u16]) -> LC3 {
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        for (i, &word) in program.iter().enumerate() {
+            vm.memory_mut().write(PC_START as usize + i, word);
+        }
+        vm
+    }
+
+    #[test]
+    fn test_run_returns_halted() {
+        // ADD R0, R0, #5 ; HALT
+        let mut vm = vm_with_program(&[0x1025, 0xF025]);
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 5);
+    }
+
+    #[test]
+    fn test_run_reports_illegal_opcode() {
+        let mut vm = vm_with_program(&[0x1025, 0xD000]);
+        assert_eq!(vm.run(), ExitReason::IllegalOpcode(0x3001, 0xD000));
+    }
+
+    #[test]
+    fn test_illegal_opcode_handler_runs() {
+        let mut vm = vm_with_program(&[0xD000]);
+        // Handler at x0600: ADD R3, R3, #1 ; HALT
+        vm.memory_mut().write(0x0101, 0x0600);
+        vm.memory_mut().write(0x0600, 0x16E1);
+        vm.memory_mut().write(0x0601, 0xF025);
+
+        assert_eq!(vm.run_for(10), ExitReason::Halted);
+        assert_eq!(vm.registers().read(RegisterEnum::R3), 1);
+    }
+
+    #[test]
+    fn test_user_mode_fetch_from_system_space() {
+        // RTI into user mode at x0200
+        let mut vm = vm_with_program(&[0x8000]);
+        vm.registers_mut().write(RegisterEnum::R6, 0x2FFE);
+        vm.memory_mut().write(0x2FFE, 0x0200);
+        vm.memory_mut().write(0x2FFF, 0x8000);
+
+        assert_eq!(vm.run_for(10), ExitReason::AccessViolation(0x0200, 0x0200));
+        assert!(vm.registers().is_user_mode());
+
+        // RTI from user mode is a privilege violation
+        vm.registers_mut().write(RegisterEnum::PC, 0x3000);
+        assert_eq!(vm.run_for(10), ExitReason::PrivilegeViolation(0x3000));
+    }
+
+    #[test]
+    fn test_os_mode_runs_trap_routines_from_memory() {
+        let buffer = Rc::new(RefCell::new(BufferConsole::default()));
+        let mut vm = LC3::with_console(buffer.clone());
+        // AND R0, R0, #0 ; ADD R0, R0, #1 ; OUT ; HALT, so R0 = 1
+        let program = [0x5020, 0x1021, 0xF021, 0xF025];
+        for (i, &word) in program.iter().enumerate() {
+            vm.memory_mut().write(PC_START as usize + i, word);
+        }
+        vm.registers_mut().write(RegisterEnum::R0, 0);
+        // OUT at x0430: STI R0, #1 ; RTI ; .FILL xFE06
+        vm.memory_mut().write(0x0021, 0x0430);
+        vm.memory_mut().write(0x0430, 0xB001);
+        vm.memory_mut().write(0x0431, 0x8000);
+        vm.memory_mut().write(0x0432, 0xFE06);
+        // HALT at x0450: AND R1, R1, #0 ; STI R1, #0 ; .FILL xFFFE
+        vm.memory_mut().write(0x0025, 0x0450);
+        vm.memory_mut().write(0x0450, 0x5260);
+        vm.memory_mut().write(0x0451, 0xB200);
+        vm.memory_mut().write(0x0452, 0xFFFE);
+        vm.set_trap_mode(TrapMode::Os);
 
+        assert_eq!(vm.run_for(20), ExitReason::Halted);
+        assert_eq!(buffer.borrow().output(), &[1]);
+        assert_eq!(vm.registers().read(RegisterEnum::PC), 0x0452);
+    }
+
+    #[test]
+    fn test_run_reports_unknown_trap() {
+        let mut vm = vm_with_program(&[0xF0FF]);
+        assert_eq!(vm.run(), ExitReason::UnknownTrap(0xFF));
+    }
+
+    #[test]
+    fn test_run_step_limit() {
+        // BRnzp #-1 loops forever
+        let mut vm = vm_with_program(&[0x0FFF]);
+        vm.set_step_limit(Some(100));
+        assert_eq!(vm.run(), ExitReason::StepLimitReached);
+        assert_eq!(vm.registers().read(RegisterEnum::PC), PC_START);
+    }
+
+    #[test]
+    fn test_run_stops_at_breakpoint_and_resumes() {
+        // ADD R0, R0, #1 (x3) ; HALT
+        let mut vm = vm_with_program(&[0x1021, 0x1021, 0x1021, 0xF025]);
+        vm.add_breakpoint(0x3002);
+        assert_eq!(vm.run(), ExitReason::Breakpoint(0x3002));
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 2);
+
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 3);
+    }
+
+    #[test]
+    fn test_run_stops_when_mcr_cleared() {
+        // AND R0, R0, #0 ; STI R0, #1 ; ADD R1, R1, #1 ; .FILL xFFFE
+        let mut vm = vm_with_program(&[0x5020, 0xB001, 0x1261, 0xFFFE]);
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.registers().read(RegisterEnum::PC), 0x3002);
+        assert_eq!(vm.registers().read(RegisterEnum::R1), 0);
+    }
+
+    #[test]
+    fn test_step_reports_registers_and_memory() {
+        // ADD R1, R1, #2 ; ST R1, #1 ; HALT ; .FILL 0
+        let mut vm = vm_with_program(&[0x1262, 0x3201, 0xF025, 0x0000]);
+
+        let info = vm.step();
+        assert_eq!(info.instr, 0x1262);
+        assert_eq!((info.pc_before, info.pc_after), (0x3000, 0x3001));
+        assert_eq!(
+            info.registers_written,
+            vec![
+                (RegisterEnum::R1, 2),
+                (RegisterEnum::PC, 0x3001),
+                (RegisterEnum::COND, ConditionFlags::POS.bits()),
+            ]
+        );
+        assert!(info.memory.is_empty());
+        assert_eq!(info.exit, None);
+
+        let info = vm.step();
+        assert_eq!(
+            info.memory,
+            vec![MemoryAccess::Write { address: 0x3003, old: 0, value: 2 }]
+        );
+
+        assert_eq!(vm.step().exit, Some(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut vm = vm_with_program(&[0x1021, 0x1021, 0x1021, 0xF025]);
+        assert_eq!(vm.run_for(2), ExitReason::StepLimitReached);
+        assert_eq!(vm.registers().read(RegisterEnum::R0), 2);
+        assert_eq!(vm.run_for(10), ExitReason::Halted);
+    }
+
+    #[test]
+    fn test_self_modifying_code_with_predecode() {
+        // ADD R0, R0, #1 ; ST R1, #-2 ; BRnzp #-3, where R1 holds ADD R0, R0, #2
+        for predecode in [true, false] {
+            let mut vm = vm_with_program(&[0x1021, 0x33FE, 0x0FFD]);
+            vm.set_predecode(predecode);
+            vm.registers_mut().write(RegisterEnum::R1, 0x1022);
+            assert_eq!(vm.run_for(6), ExitReason::StepLimitReached);
+            // The second pass runs the rewritten ADD, not a stale decoding
+            assert_eq!(vm.registers().read(RegisterEnum::R0), 3);
+        }
+    }
+
+    #[test]
+    fn test_run_until() {
+        // ADD R0, R0, #1 ; BRnzp #-2
+        let mut vm = vm_with_program(&[0x1021, 0x0FFE]);
+        let result = vm.run_until(|vm| vm.registers().read(RegisterEnum::R0) == 10);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.registers().read(RegisterEnum::PC), 0x3001);
+    }
+
+    #[test]
+    fn test_console_captures_trap_and_device_output() {
+        // GETC ; OUT ; STI R0, #1 ; HALT ; .FILL xFE06
+        let buffer = Rc::new(RefCell::new(BufferConsole::new(b"x")));
+        let mut vm = LC3::with_console(buffer.clone());
+        for (i, &word) in [0xF020, 0xF021, 0xB001, 0xF025, 0xFE06].iter().enumerate() {
+            vm.memory_mut().write(PC_START as usize + i, word);
         }
-        println!("VM execution completed.");
-        
+
+        assert_eq!(vm.run_for(10), ExitReason::Halted);
+        assert_eq!(buffer.borrow().output_string(), "xxHALT\n");
+    }
+
+    #[test]
+    fn test_2048_with_scripted_input() {
+        let script = Rc::new(RefCell::new(ScriptedConsole::new().keys("nwasd")));
+        let mut vm = LC3::with_console(script.clone());
+        vm.load_image("Static/2048.obj").unwrap();
+
+        // The game blocks in GETC once the script runs dry
+        assert_eq!(
+            vm.run_for(5_000_000),
+            ExitReason::IoError(io::ErrorKind::UnexpectedEof)
+        );
+        let output = script.borrow().output_string();
+        assert!(output.contains("Control the game using WASD keys."));
+        assert!(output.contains("+--------------------------+"));
+    }
+
+    #[test]
+    fn test_load_image_picks_up_sym_file() {
+        let dir = std::env::temp_dir().join(format!("razorvm-sym-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("prog.obj");
+        let image = image.to_str().unwrap();
+        crate::lc3::sys::file::write_image(image, 0x3000, &[0x1021, 0x0FFE]).unwrap();
+        let symbols: SymbolTable = [("LOOP", 0x3000)].into_iter().collect();
+        symbols.save(dir.join("prog.sym")).unwrap();
+
+        let mut vm = LC3::with_console(console::shared(BufferConsole::default()));
+        vm.load_image(image).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vm.symbols().address_of("LOOP"), Some(0x3000));
+        assert_eq!(vm.symbols().describe(0x3001), "LOOP+1");
+    }
+
+    #[test]
+    fn test_keyboard_interrupt_runs_handler() {
+        let buffer = Rc::new(RefCell::new(BufferConsole::new(b"k")));
+        let mut vm = LC3::with_console(buffer.clone());
+        vm.registers_mut().write(RegisterEnum::R6, 0x2FF0);
+        let program = [
+            0x2003, // LD R0, #3       ; R0 = x4000 (KBSR IE)
+            0xB003, // STI R0, #3      ; KBSR <- x4000
+            0x0FFF, // BRnzp #-1       ; wait for the interrupt
+            0x0000,
+            0x4000, // .FILL x4000
+            0xFE00, // .FILL xFE00
+        ];
+        for (i, &word) in program.iter().enumerate() {
+            vm.memory_mut().write(PC_START as usize + i, word);
+        }
+        // Handler at x1000: LDI R1, #1 ; HALT ; .FILL xFE02
+        vm.memory_mut().write(0x0180, 0x1000);
+        vm.memory_mut().write(0x1000, 0xA201);
+        vm.memory_mut().write(0x1001, 0xF025);
+        vm.memory_mut().write(0x1002, 0xFE02);
+
+        assert_eq!(vm.run_for(1_000), ExitReason::Halted);
+        assert_eq!(vm.registers().read(RegisterEnum::R1), 'k' as u16);
+        assert_eq!(vm.registers().priority(), 4);
+        // The interrupted PC and PSR were pushed onto the supervisor stack
+        assert_eq!(vm.memory().peek(0x2FEE), 0x3002);
+    }
+
+    #[test]
+    fn test_step_reports_interrupt_taken() {
+        let mut vm = vm_with_program(&[0x1021, 0x1021]);
+        vm.registers_mut().write(RegisterEnum::R6, 0x2FF0);
+        vm.memory_mut().write(0x01A0, 0x1400);
+        vm.raise_interrupt(0xA0, 2);
+
+        let info = vm.step();
+        assert_eq!(info.interrupt, Some(InterruptRequest { vector: 0xA0, priority: 2 }));
+        assert_eq!(info.pc_after, 0x1400);
+        assert_eq!(info.writes().collect::<Vec<_>>(), vec![0x2FEF, 0x2FEE]);
     }
 }
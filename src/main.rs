@@ -2,25 +2,557 @@
 #![allow(warnings)]
 
 mod lc3;
-use lc3::vm::vm;
-use lc3::sys::file;
+use lc3::asm;
+use lc3::cpu::disasm::disassemble;
+use lc3::cpu::trap::TrapMode;
+use lc3::debug::{dap, gdb, history, Debugger, Repl};
+use lc3::vm::coverage::Coverage;
+use lc3::vm::profile::Profiler;
+use lc3::vm::trace::{TraceFormat, Tracer};
+use lc3::vm::{bench, vm, ExitReason, Snapshot};
+use lc3::sys::{console, file, terminal};
+use lc3::sys::replay::{Clock, RecordingConsole, ReplayConsole};
+use lc3::sys::symbols::SymbolTable;
+use lc3::hardware::Reg::RegisterEnum;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const USAGE: &str = "Usage: lc3 [--save snapshot] [options] [image-file1] [image-file2] ...
+       lc3 asm <source.asm> [-o image.obj]
+       lc3 disasm <image.obj>
+       lc3 bench [image.obj] [-n instructions]
+       lc3 debug [options] [image-file1] ...
+       lc3 gdb [-p port] [options] [image-file1] ...
+       lc3 dap
+       lc3 profile [--top n] [--folded stacks.txt] [options] [image-file1] ...
+       lc3 coverage [--lcov coverage.info] [options] source.asm ...
+Options: --os os-image | --legacy-os os-image, --restore snapshot,
+         --record input-log | --replay input-log, --trace file.jsonl | --trace-binary file";
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() < 2 {
-        eprintln!("Usage: lc3 [image-file1] [image-file2] ...");
+    match args.get(1).map(String::as_str) {
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+        Some("asm") => assemble(&args[2..]),
+        Some("disasm") => disasm(&args[2..]),
+        Some("bench") => benchmark(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("gdb") => gdb(&args[2..]),
+        Some("dap") => debug_adapter(&args[2..]),
+        Some("profile") => profile(&args[2..]),
+        Some("coverage") => coverage(&args[2..]),
+        Some(_) => run(&args[1..]),
+    }
+}
+
+/// `asm`: assemble a source file into an image next to it (or at `-o`).
+fn assemble(args: &[String]) {
+    let (source_file, image_file) = match args {
+        [source] => (source, std::path::Path::new(source).with_extension("obj")),
+        [source, flag, image] if flag == "-o" => (source, image.into()),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let source = std::fs::read_to_string(source_file).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", source_file, e);
+        std::process::exit(1);
+    });
+    let assembly = asm::assemble(&source).unwrap_or_else(|e| {
+        eprintln!("{}:{}", source_file, e);
+        std::process::exit(1);
+    });
+    let image_file = image_file.to_string_lossy();
+    if let Err(e) = file::write_image(&image_file, assembly.origin, &assembly.words) {
+        eprintln!("Failed to write {}: {}", image_file, e);
+        std::process::exit(1);
+    }
+    println!("Assembled {} words at {:#06X} into {}", assembly.words.len(), assembly.origin, image_file);
+
+    // The symbol table goes next to the image, where `load_image` looks for it
+    let sym_file = std::path::Path::new(image_file.as_ref()).with_extension("sym");
+    let symbols: SymbolTable = assembly.symbols.iter().map(|(name, address)| (name, *address)).collect();
+    if let Err(e) = symbols.save(&sym_file) {
+        eprintln!("Failed to write {}: {}", sym_file.display(), e);
+        std::process::exit(1);
+    }
+}
+
+/// `disasm`: list an image with addresses, raw words and assembly, labelled from its `.sym` file.
+fn disasm(args: &[String]) {
+    let [image_file] = args else {
+        eprintln!("{}", USAGE);
         std::process::exit(2);
+    };
+    let (origin, words) = file::read_image_words(image_file).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", image_file, e);
+        std::process::exit(1);
+    });
+    let sym_file = std::path::Path::new(image_file).with_extension("sym");
+    let symbols = SymbolTable::load(&sym_file).unwrap_or_default();
+
+    for (i, &word) in words.iter().enumerate() {
+        let address = origin.wrapping_add(i as u16);
+        if let Some(label) = symbols.name_at(address) {
+            println!("{}:", label);
+        }
+        let instruction = disassemble(word, address);
+        println!("x{:04X}  {:04X}  {}", address, word, instruction.with_symbols(&symbols));
     }
-    //init the vm
-    let mut vm = vm::LC3::new();
-        // Load the provided image files into the VM's memory
-        for image_file in &args[1..] {
-            match vm.load_image(image_file) {
-                Ok(_) => println!("Loaded: {}", image_file),
-                Err(e) => eprintln!("Failed to load {}: {}", image_file, e),
+}
+
+/// `bench`: time an image, or the built-in workload, with and without the predecode cache
+/// (and on the JIT when it is built in).
+fn benchmark(args: &[String]) {
+    let mut image_file = None;
+    let mut limit: u64 = 50_000_000;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-n" {
+            let Some(n) = args.next().and_then(|n| n.parse().ok()) else {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            };
+            limit = n;
+        } else {
+            image_file = Some(arg);
+        }
+    }
+
+    let workload = match image_file {
+        Some(_) => None,
+        None => Some(asm::assemble(bench::WORKLOAD).expect("the built-in workload assembles")),
+    };
+    #[allow(unused_mut)]
+    let mut runs = vec![("predecode off", false, false), ("predecode on", true, false)];
+    #[cfg(feature = "jit")]
+    runs.push(("jit", true, true));
+    let mut results = Vec::new();
+    for (label, predecode, jit) in runs {
+        // Programs waiting for input see end of input instead of blocking the benchmark
+        let mut vm = vm::LC3::with_console(console::shared(console::BufferConsole::default()));
+        vm.set_predecode(predecode);
+        #[cfg(feature = "jit")]
+        if let Err(e) = vm.set_jit(jit) {
+            eprintln!("Failed to start the JIT: {}", e);
+            std::process::exit(1);
+        }
+        #[cfg(not(feature = "jit"))]
+        let _ = jit;
+        match (&workload, image_file) {
+            (Some(assembly), _) => vm.load_words(assembly.origin, &assembly.words),
+            (None, Some(image_file)) => {
+                if let Err(e) = vm.load_image(image_file) {
+                    eprintln!("Failed to load image {}: {}", image_file, e);
+                    std::process::exit(1);
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+        let measurement = bench::measure(&mut vm, limit);
+        println!(
+            "{:<13}  {:>11} instructions in {:>8.3}s  {:>8.2} MIPS  ({})",
+            label,
+            measurement.instructions,
+            measurement.elapsed.as_secs_f64(),
+            measurement.mips(),
+            measurement.exit,
+        );
+        results.push((label, measurement.mips()));
+    }
+    for (label, mips) in &results[1..] {
+        println!("speedup ({}): {:.2}x", label, mips / results[0].1.max(f64::EPSILON));
+    }
+}
+
+/// Load the images named in `args` and run the machine. With `--save`, `Ctrl+C` writes
+/// a snapshot to pick the run up from later with `--restore`.
+fn run(args: &[String]) {
+    let (save, args) = match args {
+        [flag, path, args @ ..] if flag == "--save" => (Some(path), args),
+        args => (None, args),
+    };
+    let mut vm = load(args);
+
+    // Keypresses must reach KBSR/KBDR one at a time; not being on a terminal is fine
+    let interrupted = match save {
+        Some(_) => terminal::spawn_control_c_flag().ok(),
+        None => None,
+    };
+    if interrupted.is_none() {
+        let _ = terminal::spawn_control_c_handler();
+    }
+    let _ = terminal::turn_off_canonical_and_echo_modes();
+    let live_input = !args.iter().any(|arg| arg == "--replay");
+    let reason = match (save, interrupted) {
+        (Some(path), Some(interrupted)) => run_interruptibly(&mut vm, &interrupted, live_input).unwrap_or_else(|| {
+            let _ = terminal::restore_terminal_settings();
+            match vm.snapshot().save(path) {
+                Ok(()) => eprintln!("\nSaved the machine to {}", path),
+                Err(e) => eprintln!("\nFailed to save {}: {}", path, e),
+            }
+            finish_trace(&mut vm);
+            std::process::exit(130);
+        }),
+        _ => vm.run(),
+    };
+    let _ = terminal::restore_terminal_settings();
+    finish_trace(&mut vm);
+    report_stop(&vm, reason);
+}
+
+/// `profile`: run like a normal run, counting every instruction, then print where they
+/// went. `Ctrl+C` stops the program and still reports.
+fn profile(args: &[String]) {
+    let mut folded = None;
+    let mut top = 20;
+    let mut args = args;
+    loop {
+        match args {
+            [flag, path, rest @ ..] if flag == "--folded" => {
+                folded = Some(path);
+                args = rest;
+            }
+            [flag, n, rest @ ..] if flag == "--top" => {
+                let Ok(n) = n.parse() else {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                };
+                top = n;
+                args = rest;
+            }
+            _ => break,
+        }
+    }
+    let mut vm = load(args);
+    vm.set_profiler(Some(Profiler::new()));
+    let reason = run_to_report(&mut vm, args);
+
+    let profiler = vm.set_profiler(None).expect("the profiler was set above");
+    println!("\n{}", profiler.report(&vm, top));
+    if let Some(path) = folded {
+        if let Err(e) = std::fs::write(path, profiler.folded(vm.symbols())) {
+            eprintln!("Failed to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    match reason {
+        Some(reason) => report_stop(&vm, reason),
+        None => std::process::exit(130),
+    }
+}
+
+/// `coverage`: assemble the sources, run them like a normal run, then print what they
+/// never executed, which branches only went one way and which data went unused, per
+/// source. `Ctrl+C` stops the program and still reports.
+fn coverage(args: &[String]) {
+    let (lcov, args) = match args {
+        [flag, path, args @ ..] if flag == "--lcov" => (Some(path), args),
+        args => (None, args),
+    };
+    // Option values go to `load` whatever they are called; only positional `.asm` files
+    // are sources
+    let mut sources = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if LOAD_OPTIONS.contains(&arg.as_str()) {
+            rest.push(arg.clone());
+            rest.extend(args.next().cloned());
+        } else if arg.ends_with(".asm") {
+            sources.push(arg.clone());
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    let args = rest;
+    if sources.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let assemblies: Vec<_> = sources
+        .iter()
+        .map(|path| {
+            let source = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", path, e);
+                std::process::exit(1);
+            });
+            asm::assemble(&source).unwrap_or_else(|e| {
+                eprintln!("{}:{}", path, e);
+                std::process::exit(1);
+            })
+        })
+        .collect();
+
+    let mut vm = load(&args);
+    for assembly in &assemblies {
+        vm.load_words(assembly.origin, &assembly.words);
+        let symbols: SymbolTable = assembly.symbols.iter().map(|(name, address)| (name, *address)).collect();
+        vm.symbols_mut().extend(&symbols);
+    }
+    vm.set_coverage(Some(Coverage::new()));
+    let reason = run_to_report(&mut vm, &args);
+
+    let coverage = vm.set_coverage(None).expect("the coverage was set above");
+    for (path, assembly) in sources.iter().zip(&assemblies) {
+        print!("\n{}", coverage.report(path, assembly));
+    }
+    if let Some(path) = lcov {
+        let info: String = sources.iter().zip(&assemblies).map(|(source, assembly)| coverage.lcov(source, assembly)).collect();
+        if let Err(e) = std::fs::write(path, info) {
+            eprintln!("Failed to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    match reason {
+        Some(reason) => report_stop(&vm, reason),
+        None => std::process::exit(130),
+    }
+}
+
+/// Run `vm`, loaded from `args`, for a command that reports on the run afterwards: the
+/// first `Ctrl+C` stops the program and returns `None` so the report still comes out.
+fn run_to_report(vm: &mut vm::LC3, args: &[String]) -> Option<ExitReason> {
+    let interrupted = terminal::spawn_control_c_flag().ok();
+    if interrupted.is_none() {
+        let _ = terminal::spawn_control_c_handler();
+    }
+    let _ = terminal::turn_off_canonical_and_echo_modes();
+    let live_input = !args.iter().any(|arg| arg == "--replay");
+    let reason = match interrupted {
+        Some(interrupted) => run_interruptibly(vm, &interrupted, live_input),
+        None => Some(vm.run()),
+    };
+    let _ = terminal::restore_terminal_settings();
+    finish_trace(vm);
+    reason
+}
+
+/// Say why the machine stopped and exit with an error, unless it halted.
+fn report_stop(vm: &vm::LC3, reason: ExitReason) {
+    match reason {
+        ExitReason::Halted => {}
+        reason => {
+            match reason.pc() {
+                Some(pc) if !vm.symbols().is_empty() => {
+                    eprintln!("VM stopped: {} ({})", reason, vm.symbols().describe(pc))
+                }
+                _ => eprintln!("VM stopped: {}", reason),
             }
+            std::process::exit(1);
         }
+    }
+}
 
-    vm.run();
+/// Run `vm` until it stops, or until `Ctrl+C` sets `interrupted`, which returns `None`.
+/// `live_input` says whether the console reads the terminal.
+fn run_interruptibly(vm: &mut vm::LC3, interrupted: &AtomicBool, live_input: bool) -> Option<ExitReason> {
+    // The built-in GETC and IN block on stdin, which would hold off the interruption
+    // until the next key; wait in front of them instead while no key is pending.
+    let waiting_for_key = |vm: &vm::LC3| {
+        let pc = vm.registers().read(RegisterEnum::PC);
+        live_input
+            && vm.trap_mode() == TrapMode::Fast
+            && matches!(vm.memory().peek(pc as usize), 0xF020 | 0xF023)
+            && !terminal::check_key()
+    };
+    loop {
+        match vm.run_until(|vm| interrupted.load(Ordering::SeqCst) || waiting_for_key(vm)) {
+            Err(reason) => return Some(reason),
+            Ok(()) => {
+                while waiting_for_key(vm) && !interrupted.load(Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+            }
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            return None;
+        }
+    }
+}
+
+/// Flush the trace `--trace` started, if any.
+fn finish_trace(vm: &mut vm::LC3) {
+    if let Some(Err(e)) = vm.set_tracer(None).map(|tracer| tracer.finish()) {
+        eprintln!("Failed to write the trace: {}", e);
+    }
+}
+
+/// `debug`: load images like a normal run, then stop in front of the first instruction
+/// and take debugger commands from stdin.
+fn debug(args: &[String]) {
+    use std::io::Write;
+
+    let mut debugger = Debugger::new(load(args));
+    debugger.record(Some(history::DEFAULT_HISTORY));
+    let mut repl = Repl::new(debugger);
+    let mut stdout = std::io::stdout();
+    println!("Type 'help' for a list of commands.");
+    let _ = repl.show_location(&mut stdout);
+    loop {
+        print!("(lc3) ");
+        let _ = stdout.flush();
+        // Lines are read straight from the terminal, so input typed for the program
+        // while it runs is left for its GETC and IN
+        let line = match terminal::read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Failed to read a command: {}", e);
+                std::process::exit(1);
+            }
+        };
+        match repl.execute(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("Failed to write to stdout: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
+
+/// `gdb`: load images like a normal run, then wait on a local port for one GDB
+/// remote protocol connection and serve it.
+fn gdb(args: &[String]) {
+    let (port, images) = match args {
+        [flag, port, images @ ..] if flag == "-p" => match port.parse::<u16>() {
+            Ok(port) => (port, images),
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        },
+        images => (1234, images),
+    };
+    let mut debugger = Debugger::new(load(images));
+    debugger.record(Some(history::DEFAULT_HISTORY));
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| {
+        eprintln!("Failed to listen on port {}: {}", port, e);
+        std::process::exit(1);
+    });
+    println!("Waiting for GDB on 127.0.0.1:{} (target remote :{})", port, port);
+    let result = listener.accept().and_then(|(stream, peer)| {
+        println!("GDB connected from {}", peer);
+        gdb::serve(stream, debugger)
+    });
+    if let Err(e) = result {
+        eprintln!("GDB connection failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `dap`: serve the Debug Adapter Protocol on stdin and stdout. The client names the
+/// program to debug in its launch request.
+fn debug_adapter(args: &[String]) {
+    if !args.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    if let Err(e) = dap::serve(std::io::stdin(), std::io::stdout()) {
+        eprintln!("Debug adapter failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// The options `load` takes, each followed by a file.
+const LOAD_OPTIONS: [&str; 7] = ["--os", "--legacy-os", "--restore", "--record", "--replay", "--trace", "--trace-binary"];
+
+/// A machine with the images and OS images named in `args` loaded, and its console
+/// recording or replaying input if asked to.
+fn load(args: &[String]) -> vm::LC3 {
+    // The console is the machine's from the start, so it is settled first
+    let clock = Clock::default();
+    let mut console = console::shared(console::StdConsole);
+    let mut images = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--record" && arg != "--replay" {
+            images.push(arg);
+            continue;
+        }
+        let Some(path) = args.next() else {
+            eprintln!("{} needs an input log", arg);
+            std::process::exit(2);
+        };
+        let opened = match arg.as_str() {
+            "--record" => RecordingConsole::create(console::StdConsole, clock.clone(), path).map(console::shared),
+            _ => ReplayConsole::open(console::StdConsole, clock.clone(), path).map(console::shared),
+        };
+        console = opened.unwrap_or_else(|e| {
+            eprintln!("Failed to open {}: {}", path, e);
+            std::process::exit(1);
+        });
+    }
+
+    //init the vm
+    let mut vm = vm::LC3::with_console(console);
+    vm.share_clock(clock);
+    let mut args = images.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--trace" || arg == "--trace-binary" {
+            let Some(path) = args.next() else {
+                eprintln!("{} needs a trace file", arg);
+                std::process::exit(2);
+            };
+            let format = if arg == "--trace" { TraceFormat::JsonLines } else { TraceFormat::Binary };
+            match Tracer::create(format, path) {
+                Ok(tracer) => {
+                    vm.set_tracer(Some(tracer));
+                }
+                Err(e) => {
+                    eprintln!("Failed to create {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+            continue;
+        }
+        // `--restore` puts back a machine saved with `--save`, over whatever came before it
+        if arg == "--restore" {
+            let Some(path) = args.next() else {
+                eprintln!("--restore needs a snapshot file");
+                std::process::exit(2);
+            };
+            match Snapshot::load(path) {
+                Ok(snapshot) => {
+                    vm.restore(&snapshot);
+                    println!("Restored: {}", path);
+                }
+                Err(e) => {
+                    eprintln!("Failed to restore {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+            continue;
+        }
+        // `--os` loads an OS image whose trap routines replace the built-in ones
+        let mode = match arg.as_str() {
+            "--os" => Some(TrapMode::Os),
+            "--legacy-os" => Some(TrapMode::LegacyOs),
+            _ => None,
+        };
+        let (image_file, result) = match mode {
+            Some(mode) => {
+                let Some(os_image) = args.next() else {
+                    eprintln!("{} needs an image file", arg);
+                    std::process::exit(2);
+                };
+                (os_image, vm.load_os(os_image, mode))
+            }
+            // Load the provided image files into the VM's memory
+            None => (arg, vm.load_image(arg)),
+        };
+        match result {
+            Ok(_) => println!("Loaded: {}", image_file),
+            Err(e) => eprintln!("Failed to load {}: {}", image_file, e),
+        }
+    }
+    vm
+}
+